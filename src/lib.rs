@@ -0,0 +1,6530 @@
+pub mod interpreter {
+    use crate::codegen;
+    use crate::lint;
+    use crate::optimizer::{self, Node, Pass};
+    use std::collections::{BTreeMap, VecDeque};
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum Op {
+        Increment,
+        Decrement,
+        ShiftLeft,
+        ShiftRight,
+        PrintChar,
+        GetChar,
+        LoopStart,
+        LoopEnd,
+        SwitchTape,
+    }
+
+    impl std::fmt::Display for Op {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            let name = match self {
+                Op::Increment => "Increment",
+                Op::Decrement => "Decrement",
+                Op::ShiftLeft => "ShiftLeft",
+                Op::ShiftRight => "ShiftRight",
+                Op::PrintChar => "PrintChar",
+                Op::GetChar => "GetChar",
+                Op::LoopStart => "LoopStart",
+                Op::LoopEnd => "LoopEnd",
+                Op::SwitchTape => "SwitchTape",
+            };
+            write!(f, "{}", name)
+        }
+    }
+
+    // The parsed program: one instruction per command, with each loop
+    // holding its body.
+    #[derive(PartialEq, Debug)]
+    pub enum Instruction {
+        Increment,
+        Decrement,
+        ShiftLeft,
+        ShiftRight,
+        PrintChar,
+        GetChar,
+        // `%` in dual-tape mode.
+        SwitchTape,
+        Loop(Vec<Instruction>),
+        // A marker for the source position `src_offset`, put in by
+        // `with_markers` rather than lexed. It does nothing and, unlike
+        // every other instruction, has no op index of its own.
+        Nop{ src_offset: usize },
+    }
+
+    impl Instruction {
+        // `[-]` or `[+]`, which always leaves the cell zero.
+        pub(crate) fn is_clear_loop(&self) -> bool {
+            matches!(self, Instruction::Loop(body)
+                if matches!(body.as_slice(), [Instruction::Decrement] | [Instruction::Increment]))
+        }
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    pub enum OpCode {
+        Add,
+        Move,
+        Out,
+        In,
+        JumpIfZero,
+        JumpIfNonZero,
+        SwitchTape,
+    }
+
+    #[derive(Clone, Copy)]
+    pub struct CompactOp {
+        pub code: OpCode,
+        pub arg: isize,
+    }
+
+    impl std::fmt::Display for CompactOp {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self.code {
+                OpCode::Add => write!(f, "Add {:+}", self.arg),
+                OpCode::Move => write!(f, "Move {:+}", self.arg),
+                OpCode::Out => write!(f, "Out"),
+                OpCode::In => write!(f, "In"),
+                OpCode::JumpIfZero => write!(f, "JumpIfZero -> {:04}", self.arg),
+                OpCode::JumpIfNonZero => write!(f, "JumpIfNonZero -> {:04}", self.arg),
+                OpCode::SwitchTape => write!(f, "SwitchTape"),
+            }
+        }
+    }
+
+    // Fuses runs of arithmetic and pointer moves, and resolves each jump to the
+    // address just past its matching bracket.
+    fn compile(ops: &[Op]) -> Result<Vec<CompactOp>, Error> {
+        Ok(compile_spans(ops)?.0)
+    }
+
+    // The first and last op index a compiled op was made from.
+    type OpSpan = (usize, usize);
+
+    // `compile`, also giving the span of ops each compiled op was made from.
+    fn compile_spans(ops: &[Op]) -> Result<(Vec<CompactOp>, Vec<OpSpan>), Error> {
+        let mut code = Vec::<CompactOp>::new();
+        let mut spans = Vec::<OpSpan>::new();
+        let mut starts = Vec::<usize>::new();
+        for (index, op) in ops.iter().enumerate() {
+            let (opcode, arg) = match op {
+                Op::Increment => (OpCode::Add, 1),
+                Op::Decrement => (OpCode::Add, -1),
+                Op::ShiftLeft => (OpCode::Move, -1),
+                Op::ShiftRight => (OpCode::Move, 1),
+                Op::PrintChar => (OpCode::Out, 0),
+                Op::GetChar => (OpCode::In, 0),
+                Op::LoopStart => (OpCode::JumpIfZero, 0),
+                Op::LoopEnd => (OpCode::JumpIfNonZero, 0),
+                Op::SwitchTape => (OpCode::SwitchTape, 0),
+            };
+            match opcode {
+                OpCode::Add | OpCode::Move => {
+                    if let Some(last) = code.last_mut() {
+                        if last.code == opcode {
+                            last.arg += arg;
+                            if last.arg == 0 {
+                                code.pop();
+                                spans.pop();
+                            } else if let Some(span) = spans.last_mut() {
+                                span.1 = index;
+                            }
+                            continue;
+                        }
+                    }
+                    code.push(CompactOp{ code: opcode, arg });
+                }
+                OpCode::JumpIfZero => {
+                    starts.push(code.len());
+                    code.push(CompactOp{ code: opcode, arg: 0 });
+                }
+                OpCode::JumpIfNonZero => {
+                    let start = starts.pop().ok_or(Error::Internal("unmatched ']' in the op stream"))?;
+                    code.push(CompactOp{ code: opcode, arg: (start + 1) as isize });
+                    code[start].arg = code.len() as isize;
+                }
+                _ => code.push(CompactOp{ code: opcode, arg }),
+            }
+            spans.push((index, index));
+        }
+        if !starts.is_empty() {
+            return Err(Error::Internal("unmatched '[' in the op stream"));
+        }
+        Ok((code, spans))
+    }
+
+    #[derive(Debug)]
+    pub enum Error {
+        InvalidSource,
+        UnknownCharacter(char, usize),
+        TapeLimitExceeded(usize),
+        // The input reader has no byte ready yet (`WouldBlock`).
+        InputNotReady,
+        InvalidInput(String),
+        StepLimitExceeded(u64),
+        OutputLimitExceeded(u64),
+        TimeLimitExceeded(std::time::Duration),
+        PointerUnderflow,
+        DepthLimitExceeded(usize),
+        EndlessLoop(usize),
+        // A tape fault raised by the command at a known source offset.
+        At{ offset: usize, command: char, error: Box<Error> },
+        InputRequired,
+        ArithmeticOverflow,
+        SourceTooLarge(usize),
+        // The limit and the offset of the first `[` nested beyond it.
+        NestingLimitExceeded(usize, usize),
+        // A `+`, `-` or `,` after `lock_tape`.
+        TapeLocked,
+        InvalidImage,
+        // Writing to the transcript failed.
+        Io(String),
+        // The line number, from 1, and what is wrong with it.
+        InvalidAsm(usize, String),
+        // The flag given to `with_cancel` was set.
+        Cancelled,
+        // A broken invariant inside the interpreter, which is a bug.
+        Internal(&'static str),
+    }
+
+    impl Error {
+        // Faults confined to one command, after which the program can go on.
+        pub fn is_recoverable(&self) -> bool {
+            match self {
+                Error::PointerUnderflow | Error::ArithmeticOverflow | Error::InputRequired | Error::InvalidInput(_)
+                    | Error::TapeLocked => true,
+                Error::At{ error, .. } => error.is_recoverable(),
+                _ => false,
+            }
+        }
+        // Which run-time limit stopped the program, if one did.
+        pub fn limit(&self) -> Option<&'static str> {
+            match self {
+                Error::TapeLimitExceeded(_) => Some("tape"),
+                Error::StepLimitExceeded(_) => Some("step"),
+                Error::OutputLimitExceeded(_) => Some("output"),
+                Error::TimeLimitExceeded(_) => Some("time"),
+                Error::DepthLimitExceeded(_) => Some("depth"),
+                Error::At{ error, .. } => error.limit(),
+                _ => None,
+            }
+        }
+    }
+
+    impl std::fmt::Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                Error::InvalidSource => write!(f, "Invalid source."),
+                Error::UnknownCharacter(c, offset) =>
+                    write!(f, "Unknown character {:?} at offset {}.", c, offset),
+                Error::TapeLimitExceeded(max) =>
+                    write!(f, "Tape limit exceeded: more than {} cells.", max),
+                Error::InputNotReady => write!(f, "The input is not ready."),
+                Error::InvalidInput(msg) => write!(f, "Invalid input: {}.", msg),
+                Error::StepLimitExceeded(max) =>
+                    write!(f, "Step limit exceeded: more than {} steps.", max),
+                Error::OutputLimitExceeded(max) =>
+                    write!(f, "Output limit exceeded: more than {} bytes.", max),
+                Error::TimeLimitExceeded(max) =>
+                    write!(f, "Time limit exceeded: more than {}ms.", max.as_millis()),
+                Error::PointerUnderflow => write!(f, "Pointer moved left of the first cell."),
+                Error::DepthLimitExceeded(max) =>
+                    write!(f, "Loop nesting limit exceeded: more than {} levels.", max),
+                Error::EndlessLoop(offset) =>
+                    write!(f, "The loop at offset {} never terminates.", offset),
+                Error::At{ offset, command, error } =>
+                    write!(f, "{} At offset {}: {:?}.", error, offset, command),
+                Error::InputRequired => write!(f, "The program read past the end of its input."),
+                Error::ArithmeticOverflow => write!(f, "A cell overflowed."),
+                Error::SourceTooLarge(max) =>
+                    write!(f, "Source too large: more than {} bytes.", max),
+                Error::NestingLimitExceeded(max, offset) =>
+                    write!(f, "Source nests loops more than {} levels deep at offset {}.", max, offset),
+                Error::TapeLocked => write!(f, "The tape is locked against writes."),
+                Error::InvalidImage => write!(f, "Invalid image."),
+                Error::Io(msg) => write!(f, "I/O error: {}.", msg),
+                Error::InvalidAsm(line, msg) => write!(f, "Invalid assembly at line {}: {}.", line, msg),
+                Error::Cancelled => write!(f, "The run was cancelled."),
+                Error::Internal(msg) => write!(f, "Internal error: {}.", msg),
+            }
+        }
+    }
+
+    // An endless input of pseudo-random bytes (xorshift64*), reproducible
+    // from its seed.
+    pub struct RandomInput {
+        state: u64,
+    }
+
+    impl RandomInput {
+        pub fn new(seed: u64) -> RandomInput {
+            // xorshift never leaves the all-zero state, so avoid starting there.
+            let state = seed ^ 0x9e37_79b9_7f4a_7c15;
+            RandomInput{ state: if state == 0 { 1 } else { state } }
+        }
+        fn next_byte(&mut self) -> u8 {
+            self.state ^= self.state >> 12;
+            self.state ^= self.state << 25;
+            self.state ^= self.state >> 27;
+            (self.state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 56) as u8
+        }
+    }
+
+    impl std::io::Read for RandomInput {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            for b in buf.iter_mut() {
+                *b = self.next_byte();
+            }
+            Ok(buf.len())
+        }
+    }
+
+    // What `,` stores once the input is exhausted.
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum EofPolicy {
+        Zero,
+        MinusOne,
+        Unchanged,
+        Value(u8),
+    }
+
+    // What `,` reads in sandbox mode: always end of input, always one
+    // byte, or the bytes of a buffer over and over.
+    #[derive(Clone, PartialEq, Debug)]
+    pub enum SandboxInput {
+        Eof,
+        Byte(u8),
+        Cycle(Vec<u8>),
+    }
+
+    // How output bytes are rendered: as-is, as two hex digits each, with
+    // non-printable bytes shown as `\xNN`, or as space-separated runs
+    // "value x count" such as `72x10`.
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum OutputFormat {
+        Raw,
+        Hex,
+        Escaped,
+        Rle,
+    }
+
+    // What `+` and `-` do past the ends of a cell's range: wrap around,
+    // fail with `ArithmeticOverflow`, or stay at 255 or 0.
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum ArithMode {
+        Wrapping,
+        Checked,
+        Saturating,
+    }
+
+    // How `,` delivers input characters outside ASCII: as their raw UTF-8
+    // bytes, or as one Latin-1 byte per character.
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum InputEncoding {
+        Utf8,
+        Latin1,
+    }
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum Status {
+        Running,
+        // Started with `with_start_paused` and nothing executed yet.
+        Paused,
+        // Stopped on a `,` with cooperative input and no byte provided, or
+        // with an input reader that has no byte ready yet.
+        NeedInput,
+        Finished,
+    }
+
+    // What a hook set with `Interpreter::set_hook` is told after each step.
+    // `offset` is the command's byte offset in the source; `pointer`,
+    // `cell` and `step` are as they are once the op has run.
+    #[derive(Clone, Copy, Debug)]
+    pub struct StepInfo {
+        pub op: Op,
+        pub offset: usize,
+        pub pointer: usize,
+        pub cell: u8,
+        pub step: u64,
+    }
+
+    pub type StepHook = Box<dyn FnMut(&StepInfo)>;
+
+    // Static shape of a program. Its loop weight counts each op as 2^depth,
+    // so work nested in loops dominates.
+    #[derive(Clone, Copy, Default, Debug)]
+    pub struct Metrics {
+        pub ops: usize,
+        pub loops: usize,
+        pub max_depth: usize,
+        pub loop_weight: u64,
+        // Characters other than commands and whitespace.
+        pub comment_chars: usize,
+    }
+
+    impl Metrics {
+        // Comment characters per command character, brackets included.
+        pub fn comment_density(&self) -> f64 {
+            let commands = self.ops + 2 * self.loops;
+            if commands == 0 { 0.0 } else { self.comment_chars as f64 / commands as f64 }
+        }
+    }
+
+    // Source offsets of a loop's brackets. Top-level loops have depth 1,
+    // matching `Metrics::max_depth`.
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub struct LoopSpan {
+        pub start: usize,
+        pub end: usize,
+        pub depth: usize,
+    }
+
+    fn collect_metrics(v: &[Instruction], depth: usize, m: &mut Metrics) {
+        if depth > m.max_depth {
+            m.max_depth = depth;
+        }
+        for e in v.iter() {
+            match e {
+                Instruction::Loop(child) => {
+                    m.loops += 1;
+                    collect_metrics(child, depth + 1, m);
+                }
+                Instruction::Nop{ .. } => (),
+                _ => {
+                    m.ops += 1;
+                    m.loop_weight = m.loop_weight.saturating_add(1u64.checked_shl(depth as u32).unwrap_or(u64::MAX));
+                }
+            }
+        }
+    }
+
+    // The cells the pointer can reach, as offsets from where it starts.
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub struct PointerRange {
+        pub min: isize,
+        pub max: isize,
+    }
+
+    impl PointerRange {
+        pub fn cells(&self) -> usize {
+            (self.max - self.min) as usize + 1
+        }
+    }
+
+    // Widens `range` to cover `v`, moving `ptr` along. False if some loop
+    // does not return the pointer to where it entered, or the tape is
+    // switched, so the range cannot be bounded.
+    fn walk_pointer(v: &[Instruction], ptr: &mut isize, range: &mut PointerRange) -> bool {
+        for e in v.iter() {
+            match e {
+                Instruction::ShiftLeft => *ptr -= 1,
+                Instruction::ShiftRight => *ptr += 1,
+                Instruction::SwitchTape => return false,
+                Instruction::Loop(body) => {
+                    let entry = *ptr;
+                    if !walk_pointer(body, ptr, range) || *ptr != entry {
+                        return false;
+                    }
+                }
+                _ => (),
+            }
+            range.min = range.min.min(*ptr);
+            range.max = range.max.max(*ptr);
+        }
+        true
+    }
+
+    #[derive(Clone, Copy, Default)]
+    pub struct CellAccess {
+        pub reads: u64,
+        pub writes: u64,
+    }
+
+    // The tape and position saved by `Interpreter::snapshot`.
+    #[derive(Clone)]
+    pub struct Snapshot {
+        memory: Vec<u8>,
+        adress: usize,
+        other_memory: Vec<u8>,
+        other_adress: usize,
+        on_second: bool,
+        pc: usize,
+        depth: usize,
+        steps: u64,
+    }
+
+    // What `step_back` needs to undo one step. A step changes at most the
+    // cell under the pointer, so only that cell's old value is kept.
+    struct Undo {
+        pc: usize,
+        adress: usize,
+        cell: u8,
+        tape_len: usize,
+        left_grown: usize,
+        depth: usize,
+        steps: u64,
+        iterations: u64,
+        input_ops: u64,
+        bytes_written: u64,
+        output_len: usize,
+        consumed_len: usize,
+        last_output: Option<u8>,
+        replay_pos: usize,
+        bytes_read: u64,
+        on_second: bool,
+    }
+
+    // A loop body being run by `eval_liner`: the next instruction in it
+    // and that instruction's op index. `opened` holds the op index of the
+    // loop's `[` and when the loop was entered, if it is being timed; the
+    // top level has none.
+    struct Frame<'a> {
+        body: &'a [Instruction],
+        pos: usize,
+        index: usize,
+        opened: Option<(usize, Option<std::time::Instant>)>,
+    }
+
+    pub struct Interpreter {
+        source: Vec<u8>,
+        ops: Vec<Op>,
+        offsets: Vec<usize>,
+        comment_chars: usize,
+        jumps: Vec<usize>,
+        inst: Vec<Instruction>,
+        // Whether `inst` and `optimized` are built for the current parse.
+        has_tree: bool,
+        // Source offsets to mark with `Instruction::Nop`, in order.
+        markers: Vec<usize>,
+        // A hash of what the current parse was made from, and how many
+        // parses there have been.
+        parsed_key: Option<u64>,
+        parses: u64,
+        memory: Vec<u8>,
+        adress: usize,
+        pc: usize,
+        prepared: bool,
+        start_paused: bool,
+        validate_on_build: bool,
+        validated: bool,
+        strict: bool,
+        opt_level: u8,
+        passes: Option<Vec<Pass>>,
+        arith_mode: ArithMode,
+        keep_going: bool,
+        optimized: Vec<Node>,
+        flat: bool,
+        compact: Vec<CompactOp>,
+        max_tape: Option<usize>,
+        max_source: Option<usize>,
+        max_nesting: Option<usize>,
+        growth_chunk: usize,
+        left_growth: bool,
+        warn_left: bool,
+        left_warned: bool,
+        ptr_modulo: Option<usize>,
+        max_steps: Option<u64>,
+        max_time: Option<std::time::Duration>,
+        cancel: Option<std::sync::Arc<std::sync::atomic::AtomicBool>>,
+        check_interval: u64,
+        // The step count at which limits are next checked, and how many
+        // times they have been.
+        next_check: u64,
+        limit_checks: u64,
+        max_depth: Option<usize>,
+        depth: usize,
+        steps: u64,
+        max_output: Option<u64>,
+        initial_tape: Vec<u8>,
+        // What every cell not set by `initial_tape` starts at.
+        fill: u8,
+        initial_pointer: usize,
+        sandbox: Option<SandboxInput>,
+        // Where `SandboxInput::Cycle` is up to.
+        sandbox_pos: usize,
+        input: Option<Box<dyn std::io::Read>>,
+        cooperative_input: bool,
+        pending_input: VecDeque<u8>,
+        input_ended: bool,
+        // A byte `step` read ahead to learn whether the input was ready,
+        // kept for the `,` it was read for.
+        lookahead: Option<Option<u8>>,
+        replayable: bool,
+        // All of the input once read up front, and how far `,` has got.
+        replay: Option<Vec<u8>>,
+        replay_pos: usize,
+        // Input bytes to drop unseen before the next one, as a loaded image
+        // asks.
+        skip_input: u64,
+        prompt: Option<String>,
+        is_tty: fn() -> bool,
+        eof_policy: EofPolicy,
+        newline_is_eof: bool,
+        require_input: bool,
+        input_encoding: InputEncoding,
+        output: Option<Box<dyn std::io::Write>>,
+        transcript: Option<Box<dyn std::io::Write>>,
+        // When the current run began, for transcript timestamps and the
+        // time limit. Only read from the clock when one of those needs it:
+        // `Instant::now` panics on wasm32-unknown-unknown.
+        epoch: Option<std::time::Instant>,
+        line_buffered: bool,
+        captured: Option<Vec<u8>>,
+        // Every byte `,` has read this run, when that is recorded.
+        consumed: Option<Vec<u8>>,
+        output_format: OutputFormat,
+        ascii7: bool,
+        ensure_newline: bool,
+        signed_display: bool,
+        last_output: Option<u8>,
+        // The run an Rle output has yet to write: its byte and length.
+        pending_run: Option<(u8, u64)>,
+        bytes_written: u64,
+        input_ops: u64,
+        bytes_read: u64,
+        iterations: u64,
+        tape_high_water: usize,
+        profile: bool,
+        access: Vec<CellAccess>,
+        loop_timing: bool,
+        // The weighted step count, when it is being kept.
+        work: Option<u64>,
+        // How many times each op ran during `run`, by op index, when counted.
+        op_counts: Option<Vec<u64>>,
+        loop_times: BTreeMap<usize, std::time::Duration>,
+        undo: Option<Vec<Undo>>,
+        hook: Option<StepHook>,
+        dual_tape: bool,
+        // The inactive tape in dual-tape mode, swapped in by `%`.
+        other_memory: Vec<u8>,
+        other_adress: usize,
+        other_access: Vec<CellAccess>,
+        on_second: bool,
+        // Cells ever added at the left end, which shift every index.
+        left_grown: usize,
+        tape_locked: bool,
+    }
+
+    pub const MAX_OPT_LEVEL: u8 = 3;
+
+    // The optimizer and the optimized evaluator recurse once per loop
+    // level, so sources nested deeper than this are refused with
+    // `NestingLimitExceeded` instead of being optimized.
+    pub const MAX_OPT_NESTING: usize = 1000;
+
+    fn stdin_is_tty() -> bool {
+        std::io::IsTerminal::is_terminal(&std::io::stdin())
+    }
+
+    pub fn new(s: String) -> Interpreter {
+        from_bytes(s.into_bytes())
+    }
+
+    // A UTF-8 byte order mark some editors write at the start of a file. It
+    // is dropped so it neither counts towards offsets nor trips strict mode.
+    const BOM: &[u8] = b"\xef\xbb\xbf";
+
+    fn strip_bom(source: &[u8]) -> &[u8] {
+        source.strip_prefix(BOM).unwrap_or(source)
+    }
+
+    // Only command bytes matter, so the source need not be valid UTF-8.
+    pub fn from_bytes(source: Vec<u8>) -> Interpreter {
+        let source = if source.starts_with(BOM) { strip_bom(&source).to_vec() } else { source };
+        Interpreter{
+            source,
+            ops: Vec::<Op>::new(),
+            offsets: Vec::<usize>::new(),
+            comment_chars: 0,
+            jumps: Vec::<usize>::new(),
+            inst: Vec::<Instruction>::new(),
+            has_tree: false,
+            memory: vec![0],
+            adress: 0,
+            pc: 0,
+            prepared: false,
+            start_paused: false,
+            validate_on_build: false,
+            validated: false,
+            strict: false,
+            opt_level: 0,
+            passes: None,
+            arith_mode: ArithMode::Wrapping,
+            keep_going: false,
+            optimized: Vec::<Node>::new(),
+            flat: false,
+            compact: Vec::<CompactOp>::new(),
+            max_tape: None,
+            max_source: None,
+            max_nesting: None,
+            growth_chunk: 1,
+            left_growth: true,
+            warn_left: false,
+            markers: Vec::<usize>::new(),
+            parsed_key: None,
+            parses: 0,
+            left_warned: false,
+            ptr_modulo: None,
+            max_steps: None,
+            max_time: None,
+            cancel: None,
+            check_interval: 1,
+            next_check: 0,
+            limit_checks: 0,
+            max_depth: None,
+            depth: 0,
+            steps: 0,
+            max_output: None,
+            initial_tape: Vec::<u8>::new(),
+            fill: 0,
+            initial_pointer: 0,
+            sandbox: None,
+            sandbox_pos: 0,
+            input: None,
+            cooperative_input: false,
+            pending_input: VecDeque::<u8>::new(),
+            input_ended: false,
+            lookahead: None,
+            replayable: false,
+            replay: None,
+            replay_pos: 0,
+            skip_input: 0,
+            prompt: None,
+            is_tty: stdin_is_tty,
+            eof_policy: EofPolicy::Zero,
+            newline_is_eof: false,
+            require_input: false,
+            input_encoding: InputEncoding::Utf8,
+            output: None,
+            transcript: None,
+            epoch: None,
+            line_buffered: false,
+            captured: None,
+            consumed: None,
+            output_format: OutputFormat::Raw,
+            ascii7: false,
+            ensure_newline: false,
+            signed_display: false,
+            last_output: None,
+            pending_run: None,
+            bytes_written: 0,
+            input_ops: 0,
+            bytes_read: 0,
+            iterations: 0,
+            tape_high_water: 0,
+            profile: false,
+            access: Vec::<CellAccess>::new(),
+            loop_timing: false,
+            work: None,
+            op_counts: None,
+            loop_times: BTreeMap::<usize, std::time::Duration>::new(),
+            undo: None,
+            hook: None,
+            dual_tape: false,
+            other_memory: vec![0],
+            other_adress: 0,
+            other_access: Vec::<CellAccess>::new(),
+            on_second: false,
+            left_grown: 0,
+            tape_locked: false,
+        }
+    }
+
+    const IMAGE_MAGIC: &[u8] = b"BFIMAGE1";
+
+    // Drops the cells still at `fill` at both ends of a tape, keeping the
+    // cell at `adress` and at most `max_left` cells before it. Returns how
+    // many cells were dropped on the left.
+    fn trim_tape(memory: &mut Vec<u8>, access: &mut Vec<CellAccess>, adress: usize, max_left: usize, fill: u8) -> usize {
+        let start = memory[..adress].iter().take_while(|&&v| v == fill).count().min(max_left);
+        let end = memory.len() - memory[adress + 1..].iter().rev().take_while(|&&v| v == fill).count();
+        memory.truncate(end);
+        memory.drain(..start);
+        memory.shrink_to_fit();
+        if !access.is_empty() {
+            access.truncate(end);
+            access.drain(..start);
+            access.shrink_to_fit();
+        }
+        start
+    }
+
+    fn write_block(out: &mut dyn std::io::Write, bytes: &[u8]) -> std::io::Result<()> {
+        out.write_all(&(bytes.len() as u64).to_le_bytes())?;
+        out.write_all(bytes)
+    }
+
+    fn read_bytes<'a>(image: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+        if image.len() < len {
+            return Err(Error::InvalidImage);
+        }
+        let (head, rest) = image.split_at(len);
+        *image = rest;
+        Ok(head)
+    }
+
+    fn read_u64(image: &mut &[u8]) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(read_bytes(image, 8)?.try_into().unwrap()))
+    }
+
+    fn read_block<'a>(image: &mut &'a [u8]) -> Result<&'a [u8], Error> {
+        let len = usize::try_from(read_u64(image)?).map_err(|_| Error::InvalidImage)?;
+        read_bytes(image, len)
+    }
+
+    // An interpreter for the program saved by `save_image`, positioned
+    // where it was saved, to be continued with `step` or `resume` rather
+    // than `run` or `start`, which would begin again. Given the same input,
+    // the bytes already read are skipped.
+    pub fn load_image(image: &[u8]) -> Result<Interpreter, Error> {
+        let mut image = image;
+        if read_bytes(&mut image, IMAGE_MAGIC.len())? != IMAGE_MAGIC {
+            return Err(Error::InvalidImage);
+        }
+        let ops = read_block(&mut image)?.to_vec();
+        let dual_tape = ops.contains(&b'%');
+        let mut interpreter = from_bytes(ops).with_dual_tape(dual_tape);
+        interpreter.prepare()?;
+        let mut positions = [0usize; 5];
+        for n in positions.iter_mut() {
+            *n = usize::try_from(read_u64(&mut image)?).map_err(|_| Error::InvalidImage)?;
+        }
+        let [pc, depth, adress, other_adress, left_grown] = positions;
+        interpreter.steps = read_u64(&mut image)?;
+        interpreter.iterations = read_u64(&mut image)?;
+        interpreter.input_ops = read_u64(&mut image)?;
+        interpreter.bytes_read = read_u64(&mut image)?;
+        interpreter.bytes_written = read_u64(&mut image)?;
+        let flags = read_bytes(&mut image, 4)?;
+        let memory = read_block(&mut image)?.to_vec();
+        let other_memory = read_block(&mut image)?.to_vec();
+        if !image.is_empty() || pc > interpreter.ops.len() || adress >= memory.len() || other_adress >= other_memory.len()
+            || left_grown >= memory.len() {
+            return Err(Error::InvalidImage);
+        }
+        // The evaluator can be no deeper than the loops open at `pc`.
+        let open = interpreter.ops[..pc].iter().fold(0isize, |open, op| match op {
+            Op::LoopStart => open + 1,
+            Op::LoopEnd => open - 1,
+            _ => open,
+        });
+        if depth as isize > open {
+            return Err(Error::InvalidImage);
+        }
+        interpreter.pc = pc;
+        interpreter.depth = depth;
+        interpreter.adress = adress;
+        interpreter.other_adress = other_adress;
+        interpreter.left_grown = left_grown;
+        interpreter.on_second = flags[0] != 0;
+        interpreter.last_output = if flags[1] != 0 { Some(flags[2]) } else { None };
+        interpreter.fill = flags[3];
+        interpreter.skip_input = interpreter.bytes_read;
+        interpreter.tape_high_water = memory.len();
+        interpreter.memory = memory;
+        interpreter.other_memory = other_memory;
+        Ok(interpreter)
+    }
+
+    // A flat interpreter for a program in the text form `to_asm` prints:
+    // one op per line, `add <n>`, `move <n>`, `in`, `out`, `tape`, and
+    // `loop` ... `endloop`, with `#` starting a comment. Adjacent adds or
+    // moves are fused when the program is compiled, as for any source.
+    pub fn from_asm(text: &str) -> Result<Interpreter, Error> {
+        let mut source = Vec::<u8>::new();
+        let mut open = Vec::<usize>::new();
+        for (n, line) in text.lines().enumerate() {
+            let invalid = |msg: &str| Error::InvalidAsm(n + 1, String::from(msg));
+            let line = line.split('#').next().unwrap_or("");
+            let mut words = line.split_whitespace();
+            let op = match words.next() {
+                Some(op) => op,
+                None => continue,
+            };
+            let mut count = |plus: u8, minus: u8| -> Result<(), Error> {
+                let arg : isize = words.next().ok_or_else(|| invalid("missing operand"))?
+                    .parse().map_err(|_| invalid("invalid operand"))?;
+                let c = if arg < 0 { minus } else { plus };
+                source.extend(std::iter::repeat_n(c, arg.unsigned_abs()));
+                Ok(())
+            };
+            match op {
+                "add" => count(b'+', b'-')?,
+                "move" => count(b'>', b'<')?,
+                "in" => source.push(b','),
+                "out" => source.push(b'.'),
+                "tape" => source.push(b'%'),
+                "loop" => {
+                    open.push(n + 1);
+                    source.push(b'[');
+                }
+                "endloop" => {
+                    open.pop().ok_or_else(|| invalid("endloop without loop"))?;
+                    source.push(b']');
+                }
+                _ => return Err(invalid(&format!("unknown op {:?}", op))),
+            }
+            if words.next().is_some() {
+                return Err(invalid("too many operands"));
+            }
+        }
+        if let Some(line) = open.pop() {
+            return Err(Error::InvalidAsm(line, String::from("loop without endloop")));
+        }
+        let dual_tape = source.contains(&b'%');
+        Ok(from_bytes(source).with_flat(true).with_dual_tape(dual_tape))
+    }
+
+    // Runs `source` against `input` entirely in memory, never touching the
+    // process's stdin or stdout, so it is usable on wasm32-unknown-unknown.
+    pub fn run_bytes(source: impl AsRef<[u8]>, input: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut interpreter = from_bytes(source.as_ref().to_vec())
+            .with_input(Box::new(std::io::Cursor::new(input.to_vec())))
+            .with_captured_output();
+        interpreter.run()?;
+        Ok(interpreter.take_output())
+    }
+
+    // Maps the byte offset of every bracket in `source` to the offset of its
+    // partner, in both directions, without parsing the rest of the program.
+    pub fn bracket_map(source: impl AsRef<[u8]>) -> Result<BTreeMap<usize, usize>, Error> {
+        let mut map = BTreeMap::<usize, usize>::new();
+        let mut open = Vec::<usize>::new();
+        for (offset, c) in source.as_ref().iter().enumerate() {
+            match c {
+                b'[' => open.push(offset),
+                b']' => {
+                    let start = open.pop().ok_or(Error::InvalidSource)?;
+                    map.insert(start, offset);
+                    map.insert(offset, start);
+                }
+                _ => (),
+            }
+        }
+        if !open.is_empty() {
+            return Err(Error::InvalidSource);
+        }
+        Ok(map)
+    }
+
+    fn op_of(c: u8) -> Option<Op> {
+        match c {
+            b'+' => Some(Op::Increment),
+            b'-' => Some(Op::Decrement),
+            b'<' => Some(Op::ShiftLeft),
+            b'>' => Some(Op::ShiftRight),
+            b'.' => Some(Op::PrintChar),
+            b',' => Some(Op::GetChar),
+            b'[' => Some(Op::LoopStart),
+            b']' => Some(Op::LoopEnd),
+            _ => None,
+        }
+    }
+
+    fn char_of(op: Op) -> u8 {
+        match op {
+            Op::Increment => b'+',
+            Op::Decrement => b'-',
+            Op::ShiftLeft => b'<',
+            Op::ShiftRight => b'>',
+            Op::PrintChar => b'.',
+            Op::GetChar => b',',
+            Op::LoopStart => b'[',
+            Op::LoopEnd => b']',
+            Op::SwitchTape => b'%',
+        }
+    }
+
+    // Every command in `source` with its byte offset, skipping comments.
+    // Brackets are not checked for balance.
+    pub fn tokens_with_offsets(source: impl AsRef<[u8]>) -> Vec<(usize, Op)> {
+        source.as_ref().iter().enumerate()
+            .filter_map(|(offset, &c)| op_of(c).map(|op| (offset, op)))
+            .collect()
+    }
+
+    // The character starting at byte `offset`, or U+FFFD if the bytes there
+    // are not valid UTF-8.
+    pub(crate) fn char_at(source: &[u8], offset: usize) -> char {
+        let end = source.len().min(offset + 4);
+        String::from_utf8_lossy(&source[offset..end]).chars().next().unwrap()
+    }
+
+    fn count_instructions(v: &[Instruction]) -> usize {
+        v.iter().map(|e| match e {
+            Instruction::Loop(child) => 1 + count_instructions(child),
+            Instruction::Nop{ .. } => 0,
+            _ => 1,
+        }).sum()
+    }
+
+    // Frees an instruction tree one loop at a time; dropping it the
+    // default way recurses once per nesting level.
+    fn free_instructions(mut pending: Vec<Instruction>) {
+        while let Some(i) = pending.pop() {
+            if let Instruction::Loop(body) = i {
+                pending.extend(body);
+            }
+        }
+    }
+
+    impl Drop for Interpreter {
+        fn drop(&mut self) {
+            free_instructions(std::mem::take(&mut self.inst));
+        }
+    }
+
+    const DEBUG_TAPE_PREVIEW: usize = 16;
+
+    impl std::fmt::Debug for Interpreter {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            let mut d = f.debug_struct("Interpreter");
+            d.field("source_len", &self.source.len())
+                .field("ops", &self.ops.len())
+                .field("instructions", &count_instructions(&self.inst));
+            if self.prepared {
+                let end = self.memory.len().min(DEBUG_TAPE_PREVIEW);
+                d.field("pointer", &self.adress)
+                    .field("tape_len", &self.memory.len())
+                    .field("tape", &&self.memory[..end]);
+            }
+            d.finish()
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum Mismatch {
+        Failed(Error),
+        Output { index: usize, got: Option<u8>, expected: Option<u8> },
+    }
+
+    impl std::fmt::Display for Mismatch {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            let show = |b: &Option<u8>| match b {
+                Some(b) => format!("{:?}", *b as char),
+                None => String::from("end of output"),
+            };
+            match self {
+                Mismatch::Failed(e) => write!(f, "{}", e),
+                Mismatch::Output { index, got, expected } =>
+                    write!(f, "Output differs at byte {}: got {}, expected {}.", index, show(got), show(expected)),
+            }
+        }
+    }
+
+    // The first position where `got` and `expected` differ, if any.
+    pub fn first_mismatch(got: &[u8], expected: &[u8]) -> Option<Mismatch> {
+        let len = got.len().max(expected.len());
+        (0..len)
+            .find(|&i| got.get(i) != expected.get(i))
+            .map(|index| Mismatch::Output {
+                index,
+                got: got.get(index).copied(),
+                expected: expected.get(index).copied(),
+            })
+    }
+
+    pub fn check(source: &str, input: &[u8], expected: &[u8]) -> Result<(), Mismatch> {
+        let got = run_bytes(source, input).map_err(Mismatch::Failed)?;
+        match first_mismatch(&got, expected) {
+            Some(m) => Err(m),
+            None => Ok(()),
+        }
+    }
+
+    impl std::str::FromStr for Interpreter {
+        type Err = Error;
+
+        fn from_str(s: &str) -> Result<Interpreter, Error> {
+            new(String::from(s)).with_validate_on_build(true).build()
+        }
+    }
+
+    #[allow(dead_code)]
+    fn print_instruction(v: &[Instruction]) {
+        print_instruction_with_indent(v, 0);
+    }
+
+    #[allow(dead_code)]
+    fn print_instruction_with_indent(v: &[Instruction], depth: u8) {
+        let mut indent = String::from("");
+        for _ in 0..depth {
+            indent.push_str("  ");
+        }
+
+        for e in v.iter() {
+            let label = match e {
+                Instruction::Increment =>  Some(String::from("Increment")),
+                Instruction::Decrement =>  Some(String::from("Decrement")),
+                Instruction::ShiftLeft =>  Some(String::from("ShiftLeft")),
+                Instruction::ShiftRight => Some(String::from("ShiftRight")),
+                Instruction::PrintChar =>  Some(String::from("PrintChar")),
+                Instruction::GetChar =>  Some(String::from("GetChar")),
+                Instruction::SwitchTape => Some(String::from("SwitchTape")),
+                Instruction::Nop{ src_offset } => Some(format!("Nop {}", src_offset)),
+                Instruction::Loop(child) => {
+                    println!("{}Loop:", indent);
+                    print_instruction_with_indent(child, depth + 1);
+                    None
+                }
+            };
+
+            if let Some(v) = label {
+                println!("{}{}", indent, v);
+            }
+        }
+    }
+
+    impl Interpreter {
+        pub fn with_validate_on_build(mut self, enable: bool) -> Interpreter {
+            self.validate_on_build = enable;
+            self
+        }
+        pub fn build(mut self) -> Result<Interpreter, Error> {
+            if self.validate_on_build {
+                self.validate()?;
+                self.validated = true;
+            }
+            Ok(self)
+        }
+        // Makes `start` stop before the first instruction so a frontend can
+        // attach before driving `step` or `resume`.
+        pub fn with_start_paused(mut self, enable: bool) -> Interpreter {
+            self.start_paused = enable;
+            self
+        }
+        // Rejects any character that is neither a command nor whitespace.
+        pub fn with_strict(mut self, enable: bool) -> Interpreter {
+            self.strict = enable;
+            self
+        }
+        pub fn with_opt_level(mut self, level: u8) -> Interpreter {
+            self.opt_level = level;
+            self
+        }
+        // Runs exactly these passes, in order, instead of the ones selected
+        // by the optimization level.
+        pub fn with_passes(mut self, passes: Vec<Pass>) -> Interpreter {
+            self.passes = Some(passes);
+            self
+        }
+        // Any mode but wrapping has to see every single `+` and `-`, so it
+        // runs the unoptimized tree whatever the optimization settings.
+        pub fn with_arith_mode(mut self, mode: ArithMode) -> Interpreter {
+            self.arith_mode = mode;
+            self
+        }
+        // Reports recoverable faults (see `Error::is_recoverable`) to stderr
+        // and skips the faulting command instead of stopping. Like a
+        // non-wrapping arithmetic mode, this runs the unoptimized tree.
+        pub fn with_keep_going(mut self, enable: bool) -> Interpreter {
+            self.keep_going = enable;
+            self
+        }
+        fn optimizing(&self) -> bool {
+            self.opt_level > 0 || self.passes.is_some()
+        }
+        // Runs the fused bytecode listed by `disassemble` instead of the
+        // instruction tree. The optimization level is then ignored. Without
+        // a step, time or depth limit, a pointer window or profiling, a
+        // leaner loop runs it.
+        pub fn with_flat(mut self, enable: bool) -> Interpreter {
+            self.flat = enable;
+            self
+        }
+        pub fn with_max_tape(mut self, max: usize) -> Interpreter {
+            self.max_tape = Some(max);
+            self
+        }
+        // Refuses a source whose loops nest more than `max` levels deep,
+        // checked with the brackets before parsing.
+        pub fn with_max_nesting(mut self, max: usize) -> Interpreter {
+            self.max_nesting = Some(max);
+            self
+        }
+        // Refuses a source longer than `max` bytes, checked before anything
+        // else is done with it.
+        pub fn with_max_source_bytes(mut self, max: usize) -> Interpreter {
+            self.max_source = Some(max);
+            self
+        }
+        // Grow the tape by at least `cells` cells at a time (clamped to the
+        // tape limit) instead of one cell per overrun.
+        pub fn with_growth_chunk(mut self, cells: usize) -> Interpreter {
+            self.growth_chunk = cells.max(1);
+            self
+        }
+        // With left growth disabled, moving left of the first cell is an
+        // error instead of prepending cells.
+        pub fn with_left_growth(mut self, enable: bool) -> Interpreter {
+            self.left_growth = enable;
+            self
+        }
+        // Prints a warning to stderr the first time in a run that the tape
+        // grows left, that is, the pointer goes left of the original first
+        // cell. The program still goes on.
+        pub fn with_warn_left(mut self, enable: bool) -> Interpreter {
+            self.warn_left = enable;
+            self
+        }
+        // Puts an `Instruction::Nop` before the first command at or after
+        // each of `offsets` in the source, or at the very end if there is
+        // none. Markers change neither the output nor op offsets; the
+        // optimized and flat forms drop them.
+        pub fn with_markers(mut self, mut offsets: Vec<usize>) -> Interpreter {
+            offsets.sort_unstable();
+            self.markers = offsets;
+            self
+        }
+        // Keeps the pointer within cells 0..window, wrapping around at
+        // either edge. Optimization is then skipped, except for `with_flat`.
+        pub fn with_ptr_modulo(mut self, window: usize) -> Interpreter {
+            self.ptr_modulo = Some(window.max(1));
+            self
+        }
+        pub fn with_max_steps(mut self, max: u64) -> Interpreter {
+            self.max_steps = Some(max);
+            self
+        }
+        // Stops the run once it has taken longer than `max`, checked along
+        // with the step limit.
+        pub fn with_max_time(mut self, max: std::time::Duration) -> Interpreter {
+            self.max_time = Some(max);
+            self
+        }
+        // Stops the run with `Error::Cancelled` once another thread sets
+        // `flag`, checked along with the step limit. The flag is never
+        // cleared here.
+        pub fn with_cancel(mut self, flag: std::sync::Arc<std::sync::atomic::AtomicBool>) -> Interpreter {
+            self.cancel = Some(flag);
+            self
+        }
+        // Checks the step limit (and a pending signal) only every `steps`
+        // steps instead of on each one, so a run may overshoot the limit by
+        // up to `steps - 1` steps.
+        pub fn with_check_interval(mut self, steps: u64) -> Interpreter {
+            self.check_interval = steps.max(1);
+            self
+        }
+        // Bounds how deeply loops may nest as the program runs, flat or not.
+        pub fn with_max_runtime_depth(mut self, max: usize) -> Interpreter {
+            self.max_depth = Some(max);
+            self
+        }
+        pub fn with_max_output(mut self, max: u64) -> Interpreter {
+            self.max_output = Some(max);
+            self
+        }
+        pub fn steps(&self) -> u64 {
+            self.steps
+        }
+        // How many times the run has checked its limits, which
+        // `with_check_interval` cuts down.
+        pub fn limit_checks(&self) -> u64 {
+            self.limit_checks
+        }
+        // Bytes the program has printed with `.`, counted even when the
+        // output is discarded.
+        pub fn bytes_written(&self) -> u64 {
+            self.bytes_written
+        }
+        // Times a loop body was entered, counting each iteration.
+        pub fn loop_iterations(&self) -> u64 {
+            self.iterations
+        }
+        // Largest the tape has been during the run.
+        pub fn tape_high_water(&self) -> usize {
+            self.tape_high_water
+        }
+        // The run counters as one JSON object, along with the caller's
+        // measurement of the wall time.
+        pub fn stats_json(&self, wall_time: std::time::Duration) -> String {
+            format!("{{\"steps\":{},\"loop_iterations\":{},\"bytes_in\":{},\"bytes_out\":{},\"tape_high_water\":{},\"cells_touched\":{},\"wall_time_ms\":{:.3}}}",
+                self.steps, self.iterations, self.bytes_read, self.bytes_written, self.tape_high_water,
+                self.cells_touched(), wall_time.as_secs_f64() * 1000.0)
+        }
+        // Number of `,` executed.
+        pub fn input_ops(&self) -> u64 {
+            self.input_ops
+        }
+        // Whether I/O made up at least a tenth as many steps as everything
+        // else in the last run.
+        pub fn is_io_bound(&self) -> bool {
+            let io = self.bytes_written + self.input_ops;
+            io * 10 >= self.steps.saturating_sub(io)
+        }
+        pub fn with_initial_tape(mut self, tape: Vec<u8>) -> Interpreter {
+            self.initial_tape = tape;
+            self
+        }
+        // Starts every cell at `value` instead of zero, both on the initial
+        // tape past what `with_initial_tape` sets and wherever the tape
+        // grows. Clears and `,` at end of input still store zero.
+        pub fn with_fill_value(mut self, value: u8) -> Interpreter {
+            self.fill = value;
+            self
+        }
+        // Starts with `len` cells of pseudo-random bytes, the same ones for
+        // the same seed (see `RandomInput`).
+        pub fn with_seeded_tape(mut self, seed: u64, len: usize) -> Interpreter {
+            let mut random = RandomInput::new(seed);
+            self.initial_tape = (0..len).map(|_| random.next_byte()).collect();
+            self
+        }
+        // Starts the pointer at `pointer`, zero-extending the initial tape
+        // if it is shorter, so programs can move left into preloaded data.
+        pub fn with_initial_pointer(mut self, pointer: usize) -> Interpreter {
+            self.initial_pointer = pointer;
+            self
+        }
+        pub fn with_sandbox(self, eof: u8) -> Interpreter {
+            self.with_sandbox_input(SandboxInput::Byte(eof))
+        }
+        // Discards output, and has `,` read from `input` rather than the
+        // real input. An empty cycle reads as end of input.
+        pub fn with_sandbox_input(mut self, input: SandboxInput) -> Interpreter {
+            self.sandbox = Some(input);
+            self
+        }
+        pub fn with_input(mut self, input: Box<dyn std::io::Read>) -> Interpreter {
+            self.input = Some(input);
+            self
+        }
+        // Makes `step` return `NeedInput` on a `,` instead of reading, until
+        // the host supplies a byte with `provide_input` or calls `end_input`.
+        pub fn with_cooperative_input(mut self, enable: bool) -> Interpreter {
+            self.cooperative_input = enable;
+            self
+        }
+        pub fn provide_input(&mut self, byte: u8) {
+            self.pending_input.push_back(byte);
+        }
+        // Lets `,` reach the EOF policy once the provided bytes run out.
+        pub fn end_input(&mut self) {
+            self.input_ended = true;
+        }
+        // Writes `prompt` to stderr before each `,` that would block on an
+        // interactive stdin. Piped input and custom readers get no prompt.
+        pub fn with_prompt(mut self, prompt: String) -> Interpreter {
+            self.prompt = Some(prompt);
+            self
+        }
+        // Replaces the check for whether stdin is a terminal.
+        pub fn with_tty_detector(mut self, is_tty: fn() -> bool) -> Interpreter {
+            self.is_tty = is_tty;
+            self
+        }
+        pub fn with_eof_policy(mut self, policy: EofPolicy) -> Interpreter {
+            self.eof_policy = policy;
+            self
+        }
+        // Treats a newline read by `,` as the end of input.
+        pub fn with_newline_is_eof(mut self, enable: bool) -> Interpreter {
+            self.newline_is_eof = enable;
+            self
+        }
+        // Makes reading past the end of input an error instead of applying
+        // the EOF policy.
+        pub fn with_require_input(mut self, enable: bool) -> Interpreter {
+            self.require_input = enable;
+            self
+        }
+        pub fn with_input_encoding(mut self, encoding: InputEncoding) -> Interpreter {
+            self.input_encoding = encoding;
+            self
+        }
+        pub fn with_output(mut self, output: Box<dyn std::io::Write>) -> Interpreter {
+            self.output = Some(output);
+            self
+        }
+        // Logs every byte `,` reads and `.` prints to `transcript`, one line
+        // each in the order they happen, with the milliseconds since the
+        // run began: "12.345ms in 104 'h'". End of input is logged as EOF.
+        pub fn with_transcript(mut self, transcript: Box<dyn std::io::Write>) -> Interpreter {
+            self.transcript = Some(transcript);
+            self
+        }
+        fn log_transcript(&mut self, direction: &str, byte: Option<u8>) -> Result<(), Error> {
+            let Some(log) = self.transcript.as_mut() else {
+                return Ok(());
+            };
+            let ms = self.epoch.map_or(0.0, |e| e.elapsed().as_secs_f64() * 1000.0);
+            let result = match byte {
+                Some(b) if b.is_ascii() => writeln!(log, "{:.3}ms {} {} '{}'", ms, direction, b, b.escape_ascii()),
+                Some(b) => writeln!(log, "{:.3}ms {} {}", ms, direction, b),
+                None => writeln!(log, "{:.3}ms {} EOF", ms, direction),
+            };
+            result.map_err(|e| Error::Io(e.to_string()))
+        }
+        // Flushes the output writer after every newline, so a buffered
+        // writer still interleaves prompts and responses over a pipe.
+        pub fn with_line_buffered(mut self, enable: bool) -> Interpreter {
+            self.line_buffered = enable;
+            self
+        }
+        // Collects output in memory instead of writing it; see `take_output`.
+        pub fn with_captured_output(mut self) -> Interpreter {
+            self.captured = Some(Vec::<u8>::new());
+            self
+        }
+        // Records each byte `,` delivers, for `consumed_input`.
+        pub fn with_consumed_input(mut self, enable: bool) -> Interpreter {
+            self.consumed = if enable { Some(Vec::<u8>::new()) } else { None };
+            self
+        }
+        // The bytes `,` has delivered this run, in order, after decoding;
+        // reads at end of input add nothing. Empty unless recorded.
+        pub fn consumed_input(&self) -> &[u8] {
+            self.consumed.as_deref().unwrap_or(&[])
+        }
+        // Drops all buffered I/O: captured output, output not yet written,
+        // input given with `provide_input`, and the copy replayable input
+        // is served from, which is read again when next prepared.
+        pub fn clear_io_buffers(&mut self) {
+            self.pending_input.clear();
+            self.input_ended = false;
+            if let Some(v) = self.captured.as_mut() {
+                v.clear();
+            }
+            self.pending_run = None;
+            self.replay = None;
+            self.replay_pos = 0;
+        }
+        // Input held by the interpreter that `,` has not taken yet: the
+        // rest of replayable input, then bytes given with `provide_input`.
+        pub fn buffered_input(&self) -> Vec<u8> {
+            let mut bytes = match self.replay.as_ref() {
+                Some(all) => all[self.replay_pos.min(all.len())..].to_vec(),
+                None => Vec::<u8>::new(),
+            };
+            bytes.extend(self.pending_input.iter());
+            bytes
+        }
+        pub fn take_output(&mut self) -> Vec<u8> {
+            match self.captured.as_mut() {
+                Some(v) => std::mem::take(v),
+                None => Vec::<u8>::new(),
+            }
+        }
+        pub fn with_output_format(mut self, format: OutputFormat) -> Interpreter {
+            self.output_format = format;
+            self
+        }
+        // Masks every printed byte to 7 bits before it is formatted.
+        pub fn with_ascii7(mut self, enable: bool) -> Interpreter {
+            self.ascii7 = enable;
+            self
+        }
+        pub fn with_ensure_newline(mut self, enable: bool) -> Interpreter {
+            self.ensure_newline = enable;
+            self
+        }
+        pub fn with_profile(mut self, enable: bool) -> Interpreter {
+            self.profile = enable;
+            self
+        }
+        // Measures the wall-clock time spent in each loop, nested loops
+        // included. Reading the clock around every loop entry is costly for
+        // programs with many short loops, and forces the unoptimized tree,
+        // so the times are only good for comparing loops with each other.
+        pub fn with_loop_timing(mut self, enable: bool) -> Interpreter {
+            self.loop_timing = enable;
+            self
+        }
+        // Keeps a weighted step count during `run`, where each step weighs
+        // one more than the number of loops it runs inside, so work in hot
+        // inner loops counts for more. Forces the unoptimized tree.
+        pub fn with_work(mut self, enable: bool) -> Interpreter {
+            self.work = if enable { Some(0) } else { None };
+            self
+        }
+        pub fn work(&self) -> Option<u64> {
+            self.work
+        }
+        // Counts how many times each command runs, for `counted_source`.
+        // A `[` counts its test on entry and a `]` each test at the end of
+        // the body. Forces the unoptimized tree.
+        pub fn with_op_counts(mut self, enable: bool) -> Interpreter {
+            self.op_counts = if enable { Some(Vec::<u64>::new()) } else { None };
+            self
+        }
+        // Time spent in each loop as (offset of its '[', time), slowest
+        // first; needs loop timing.
+        pub fn loop_times(&self) -> Vec<(usize, std::time::Duration)> {
+            let mut times : Vec<(usize, std::time::Duration)> = self.loop_times.iter().map(|(&o, &t)| (o, t)).collect();
+            times.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            times
+        }
+        pub fn cell_access(&self) -> &[CellAccess] {
+            &self.access
+        }
+        // Number of cells the program read or wrote; needs profiling.
+        pub fn cells_touched(&self) -> usize {
+            self.access.iter().filter(|a| a.reads + a.writes > 0).count()
+        }
+        pub fn source(&self) -> &[u8] {
+            &self.source
+        }
+        // Shows cells as signed bytes (200 as -56) in tape dumps and views.
+        // Arithmetic is unaffected.
+        pub fn with_signed_display(mut self, enable: bool) -> Interpreter {
+            self.signed_display = enable;
+            self
+        }
+        pub fn display_cell(&self, value: u8) -> String {
+            if self.signed_display { (value as i8).to_string() } else { value.to_string() }
+        }
+        // The instruction tree, built when the program is parsed (by
+        // `prepare`, `run` and the like) and empty before that. Flat mode
+        // builds it only for what needs it, such as `warnings` or `to_c`.
+        pub fn instructions(&self) -> &[Instruction] {
+            &self.inst
+        }
+        pub fn memory(&self) -> &[u8] {
+            &self.memory
+        }
+        pub fn pointer(&self) -> usize {
+            self.adress
+        }
+        // The pointer counted from the start of the initial tape, which
+        // unlike `pointer` stays put when the tape grows to the left.
+        // Cells grown on the left are negative.
+        pub fn logical_pointer(&self) -> isize {
+            self.adress as isize - self.left_grown as isize
+        }
+        // Saves the tape, the pointer and the position in the program, to
+        // go back to with `restore`. Output and input are not part of it.
+        pub fn snapshot(&self) -> Snapshot {
+            Snapshot{
+                memory: self.memory.clone(),
+                adress: self.adress,
+                other_memory: self.other_memory.clone(),
+                other_adress: self.other_adress,
+                on_second: self.on_second,
+                pc: self.pc,
+                depth: self.depth,
+                steps: self.steps,
+            }
+        }
+        pub fn restore(&mut self, snapshot: &Snapshot) {
+            self.memory = snapshot.memory.clone();
+            self.adress = snapshot.adress;
+            self.other_memory = snapshot.other_memory.clone();
+            self.other_adress = snapshot.other_adress;
+            self.on_second = snapshot.on_second;
+            self.pc = snapshot.pc;
+            self.depth = snapshot.depth;
+            self.steps = snapshot.steps;
+            self.next_check = 0;
+            if self.profile {
+                self.access.resize(self.memory.len(), CellAccess::default());
+                self.other_access.resize(self.other_memory.len(), CellAccess::default());
+            }
+            if let Some(log) = self.undo.as_mut() {
+                log.clear();
+            }
+        }
+        // Writes the program's commands and everything `step` needs to go
+        // on from here, for `load_image`. The input is recorded only as the
+        // number of bytes already read from it.
+        pub fn save_image(&self, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+            let ops : Vec<u8> = self.ops.iter().map(|op| char_of(*op)).collect();
+            out.write_all(IMAGE_MAGIC)?;
+            write_block(out, &ops)?;
+            for n in [self.pc, self.depth, self.adress, self.other_adress, self.left_grown] {
+                out.write_all(&(n as u64).to_le_bytes())?;
+            }
+            for n in [self.steps, self.iterations, self.input_ops, self.bytes_read, self.bytes_written] {
+                out.write_all(&n.to_le_bytes())?;
+            }
+            out.write_all(&[self.on_second as u8, self.last_output.is_some() as u8, self.last_output.unwrap_or(0), self.fill])?;
+            write_block(out, &self.memory)?;
+            write_block(out, &self.other_memory)
+        }
+        // Writes the tape as a `width` x `height` grayscale (P5) PPM image,
+        // one byte per cell, zero-padding or truncating the tape to fit.
+        pub fn dump_ppm(&self, width: usize, height: usize, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+            let len = width.checked_mul(height)
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "the image is too large"))?;
+            write!(out, "P5\n{} {}\n255\n", width, height)?;
+            let cells = &self.memory[..self.memory.len().min(len)];
+            out.write_all(cells)?;
+            std::io::copy(&mut std::io::Read::take(std::io::repeat(0), (len - cells.len()) as u64), out)?;
+            Ok(())
+        }
+        // Writes the pointer, run counters and tape as one JSON object.
+        pub fn dump_tape_json(&self, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+            let cells : Vec<String> = self.memory.iter().map(|v| self.display_cell(*v)).collect();
+            writeln!(out, "{{\"pointer\":{},\"steps\":{},\"bytes_written\":{},\"tape\":[{}]}}",
+                self.adress, self.steps, self.bytes_written, cells.join(","))
+        }
+        // A 64-bit FNV-1a hash of the pointer, the tape and any captured
+        // output. Zero cells beyond the pointer at either end are left out,
+        // so how far the tape happened to grow does not matter.
+        pub fn state_hash(&self) -> u64 {
+            let start = self.memory[..self.adress].iter().take_while(|&&v| v == 0).count();
+            let end = self.memory.len() - self.memory[self.adress + 1..].iter().rev().take_while(|&&v| v == 0).count();
+            let output : &[u8] = self.captured.as_deref().unwrap_or(&[]);
+            let mut hash : u64 = 0xcbf2_9ce4_8422_2325;
+            let mut feed = |bytes: &[u8]| {
+                for b in bytes.iter() {
+                    hash ^= *b as u64;
+                    hash = hash.wrapping_mul(0x0100_0000_01b3);
+                }
+            };
+            feed(&((self.adress - start) as u64).to_le_bytes());
+            feed(&((end - start) as u64).to_le_bytes());
+            feed(&self.memory[start..end]);
+            feed(&(output.len() as u64).to_le_bytes());
+            feed(output);
+            hash
+        }
+        // Drops the cells still at the fill value at both ends of both tapes,
+        // so regrowing them reads the same, and releases the freed memory.
+        // The cells under the pointers are always kept, and on the left
+        // only cells the tape grew by are dropped, so `pointer` shifts but
+        // the logical pointer still refers to the same cell.
+        pub fn shrink_tape(&mut self) {
+            let dropped = trim_tape(&mut self.memory, &mut self.access, self.adress, self.left_grown, self.fill);
+            self.adress -= dropped;
+            self.left_grown -= dropped;
+            trim_tape(&mut self.other_memory, &mut self.other_access, self.other_adress, 0, self.fill);
+            if let Some(log) = self.undo.as_mut() {
+                log.clear();
+            }
+        }
+        pub fn set_cell(&mut self, index: usize, value: u8) -> Result<(), String> {
+            match self.memory.get_mut(index) {
+                Some(cell) => {
+                    *cell = value;
+                    Ok(())
+                }
+                None => Err(format!("Cell {} is out of the tape (length {}).", index, self.memory.len())),
+            }
+        }
+        pub fn run(&mut self) -> Result<(), Error> {
+            self.prepare()?;
+            self.eval_instruction()?;
+            self.finish();
+            Ok(())
+        }
+        // Runs each of `sources` in turn, in place of the interpreter's own
+        // source, on one tape: only the first starts from the initial tape,
+        // and each later one begins where the previous one left the pointer.
+        // The source left in place is the last one.
+        pub fn run_sequence<S: AsRef<[u8]>>(&mut self, sources: &[S]) -> Result<(), Error> {
+            for (i, source) in sources.iter().enumerate() {
+                self.source = strip_bom(source.as_ref()).to_vec();
+                self.validated = false;
+                if i == 0 {
+                    self.prepare()?;
+                } else {
+                    self.parse()?;
+                    self.pc = 0;
+                }
+                self.eval_instruction()?;
+            }
+            self.finish();
+            Ok(())
+        }
+        fn finish(&mut self) {
+            self.flush_run();
+            if let Some(log) = self.transcript.as_mut() {
+                let _ = log.flush();
+            }
+            if self.ensure_newline {
+                match self.last_output {
+                    Some(b'\n') | None => (),
+                    Some(_) => self.write_raw(b"\n"),
+                }
+            }
+        }
+        // Prepares the machine, then either stops before the first
+        // instruction (with `with_start_paused`) or runs to completion.
+        pub fn start(&mut self) -> Result<Status, Error> {
+            self.prepare()?;
+            if !self.start_paused {
+                return self.resume();
+            }
+            if self.pc < self.ops.len() {
+                Ok(Status::Paused)
+            } else {
+                Ok(Status::Finished)
+            }
+        }
+        // Steps from the current position until the program finishes or
+        // needs input.
+        pub fn resume(&mut self) -> Result<Status, Error> {
+            loop {
+                match self.step()? {
+                    Status::Running => (),
+                    status => return Ok(status),
+                }
+            }
+        }
+        // Parses the source and resets the machine, leaving it ready for
+        // either `run`-style evaluation or `step`.
+        pub fn prepare(&mut self) -> Result<(), Error> {
+            self.parse()?;
+            if self.replayable && self.replay.is_none() {
+                let mut all = Vec::<u8>::new();
+                match self.input.as_mut() {
+                    Some(reader) => reader.read_to_end(&mut all),
+                    None => std::io::Read::read_to_end(&mut std::io::stdin(), &mut all),
+                }.map_err(|e| Error::InvalidInput(e.to_string()))?;
+                self.replay = Some(all);
+            }
+            self.reset();
+            Ok(())
+        }
+        // Restores the initial tape, pointer and counters without parsing
+        // the source again. Captured output and input given with
+        // `provide_input` are dropped; replayable input is rewound.
+        pub fn reset(&mut self) {
+            self.pending_input.clear();
+            self.input_ended = false;
+            if let Some(v) = self.captured.as_mut() {
+                v.clear();
+            }
+            if let Some(v) = self.consumed.as_mut() {
+                v.clear();
+            }
+            self.memory = self.initial_tape.clone();
+            if self.memory.len() <= self.initial_pointer {
+                self.memory.resize(self.initial_pointer + 1, self.fill);
+            }
+            self.adress = self.initial_pointer;
+            self.left_grown = 0;
+            self.other_memory = vec![self.fill];
+            self.other_adress = 0;
+            self.on_second = false;
+            self.pc = 0;
+            self.steps = 0;
+            self.lookahead = None;
+            self.next_check = 0;
+            self.limit_checks = 0;
+            self.tape_locked = false;
+            self.left_warned = false;
+            self.epoch = if self.transcript.is_some() || self.max_time.is_some() {
+                Some(std::time::Instant::now())
+            } else {
+                None
+            };
+            self.replay_pos = 0;
+            self.sandbox_pos = 0;
+            self.skip_input = 0;
+            self.depth = 0;
+            self.prepared = true;
+            self.last_output = None;
+            self.pending_run = None;
+            self.bytes_written = 0;
+            self.input_ops = 0;
+            self.bytes_read = 0;
+            self.iterations = 0;
+            self.loop_times.clear();
+            if let Some(counts) = self.op_counts.as_mut() {
+                counts.clear();
+            }
+            if self.work.is_some() {
+                self.work = Some(0);
+            }
+            if let Some(log) = self.undo.as_mut() {
+                log.clear();
+            }
+            self.tape_high_water = self.memory.len();
+            self.access.clear();
+            self.other_access.clear();
+            if self.profile {
+                self.access.resize(self.memory.len(), CellAccess::default());
+                self.other_access.resize(self.other_memory.len(), CellAccess::default());
+            }
+        }
+        // Parses once, then evaluates the program `repeat` times from a fresh
+        // machine, returning the evaluation time of each run.
+        pub fn bench(&mut self, repeat: usize) -> Result<Vec<std::time::Duration>, Error> {
+            self.parse()?;
+            let mut times = Vec::<std::time::Duration>::with_capacity(repeat);
+            for _ in 0..repeat {
+                self.reset();
+                let start = std::time::Instant::now();
+                self.eval_instruction()?;
+                times.push(start.elapsed());
+            }
+            Ok(times)
+        }
+        // Runs the program from a fresh machine on `input` with its output
+        // discarded, and returns how many commands it executed, counting
+        // each loop test. Past the end of `input`, `,` follows the EOF
+        // policy. Optimization is ignored, so the count does not depend
+        // on it.
+        pub fn dry_count(&mut self, input: &[u8]) -> Result<u64, Error> {
+            self.parse_tree()?;
+            // Output captured before is kept, though `reset` would drop it.
+            let captured = self.captured.take();
+            self.reset();
+            let output = self.output.replace(Box::new(std::io::sink()));
+            let reader = self.input.replace(Box::new(std::io::Cursor::new(input.to_vec())));
+            let replay = self.replay.take();
+            let sandbox = self.sandbox.take();
+            let cooperative = std::mem::replace(&mut self.cooperative_input, false);
+            let inst = std::mem::take(&mut self.inst);
+            let result = self.eval_liner(&inst, 0);
+            self.inst = inst;
+            self.captured = captured;
+            self.output = output;
+            self.input = reader;
+            self.replay = replay;
+            self.sandbox = sandbox;
+            self.cooperative_input = cooperative;
+            self.pending_run = None;
+            result.map(|_| self.steps)
+        }
+        pub fn position(&self) -> usize {
+            self.pc
+        }
+        pub fn source_offset(&self) -> Option<usize> {
+            self.offsets.get(self.pc).copied()
+        }
+        // When stopped on a `[`, the position just past its matching `]`.
+        pub fn loop_exit(&self) -> Option<usize> {
+            match self.ops.get(self.pc) {
+                Some(Op::LoopStart) => Some(self.jumps[self.pc] + 1),
+                _ => None,
+            }
+        }
+        // The source and every setting that shapes its parse.
+        fn parse_key(&self) -> u64 {
+            use std::hash::{Hash, Hasher};
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            (&self.source, self.dual_tape, self.strict, self.flat, self.opt_level, &self.passes, &self.markers).hash(&mut hasher);
+            hasher.finish()
+        }
+        // How many times the source has been parsed. Parsing is skipped
+        // while neither the source nor the settings it depends on change.
+        pub fn parse_count(&self) -> u64 {
+            self.parses
+        }
+        fn parse(&mut self) -> Result<(), Error> {
+            let key = self.parse_key();
+            if self.parsed_key == Some(key) && self.validated {
+                return Ok(());
+            }
+            if !self.validated {
+                self.validate()?;
+                self.validated = true;
+            }
+            self.lex_code()?;
+            self.build_jumps()?;
+            free_instructions(std::mem::take(&mut self.inst));
+            self.optimized = Vec::<Node>::new();
+            self.has_tree = false;
+            // Flat mode runs the bytecode alone, so it leaves the tree until
+            // something asks for it.
+            if self.flat {
+                self.compact = compile(&self.ops)?;
+            } else {
+                self.compact = Vec::<CompactOp>::new();
+                self.build_tree()?;
+            }
+            self.parsed_key = Some(key);
+            self.parses += 1;
+            Ok(())
+        }
+        // Parses the source, then builds the tree if flat mode skipped it.
+        fn parse_tree(&mut self) -> Result<(), Error> {
+            self.parse()?;
+            self.build_tree()
+        }
+        // The instruction tree and, when optimizing, its optimized form.
+        fn build_tree(&mut self) -> Result<(), Error> {
+            if self.has_tree {
+                return Ok(());
+            }
+            self.build_instruction()?;
+            if self.optimizing() {
+                self.check_opt_nesting()?;
+            }
+            self.optimized = if let Some(passes) = self.passes.as_ref() {
+                optimizer::optimize(&self.inst, passes)
+            } else if self.opt_level > 0 {
+                optimizer::optimize(&self.inst, &optimizer::level_passes(self.opt_level))
+            } else {
+                Vec::<Node>::new()
+            };
+            self.has_tree = true;
+            Ok(())
+        }
+        fn check_opt_nesting(&self) -> Result<(), Error> {
+            let mut depth = 0;
+            for (op, offset) in self.ops.iter().zip(self.offsets.iter()) {
+                match op {
+                    Op::LoopStart => {
+                        depth += 1;
+                        if depth > MAX_OPT_NESTING {
+                            return Err(Error::NestingLimitExceeded(MAX_OPT_NESTING, *offset));
+                        }
+                    }
+                    Op::LoopEnd => depth -= 1,
+                    _ => (),
+                }
+            }
+            Ok(())
+        }
+        pub fn warnings(&mut self) -> Result<Vec<lint::Warning>, Error> {
+            self.parse_tree()?;
+            let start = self.initial_tape.get(self.initial_pointer).copied().unwrap_or(self.fill);
+            Ok(lint::check(&self.inst, &self.offsets, Some(start)))
+        }
+        // Stretches of code that can never run, found without running.
+        pub fn unreachable(&mut self) -> Result<Vec<lint::Span>, Error> {
+            self.parse_tree()?;
+            let bounded = !self.left_growth || self.max_tape.is_some();
+            Ok(lint::unreachable(&self.inst, &self.offsets, &self.initial_tape, self.initial_pointer, self.fill, bounded))
+        }
+        // Refuses programs that certainly reach a loop they can never leave.
+        pub fn check_termination(&mut self) -> Result<(), Error> {
+            self.parse_tree()?;
+            let bounded = !self.left_growth || self.max_tape.is_some();
+            match lint::endless_loop(&self.inst, &self.offsets, &self.initial_tape, self.initial_pointer, self.fill, bounded) {
+                Some(offset) => Err(Error::EndlessLoop(offset)),
+                None => Ok(()),
+            }
+        }
+        // 1-based (line, column) of each lexed command.
+        fn positions(&self) -> Vec<(usize, usize)> {
+            let mut positions = Vec::<(usize, usize)>::with_capacity(self.offsets.len());
+            let (mut line, mut line_start, mut scanned) = (1, 0, 0);
+            for &offset in self.offsets.iter() {
+                for (i, c) in self.source[scanned..offset].iter().enumerate() {
+                    if *c == b'\n' {
+                        line += 1;
+                        line_start = scanned + i + 1;
+                    }
+                }
+                scanned = offset;
+                positions.push((line, String::from_utf8_lossy(&self.source[line_start..offset]).chars().count() + 1));
+            }
+            positions
+        }
+        pub fn to_c(&mut self, source_map: bool) -> Result<String, Error> {
+            self.parse_tree()?;
+            // The optimized form no longer maps to source positions.
+            if self.optimizing() && !source_map {
+                return Ok(codegen::nodes_to_c(&self.optimized));
+            }
+            let positions = if source_map { Some(self.positions()) } else { None };
+            Ok(codegen::to_c(&self.inst, positions.as_deref()))
+        }
+        pub fn to_rust(&mut self, source_map: bool) -> Result<String, Error> {
+            self.parse_tree()?;
+            let positions = if source_map { Some(self.positions()) } else { None };
+            Ok(codegen::to_rust(&self.inst, positions.as_deref()))
+        }
+        pub fn to_sexpr(&mut self) -> Result<String, Error> {
+            self.parse_tree()?;
+            Ok(codegen::to_sexpr(&self.inst))
+        }
+        pub fn annotated_source(&mut self) -> Result<String, Error> {
+            self.parse_tree()?;
+            Ok(codegen::to_annotated(&self.inst))
+        }
+        // Each command of the source on its own line, after how many times
+        // it ran and its line and column; needs op counts.
+        pub fn counted_source(&mut self) -> Result<String, Error> {
+            self.parse()?;
+            let counts = self.op_counts.as_deref().unwrap_or(&[]);
+            let mut listing = String::new();
+            for (i, (line, column)) in self.positions().into_iter().enumerate() {
+                let count = counts.get(i).copied().unwrap_or(0);
+                let c = self.source[self.offsets[i]] as char;
+                listing += &format!("{:>10}  {}:{}  {}\n", count, line, column, c);
+            }
+            Ok(listing)
+        }
+        // Every loop in source order.
+        pub fn loops(&mut self) -> Result<Vec<LoopSpan>, Error> {
+            self.parse()?;
+            let mut spans = Vec::<LoopSpan>::new();
+            let mut depth = 0;
+            for (pc, op) in self.ops.iter().enumerate() {
+                match op {
+                    Op::LoopStart => {
+                        depth += 1;
+                        spans.push(LoopSpan{ start: self.offsets[pc], end: self.offsets[self.jumps[pc]], depth });
+                    }
+                    Op::LoopEnd => depth -= 1,
+                    _ => (),
+                }
+            }
+            Ok(spans)
+        }
+        // The loop nesting as an indented tree, one loop per line with its
+        // bracket offsets and the number of instructions in its body.
+        pub fn loop_tree(&mut self) -> Result<String, Error> {
+            self.parse_tree()?;
+            let mut out = String::new();
+            let mut index = 0;
+            self.write_loop_tree(&self.inst, &mut index, 0, &mut out);
+            Ok(out)
+        }
+        // Whether both programs parse to the same instruction tree, so that
+        // comments and formatting are ignored but every command and loop
+        // must match.
+        pub fn same_structure(&mut self, other: &mut Interpreter) -> Result<bool, Error> {
+            self.parse_tree()?;
+            other.parse_tree()?;
+            Ok(self.inst == other.inst)
+        }
+        fn write_loop_tree(&self, inst: &[Instruction], index: &mut usize, depth: usize, out: &mut String) {
+            for i in inst.iter() {
+                if let Instruction::Nop{ .. } = i {
+                    continue;
+                }
+                if let Instruction::Loop(body) = i {
+                    out.push_str(&format!("{}loop {}..{} ({} ops)\n", "  ".repeat(depth),
+                        self.offsets[*index], self.offsets[self.jumps[*index]], count_instructions(body)));
+                    *index += 1;
+                    self.write_loop_tree(body, index, depth + 1, out);
+                }
+                *index += 1;
+            }
+        }
+        // How far the pointer can travel either way, or None when some loop
+        // may move it further each time round.
+        pub fn pointer_range(&mut self) -> Result<Option<PointerRange>, Error> {
+            self.parse_tree()?;
+            let mut range = PointerRange{ min: 0, max: 0 };
+            Ok(walk_pointer(&self.inst, &mut 0, &mut range).then_some(range))
+        }
+        pub fn metrics(&mut self) -> Result<Metrics, Error> {
+            self.parse_tree()?;
+            let mut m = Metrics{ comment_chars: self.comment_chars, ..Metrics::default() };
+            collect_metrics(&self.inst, 0, &mut m);
+            Ok(m)
+        }
+        // Runs one command. The step that ends the program also finishes
+        // the output as `run` does, writing out a pending run-length group
+        // and the newline `with_ensure_newline` asks for.
+        pub fn step(&mut self) -> Result<Status, Error> {
+            if self.pc >= self.ops.len() {
+                return Ok(Status::Finished);
+            }
+            if self.ops[self.pc] == Op::GetChar && self.cooperative_input
+                && self.pending_input.is_empty() && !self.input_ended {
+                return Ok(Status::NeedInput);
+            }
+            if self.ops[self.pc] == Op::GetChar && !self.cooperative_input && self.sandbox.is_none()
+                && self.replay.is_none() && self.lookahead.is_none() {
+                self.show_prompt();
+                // Any other read error is reported by the `,` itself.
+                match self.next_input_byte() {
+                    Ok(b) => self.lookahead = Some(b),
+                    Err(Error::InputNotReady) => return Ok(Status::NeedInput),
+                    Err(_) => (),
+                }
+            }
+            let undo = self.undo.is_some().then(|| self.undo_entry());
+            let (op, offset) = (self.ops[self.pc], self.offsets[self.pc]);
+            match op {
+                Op::LoopStart => {
+                    if !self.test_cell()? {
+                        self.pc = self.jumps[self.pc];
+                    }
+                }
+                Op::LoopEnd => {
+                    if self.test_cell()? {
+                        self.pc = self.jumps[self.pc];
+                    }
+                }
+                op => self.execute_at(op, self.pc)?,
+            }
+            if let (Some(log), Some(undo)) = (self.undo.as_mut(), undo) {
+                log.push(undo);
+            }
+            if let Some(hook) = self.hook.as_mut() {
+                hook(&StepInfo{
+                    op,
+                    offset,
+                    pointer: self.adress,
+                    cell: self.memory[self.adress],
+                    step: self.steps,
+                });
+            }
+            self.pc += 1;
+            if self.pc >= self.ops.len() {
+                self.finish();
+                Ok(Status::Finished)
+            } else {
+                Ok(Status::Running)
+            }
+        }
+        // Makes every later `+`, `-` or `,` fail with `TapeLocked` while
+        // moves, loops and `.` go on working. Meant for a paused program
+        // driven by `step` or `resume`; `reset` unlocks the tape again.
+        pub fn lock_tape(&mut self) {
+            self.tape_locked = true;
+        }
+        // Calls `hook` after every op run by `step`, replacing any earlier
+        // hook. `run` and the other evaluators do not call it.
+        pub fn set_hook(&mut self, hook: StepHook) {
+            self.hook = Some(hook);
+        }
+        // Adds a second tape with its own pointer; `%` switches which one the
+        // other commands work on. The second tape starts empty, at cell 0.
+        pub fn with_dual_tape(mut self, enable: bool) -> Interpreter {
+            self.dual_tape = enable;
+            self
+        }
+        fn switch_tape(&mut self) {
+            std::mem::swap(&mut self.memory, &mut self.other_memory);
+            std::mem::swap(&mut self.adress, &mut self.other_adress);
+            std::mem::swap(&mut self.access, &mut self.other_access);
+            self.on_second = !self.on_second;
+        }
+        // Reads all of the input when the program is prepared and serves `,`
+        // from that copy, so `step_back` and `reset` rewind the input too.
+        // Cooperative input is not affected.
+        pub fn with_replayable_input(mut self, enable: bool) -> Interpreter {
+            self.replayable = enable;
+            self
+        }
+        // Records every `step` so that `step_back` can undo it. The tape,
+        // pointer, counters and captured output are restored; bytes already
+        // written elsewhere cannot be taken back, and neither can input
+        // unless it is replayable.
+        pub fn with_undo(mut self, enable: bool) -> Interpreter {
+            self.undo = if enable { Some(Vec::<Undo>::new()) } else { None };
+            self
+        }
+        fn undo_entry(&self) -> Undo {
+            Undo{
+                pc: self.pc,
+                adress: self.adress,
+                cell: self.memory[self.adress],
+                tape_len: self.memory.len(),
+                left_grown: self.left_grown,
+                depth: self.depth,
+                steps: self.steps,
+                iterations: self.iterations,
+                input_ops: self.input_ops,
+                bytes_written: self.bytes_written,
+                output_len: self.captured.as_ref().map_or(0, |v| v.len()),
+                consumed_len: self.consumed.as_ref().map_or(0, |v| v.len()),
+                last_output: self.last_output,
+                replay_pos: self.replay_pos,
+                bytes_read: self.bytes_read,
+                on_second: self.on_second,
+            }
+        }
+        // Undoes the latest `step`, returning false when there is none left
+        // to undo or undo is not enabled.
+        pub fn step_back(&mut self) -> bool {
+            let undo = match self.undo.as_mut().and_then(|log| log.pop()) {
+                Some(undo) => undo,
+                None => return false,
+            };
+            // A `%` changes no cell, so undoing one only switches back.
+            if self.on_second != undo.on_second {
+                self.switch_tape();
+            }
+            let shift = self.left_grown - undo.left_grown;
+            self.memory.drain(..shift);
+            self.memory.truncate(undo.tape_len);
+            if self.profile {
+                self.access.drain(..shift);
+                self.access.truncate(undo.tape_len);
+            }
+            self.left_grown = undo.left_grown;
+            self.adress = undo.adress;
+            self.memory[self.adress] = undo.cell;
+            self.pc = undo.pc;
+            self.depth = undo.depth;
+            self.steps = undo.steps;
+            self.next_check = 0;
+            self.iterations = undo.iterations;
+            self.input_ops = undo.input_ops;
+            self.bytes_written = undo.bytes_written;
+            if let Some(v) = self.captured.as_mut() {
+                v.truncate(undo.output_len);
+            }
+            if let Some(v) = self.consumed.as_mut() {
+                v.truncate(undo.consumed_len);
+            }
+            self.last_output = undo.last_output;
+            self.replay_pos = undo.replay_pos;
+            self.bytes_read = undo.bytes_read;
+            true
+        }
+        pub fn validate(&self) -> Result<(), Error> {
+            if let Some(max) = self.max_source {
+                if self.source.len() > max {
+                    return Err(Error::SourceTooLarge(max));
+                }
+            }
+            bracket_map(&self.source)?;
+            if let Some(max) = self.max_nesting {
+                let mut depth = 0;
+                for (offset, c) in self.source.iter().enumerate() {
+                    match c {
+                        b'[' => {
+                            depth += 1;
+                            if depth > max {
+                                return Err(Error::NestingLimitExceeded(max, offset));
+                            }
+                        }
+                        b']' => depth -= 1,
+                        _ => (),
+                    }
+                }
+            }
+            Ok(())
+        }
+        pub fn disassemble(&mut self) -> Result<String, Error> {
+            self.parse()?;
+            let mut listing = String::new();
+            for (i, op) in compile(&self.ops)?.iter().enumerate() {
+                listing.push_str(&format!("{:04}  {}\n", i, op));
+            }
+            Ok(listing)
+        }
+        // The compiled bytecode in the text form `from_asm` reads, with
+        // loop bodies indented.
+        pub fn to_asm(&mut self) -> Result<String, Error> {
+            self.parse()?;
+            let mut text = String::new();
+            let mut depth = 0;
+            for op in compile(&self.ops)?.iter() {
+                if op.code == OpCode::JumpIfNonZero {
+                    depth -= 1;
+                }
+                let line = match op.code {
+                    OpCode::Add => format!("add {}", op.arg),
+                    OpCode::Move => format!("move {}", op.arg),
+                    OpCode::Out => String::from("out"),
+                    OpCode::In => String::from("in"),
+                    OpCode::JumpIfZero => String::from("loop"),
+                    OpCode::JumpIfNonZero => String::from("endloop"),
+                    OpCode::SwitchTape => String::from("tape"),
+                };
+                text.push_str(&format!("{}{}\n", "  ".repeat(depth), line));
+                if op.code == OpCode::JumpIfZero {
+                    depth += 1;
+                }
+            }
+            Ok(text)
+        }
+        // The `disassemble` listing with the source span and commands each
+        // op was fused from, as `src[4..9] '+++++'  =>  Add +5`.
+        pub fn explain_opt(&mut self) -> Result<String, Error> {
+            self.parse()?;
+            let (code, spans) = compile_spans(&self.ops)?;
+            let mut listing = String::new();
+            for (op, &(first, last)) in code.iter().zip(spans.iter()) {
+                let commands : String = self.ops[first..=last].iter().map(|&op| char_of(op) as char).collect();
+                listing.push_str(&format!("src[{}..{}] '{}'  =>  {}\n",
+                    self.offsets[first], self.offsets[last] + 1, commands, op));
+            }
+            Ok(listing)
+        }
+        // The program optimized with the configured passes (or at least
+        // level 1) and lowered back to brainfuck.
+        pub fn optimized_source(&mut self) -> Result<String, Error> {
+            self.parse_tree()?;
+            self.check_opt_nesting()?;
+            let nodes = if self.optimizing() {
+                self.optimized.clone()
+            } else {
+                optimizer::optimize(&self.inst, &optimizer::level_passes(1))
+            };
+            Ok(optimizer::to_source(&nodes))
+        }
+        // How each pass selected by the optimization level or `with_passes`
+        // changed the size of the tree, in the order they run.
+        pub fn opt_report(&mut self) -> Result<Vec<optimizer::PassReport>, Error> {
+            self.parse_tree()?;
+            self.check_opt_nesting()?;
+            let passes = self.passes.clone().unwrap_or_else(|| optimizer::level_passes(self.opt_level));
+            Ok(optimizer::optimize_report(&self.inst, &passes).1)
+        }
+        // The size of the program, in nodes, optimized at each level from 0
+        // to `max_level`.
+        pub fn size_table(&mut self, max_level: u8) -> Result<Vec<usize>, Error> {
+            self.parse_tree()?;
+            self.check_opt_nesting()?;
+            Ok((0..=max_level.min(MAX_OPT_LEVEL))
+                .map(|level| optimizer::count_nodes(&optimizer::optimize(&self.inst, &optimizer::level_passes(level))))
+                .collect())
+        }
+        // The program's net effect, found by optimizing it fully, or None if
+        // loops or I/O remain afterwards.
+        pub fn summarize(&mut self) -> Result<Option<optimizer::Summary>, Error> {
+            self.parse_tree()?;
+            self.check_opt_nesting()?;
+            let nodes = optimizer::optimize(&self.inst, &optimizer::level_passes(MAX_OPT_LEVEL));
+            Ok(optimizer::summarize(&nodes))
+        }
+        fn lex_code(&mut self) -> Result<(), Error> {
+            self.ops.clear();
+            self.offsets.clear();
+            self.comment_chars = 0;
+            for (offset, c) in self.source.iter().enumerate() {
+                let op = if *c == b'%' && self.dual_tape { Some(Op::SwitchTape) } else { op_of(*c) };
+                if op.is_none() && !c.is_ascii_whitespace() {
+                    if self.strict {
+                        return Err(Error::UnknownCharacter(char_at(&self.source, offset), offset));
+                    }
+                    // Count each UTF-8 character once, by its leading byte.
+                    if *c & 0xc0 != 0x80 {
+                        self.comment_chars += 1;
+                    }
+                }
+                if let Some(v) = op {
+                    self.ops.push(v);
+                    self.offsets.push(offset);
+                }
+            }
+            Ok(())
+        }
+        fn build_jumps(&mut self) -> Result<(), Error> {
+            let mut starts = Vec::<usize>::new();
+            self.jumps = vec![0; self.ops.len()];
+            for (i, op) in self.ops.iter().enumerate() {
+                match op {
+                    Op::LoopStart => starts.push(i),
+                    Op::LoopEnd => {
+                        let start = starts.pop().ok_or(Error::Internal("unmatched ']' in the op stream"))?;
+                        self.jumps[start] = i;
+                        self.jumps[i] = start;
+                    }
+                    _ => (),
+                }
+            }
+            if !starts.is_empty() {
+                return Err(Error::Internal("unmatched '[' in the op stream"));
+            }
+            Ok(())
+        }
+        fn build_instruction(&mut self) -> Result<(), Error> {
+            let mut queue = Vec::<Vec::<Instruction>>::new();
+            let mut inst = Vec::<Instruction>::new();
+            let mut markers = self.markers.iter().peekable();
+            for (op, &offset) in self.ops.iter().zip(self.offsets.iter()) {
+                while let Some(&&src_offset) = markers.peek() {
+                    if src_offset > offset {
+                        break;
+                    }
+                    inst.push(Instruction::Nop{ src_offset });
+                    markers.next();
+                }
+                let i = match op {
+                    Op::Increment => Some(Instruction::Increment),
+                    Op::Decrement => Some(Instruction::Decrement),
+                    Op::ShiftLeft => Some(Instruction::ShiftLeft),
+                    Op::ShiftRight => Some(Instruction::ShiftRight),
+                    Op::PrintChar => Some(Instruction::PrintChar),
+                    Op::GetChar => Some(Instruction::GetChar),
+                    Op::SwitchTape => Some(Instruction::SwitchTape),
+                    Op::LoopStart => {
+                        queue.push(inst);
+                        inst = Vec::<Instruction>::new();
+                        None
+                    }
+                    Op::LoopEnd => {
+                        let mut v = queue.pop().ok_or(Error::Internal("unmatched ']' in the op stream"))?;
+                        v.push(Instruction::Loop(inst));
+                        inst = v;
+                        None
+                    }
+                };
+                if let Some(v) = i {
+                    inst.push(v);
+                }
+            }
+            if !queue.is_empty() {
+                return Err(Error::Internal("unmatched '[' in the op stream"));
+            }
+            inst.extend(markers.map(|&src_offset| Instruction::Nop{ src_offset }));
+            self.inst = inst;
+            Ok(())
+        }
+        fn eval_instruction(&mut self) -> Result<(), Error> {
+            // A locked tape is only enforced command by command, so it also
+            // needs the plain evaluator.
+            let exact = self.arith_mode == ArithMode::Wrapping && !self.keep_going && !self.loop_timing && self.work.is_none() && self.op_counts.is_none()
+                && !self.tape_locked;
+            if self.flat && exact {
+                // Without a step, time or depth limit, a window or
+                // profiling, nothing needs to be checked per op.
+                let fast = self.max_steps.is_none() && self.max_time.is_none() && self.max_depth.is_none()
+                    && self.cancel.is_none() && self.ptr_modulo.is_none() && !self.profile;
+                let code = std::mem::take(&mut self.compact);
+                let result = if fast { self.eval_compact_fast(&code) } else { self.eval_compact(&code) };
+                self.compact = code;
+                return result;
+            }
+            self.build_tree()?;
+            // Fusing assumes distinct offsets are distinct cells.
+            if self.optimizing() && exact && self.ptr_modulo.is_none() {
+                let nodes = std::mem::take(&mut self.optimized);
+                let result = self.eval_optimized(&nodes);
+                self.optimized = nodes;
+                return result;
+            }
+            let inst = std::mem::take(&mut self.inst);
+            let result = self.eval_liner(&inst, 0);
+            self.inst = inst;
+            result
+        }
+        // Adds at least `need` cells in front of the tape, shifting the
+        // pointer so it stays on the same cell.
+        fn grow_left(&mut self, need: usize) -> Result<(), Error> {
+            if !self.left_growth {
+                return Err(Error::PointerUnderflow);
+            }
+            if self.warn_left && !self.left_warned {
+                eprintln!("warning: the pointer moved left of the origin.");
+                self.left_warned = true;
+            }
+            let room = match self.max_tape {
+                Some(max) if self.memory.len() + need > max => return Err(Error::TapeLimitExceeded(max)),
+                Some(max) => max - self.memory.len(),
+                None => usize::MAX,
+            };
+            let n = need.max(self.growth_chunk).min(room);
+            self.memory.splice(0..0, std::iter::repeat_n(self.fill, n));
+            self.left_grown += n;
+            if self.profile {
+                self.access.splice(0..0, std::iter::repeat_n(CellAccess::default(), n));
+            }
+            self.adress += n;
+            self.tape_high_water = self.tape_high_water.max(self.memory.len());
+            Ok(())
+        }
+        // Extends the tape to at least `len` cells.
+        fn grow_right(&mut self, len: usize) -> Result<(), Error> {
+            let mut new_len = len.max(self.memory.len() + self.growth_chunk);
+            if let Some(max) = self.max_tape {
+                if len > max {
+                    return Err(Error::TapeLimitExceeded(max));
+                }
+                new_len = new_len.min(max);
+            }
+            self.memory.resize(new_len, self.fill);
+            if self.profile {
+                self.access.resize(new_len, CellAccess::default());
+            }
+            self.tape_high_water = self.tape_high_water.max(new_len);
+            Ok(())
+        }
+        fn count_read(&mut self) {
+            self.count_read_at(self.adress);
+        }
+        fn count_write(&mut self) {
+            self.count_write_at(self.adress);
+        }
+        fn count_read_at(&mut self, index: usize) {
+            if self.profile {
+                self.access[index].reads += 1;
+            }
+        }
+        fn count_write_at(&mut self, index: usize) {
+            if self.profile {
+                self.access[index].writes += 1;
+            }
+        }
+        // Index of the cell `offset` away from the pointer, growing the tape
+        // on either side as needed.
+        fn cell_index(&mut self, offset: isize) -> Result<usize, Error> {
+            if let Some(window) = self.ptr_modulo {
+                let target = (self.adress as isize + offset).rem_euclid(window as isize) as usize;
+                if target >= self.memory.len() {
+                    self.grow_right(target + 1)?;
+                }
+                return Ok(target);
+            }
+            let target = self.adress as isize + offset;
+            if target < 0 {
+                self.grow_left((-target) as usize)?;
+                return Ok((self.adress as isize + offset) as usize);
+            }
+            let target = target as usize;
+            if target >= self.memory.len() {
+                self.grow_right(target + 1)?;
+            }
+            Ok(target)
+        }
+        fn put_char(&mut self, index: usize) -> Result<(), Error> {
+            if let Some(max) = self.max_output {
+                if self.bytes_written >= max {
+                    return Err(Error::OutputLimitExceeded(max));
+                }
+            }
+            self.bytes_written += 1;
+            self.log_transcript("out", Some(self.memory[index]))?;
+            if self.sandbox.is_none() {
+                self.emit(self.memory[index]);
+            }
+            self.count_read_at(index);
+            Ok(())
+        }
+        fn emit(&mut self, byte: u8) {
+            let byte = if self.ascii7 { byte & 0x7f } else { byte };
+            match self.output_format {
+                OutputFormat::Raw => self.write_raw(&[byte]),
+                OutputFormat::Hex => self.write_raw(format!("{:02x}", byte).as_bytes()),
+                OutputFormat::Escaped => match byte {
+                    b'\\' => self.write_raw(b"\\\\"),
+                    b'\n' | 0x20..=0x7e => self.write_raw(&[byte]),
+                    _ => self.write_raw(format!("\\x{:02x}", byte).as_bytes()),
+                },
+                OutputFormat::Rle => match self.pending_run {
+                    Some((b, ref mut count)) if b == byte => *count += 1,
+                    _ => {
+                        self.flush_run();
+                        self.pending_run = Some((byte, 1));
+                    }
+                },
+            }
+        }
+        fn flush_run(&mut self) {
+            if let Some((byte, count)) = self.pending_run.take() {
+                let sep = if self.last_output.is_some() { " " } else { "" };
+                self.write_raw(format!("{}{}x{}", sep, byte, count).as_bytes());
+            }
+        }
+        fn write_raw(&mut self, bytes: &[u8]) {
+            let result = if let Some(v) = self.captured.as_mut() {
+                v.extend_from_slice(bytes);
+                Ok(())
+            } else if let Some(writer) = self.output.as_mut() {
+                writer.write_all(bytes).and_then(|_| {
+                    if self.line_buffered && bytes.contains(&b'\n') { writer.flush() } else { Ok(()) }
+                })
+            } else {
+                let mut stdout = std::io::stdout();
+                std::io::Write::write_all(&mut stdout, bytes).and_then(|_| {
+                    if self.line_buffered && bytes.contains(&b'\n') { std::io::Write::flush(&mut stdout) } else { Ok(()) }
+                })
+            };
+            if let Err(msg) = result {
+                panic!("{}", msg);
+            }
+            if let Some(last) = bytes.last() {
+                self.last_output = Some(*last);
+            }
+        }
+        // Next input byte, or None once the input is exhausted. Each `,`
+        // reads exactly one character, so a huge input is never slurped into
+        // memory and unread input stays in the source. An `Interrupted` read
+        // is retried and a reader with nothing ready (`WouldBlock`) gives
+        // `InputNotReady`, so only a real end of input reaches the EOF
+        // policy. Other read errors are `InvalidInput`.
+        fn read_input(&mut self) -> Result<Option<u8>, Error> {
+            if let Some(input) = self.sandbox.as_ref() {
+                let b = match input {
+                    SandboxInput::Eof => None,
+                    SandboxInput::Byte(b) => Some(*b),
+                    SandboxInput::Cycle(bytes) if bytes.is_empty() => None,
+                    SandboxInput::Cycle(bytes) => Some(bytes[self.sandbox_pos % bytes.len()]),
+                };
+                self.sandbox_pos += 1;
+                return Ok(b);
+            }
+            if self.lookahead.is_none() {
+                self.show_prompt();
+            }
+            let first = match self.read_byte()? {
+                Some(b) => b,
+                None => return Ok(None),
+            };
+            if self.input_encoding == InputEncoding::Utf8 || first < 0x80 {
+                return Ok(Some(first));
+            }
+            // Latin-1: decode one UTF-8 character and deliver it as one byte.
+            let invalid = || Error::InvalidInput(String::from("input is not valid UTF-8"));
+            let len = match first {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => return Err(invalid()),
+            };
+            let mut buf = [first, 0, 0, 0];
+            for b in buf[1..len].iter_mut() {
+                *b = self.read_byte()?.ok_or_else(invalid)?;
+            }
+            let c = match std::str::from_utf8(&buf[..len]) {
+                Ok(s) => s.chars().next().unwrap(),
+                Err(_) => return Err(invalid()),
+            };
+            match u8::try_from(c as u32) {
+                Ok(b) => Ok(Some(b)),
+                Err(_) => Err(Error::InvalidInput(format!("{:?} is not a Latin-1 character", c))),
+            }
+        }
+        // Only a `,` that would block on an interactive stdin is prompted.
+        fn wants_prompt(&self) -> bool {
+            self.prompt.is_some() && !self.cooperative_input && self.input.is_none() && (self.is_tty)()
+        }
+        fn show_prompt(&self) {
+            if self.wants_prompt() {
+                // The prompt must appear after any output that is still buffered.
+                let _ = std::io::Write::flush(&mut std::io::stdout());
+                eprint!("{}", self.prompt.as_ref().unwrap());
+            }
+        }
+        fn read_byte(&mut self) -> Result<Option<u8>, Error> {
+            if self.cooperative_input {
+                return Ok(self.pending_input.pop_front());
+            }
+            let b = match self.lookahead.take() {
+                Some(b) => b,
+                None => self.next_input_byte()?,
+            };
+            self.bytes_read += b.is_some() as u64;
+            Ok(b)
+        }
+        // The next byte after the ones a loaded image says to skip.
+        fn next_input_byte(&mut self) -> Result<Option<u8>, Error> {
+            while self.skip_input > 0 {
+                if self.next_raw_byte()?.is_none() {
+                    return Ok(None);
+                }
+                self.skip_input -= 1;
+            }
+            self.next_raw_byte()
+        }
+        fn next_raw_byte(&mut self) -> Result<Option<u8>, Error> {
+            if let Some(all) = self.replay.as_ref() {
+                let b = all.get(self.replay_pos).copied();
+                self.replay_pos += b.is_some() as usize;
+                return Ok(b);
+            }
+            let mut buf = [0u8; 1];
+            loop {
+                let result = match self.input.as_mut() {
+                    Some(reader) => reader.read(&mut buf),
+                    None => std::io::Read::read(&mut std::io::stdin(), &mut buf),
+                };
+                match result {
+                    Ok(0) => return Ok(None),
+                    Ok(_) => return Ok(Some(buf[0])),
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Err(Error::InputNotReady),
+                    Err(e) => return Err(Error::InvalidInput(e.to_string())),
+                }
+            }
+        }
+        fn get_char(&mut self, index: usize) -> Result<(), Error> {
+            self.input_ops += 1;
+            let input = self.read_input()?;
+            self.log_transcript("in", input)?;
+            if let (Some(b), Some(v)) = (input, self.consumed.as_mut()) {
+                v.push(b);
+            }
+            match input {
+                Some(b'\n') if self.newline_is_eof => self.apply_eof(index),
+                Some(b) => self.memory[index] = b,
+                None if self.require_input => return Err(Error::InputRequired),
+                None => self.apply_eof(index),
+            }
+            self.count_write_at(index);
+            Ok(())
+        }
+        fn apply_eof(&mut self, index: usize) {
+            match self.eof_policy {
+                EofPolicy::Zero => self.memory[index] = 0,
+                EofPolicy::MinusOne => self.memory[index] = 255,
+                EofPolicy::Unchanged => (),
+                EofPolicy::Value(v) => self.memory[index] = v,
+            }
+        }
+        // Counts one executed step against the step limit. Loop tests count
+        // as steps, and so does each fused op of the optimized form.
+        fn tick(&mut self) -> Result<(), Error> {
+            self.steps += 1;
+            if let Some(work) = self.work.as_mut() {
+                *work += self.depth as u64 + 1;
+            }
+            if self.steps < self.next_check {
+                return Ok(());
+            }
+            self.next_check = self.steps + self.check_interval;
+            self.limit_checks += 1;
+            self.poll_signal();
+            if let Some(flag) = self.cancel.as_ref() {
+                if flag.load(std::sync::atomic::Ordering::Relaxed) {
+                    return Err(Error::Cancelled);
+                }
+            }
+            match (self.max_steps, self.max_time) {
+                (Some(max), _) if self.steps > max => Err(Error::StepLimitExceeded(max)),
+                (_, Some(max)) if self.epoch.is_some_and(|e| e.elapsed() > max) => Err(Error::TimeLimitExceeded(max)),
+                _ => Ok(()),
+            }
+        }
+        #[cfg(all(unix, feature = "signal"))]
+        fn poll_signal(&self) {
+            if crate::signal::take_request() {
+                let end = self.memory.len().min(16);
+                eprintln!("steps: {}, pointer: {}, tape: {:?}", self.steps, self.adress, &self.memory[..end]);
+            }
+        }
+        #[cfg(not(all(unix, feature = "signal")))]
+        fn poll_signal(&self) {}
+        // Executes op `index`, attaching its source position to a fault.
+        // With `keep_going`, a recoverable fault is reported and the op is
+        // skipped, leaving the machine as it was before it.
+        fn execute_at(&mut self, op: Op, index: usize) -> Result<(), Error> {
+            match self.execute(op).map_err(|e| self.locate(e, index)) {
+                Err(e) if self.keep_going && e.is_recoverable() => {
+                    eprintln!("warning: {}", e);
+                    Ok(())
+                }
+                result => result,
+            }
+        }
+        fn locate(&self, error: Error, index: usize) -> Error {
+            match error {
+                error if error.is_recoverable() || matches!(error, Error::TapeLimitExceeded(_)) => {
+                    let offset = self.offsets[index];
+                    let command = self.source[offset] as char;
+                    Error::At{ offset, command, error: Box::new(error) }
+                }
+                error => error,
+            }
+        }
+        fn enter_loop(&mut self) -> Result<(), Error> {
+            self.depth += 1;
+            match self.max_depth {
+                Some(max) if self.depth > max => Err(Error::DepthLimitExceeded(max)),
+                _ => Ok(()),
+            }
+        }
+        // Whether the loop under the pointer should be (re-)entered.
+        fn test_cell(&mut self) -> Result<bool, Error> {
+            self.tick()?;
+            self.count_read();
+            let enter = self.memory[self.adress] != 0;
+            if enter {
+                self.iterations += 1;
+            }
+            Ok(enter)
+        }
+        fn execute(&mut self, op: Op) -> Result<(), Error> {
+            self.tick()?;
+            if self.tape_locked && matches!(op, Op::Increment | Op::Decrement | Op::GetChar) {
+                return Err(Error::TapeLocked);
+            }
+            match op {
+                Op::Increment => {
+                    let v = self.memory[self.adress];
+                    self.memory[self.adress] = match self.arith_mode {
+                        ArithMode::Wrapping => v.wrapping_add(1),
+                        ArithMode::Checked => v.checked_add(1).ok_or(Error::ArithmeticOverflow)?,
+                        ArithMode::Saturating => v.saturating_add(1),
+                    };
+                    self.count_read();
+                    self.count_write();
+                }
+                Op::Decrement => {
+                    let v = self.memory[self.adress];
+                    self.memory[self.adress] = match self.arith_mode {
+                        ArithMode::Wrapping => v.wrapping_sub(1),
+                        ArithMode::Checked => v.checked_sub(1).ok_or(Error::ArithmeticOverflow)?,
+                        ArithMode::Saturating => v.saturating_sub(1),
+                    };
+                    self.count_read();
+                    self.count_write();
+                }
+                Op::ShiftLeft | Op::ShiftRight if self.ptr_modulo.is_some() => {
+                    self.adress = self.cell_index(if op == Op::ShiftLeft { -1 } else { 1 })?;
+                }
+                Op::ShiftLeft => {
+                    if self.adress == 0 {
+                        self.grow_left(1)?;
+                    }
+                    self.adress -= 1;
+                }
+                Op::ShiftRight => {
+                    if self.adress + 1 == self.memory.len() {
+                        self.grow_right(self.memory.len() + 1)?;
+                    }
+                    self.adress += 1;
+                }
+                Op::PrintChar => self.put_char(self.adress)?,
+                Op::GetChar => self.get_char(self.adress)?,
+                Op::SwitchTape => self.switch_tape(),
+                Op::LoopStart | Op::LoopEnd => (),
+            }
+            Ok(())
+        }
+        // `start` is the op index of `inst[0]`, used to locate tape faults.
+        // Loops are entered by pushing a frame rather than by recursion, so
+        // deep nesting cannot overflow the call stack.
+        fn eval_liner(&mut self, inst: &[Instruction], start: usize) -> Result<(), Error> {
+            let mut stack = vec![Frame{ body: inst, pos: 0, index: start, opened: None }];
+            while let Some(frame) = stack.last_mut() {
+                let index = frame.index;
+                let Some(op) = frame.body.get(frame.pos) else {
+                    let Some((open, started)) = frame.opened else {
+                        stack.pop();
+                        continue;
+                    };
+                    self.count_op(self.jumps[open]);
+                    if self.test_cell()? {
+                        frame.pos = 0;
+                        frame.index = open + 1;
+                        continue;
+                    }
+                    stack.pop();
+                    self.leave_loop(open, started);
+                    continue;
+                };
+                frame.pos += 1;
+                if let Instruction::Nop{ .. } = op {
+                    continue;
+                }
+                frame.index += 1;
+                self.count_op(index);
+                match op {
+                    Instruction::Increment => self.execute_at(Op::Increment, index)?,
+                    Instruction::Decrement => self.execute_at(Op::Decrement, index)?,
+                    Instruction::ShiftLeft => self.execute_at(Op::ShiftLeft, index)?,
+                    Instruction::ShiftRight => self.execute_at(Op::ShiftRight, index)?,
+                    Instruction::PrintChar => self.execute_at(Op::PrintChar, index)?,
+                    Instruction::GetChar => self.execute_at(Op::GetChar, index)?,
+                    Instruction::SwitchTape => self.execute_at(Op::SwitchTape, index)?,
+                    Instruction::Nop{ .. } => (),
+                    Instruction::Loop(body) => {
+                        frame.index = self.jumps[index] + 1;
+                        self.enter_loop()?;
+                        let started = if self.loop_timing { Some(std::time::Instant::now()) } else { None };
+                        if self.test_cell()? {
+                            stack.push(Frame{ body, pos: 0, index: index + 1, opened: Some((index, started)) });
+                        } else {
+                            self.leave_loop(index, started);
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+        fn count_op(&mut self, index: usize) {
+            if let Some(counts) = self.op_counts.as_mut() {
+                if counts.len() <= index {
+                    counts.resize(index + 1, 0);
+                }
+                counts[index] += 1;
+            }
+        }
+        fn leave_loop(&mut self, index: usize, started: Option<std::time::Instant>) {
+            if let Some(started) = started {
+                *self.loop_times.entry(self.offsets[index]).or_default() += started.elapsed();
+            }
+            self.depth -= 1;
+        }
+        fn eval_compact(&mut self, code: &[CompactOp]) -> Result<(), Error> {
+            let mut pc = 0;
+            while pc < code.len() {
+                let op = code[pc];
+                pc += 1;
+                match op.code {
+                    OpCode::Add => {
+                        self.tick()?;
+                        self.memory[self.adress] = self.memory[self.adress].wrapping_add(op.arg as u8);
+                        self.count_read();
+                        self.count_write();
+                    }
+                    OpCode::Move => {
+                        self.tick()?;
+                        self.adress = self.cell_index(op.arg)?;
+                    }
+                    OpCode::Out => {
+                        self.tick()?;
+                        self.put_char(self.adress)?;
+                    }
+                    OpCode::In => {
+                        self.tick()?;
+                        self.get_char(self.adress)?;
+                    }
+                    OpCode::JumpIfZero => {
+                        self.enter_loop()?;
+                        if !self.test_cell()? {
+                            self.depth -= 1;
+                            pc = op.arg as usize;
+                        }
+                    }
+                    OpCode::JumpIfNonZero => {
+                        if self.test_cell()? {
+                            pc = op.arg as usize;
+                        } else {
+                            self.depth -= 1;
+                        }
+                    }
+                    OpCode::SwitchTape => {
+                        self.tick()?;
+                        self.switch_tape();
+                    }
+                }
+            }
+            Ok(())
+        }
+        // `eval_compact` for the plain configuration: steps and iterations
+        // are still counted, but the tape only grows when a move leaves it
+        // and a pending signal is handled once per loop iteration.
+        fn eval_compact_fast(&mut self, code: &[CompactOp]) -> Result<(), Error> {
+            let mut pc = 0;
+            while pc < code.len() {
+                let op = code[pc];
+                pc += 1;
+                self.steps += 1;
+                match op.code {
+                    OpCode::Add => {
+                        let cell = &mut self.memory[self.adress];
+                        *cell = cell.wrapping_add(op.arg as u8);
+                    }
+                    OpCode::Move => {
+                        let target = self.adress.wrapping_add_signed(op.arg);
+                        self.adress = if target < self.memory.len() { target } else { self.cell_index(op.arg)? };
+                    }
+                    OpCode::Out => self.put_char(self.adress)?,
+                    OpCode::In => self.get_char(self.adress)?,
+                    OpCode::JumpIfZero => {
+                        if self.memory[self.adress] == 0 {
+                            pc = op.arg as usize;
+                        } else {
+                            self.iterations += 1;
+                        }
+                    }
+                    OpCode::JumpIfNonZero => {
+                        if self.memory[self.adress] != 0 {
+                            self.iterations += 1;
+                            self.poll_signal();
+                            pc = op.arg as usize;
+                        }
+                    }
+                    OpCode::SwitchTape => self.switch_tape(),
+                }
+            }
+            Ok(())
+        }
+        fn eval_optimized(&mut self, nodes: &[Node]) -> Result<(), Error> {
+            for node in nodes.iter() {
+                if !matches!(node, Node::Loop(_) | Node::Scan(_)) {
+                    self.tick()?;
+                }
+                match node {
+                    Node::Add(offset, amount) => {
+                        let i = self.cell_index(*offset)?;
+                        self.memory[i] = self.memory[i].wrapping_add(*amount);
+                        self.count_read_at(i);
+                        self.count_write_at(i);
+                    }
+                    Node::Move(offset) => {
+                        self.adress = self.cell_index(*offset)?;
+                    }
+                    Node::Print(offset) => {
+                        let i = self.cell_index(*offset)?;
+                        self.put_char(i)?;
+                    }
+                    Node::SetZero(offset) => {
+                        let i = self.cell_index(*offset)?;
+                        self.memory[i] = 0;
+                        self.count_write_at(i);
+                    }
+                    Node::Read(offset) => {
+                        let i = self.cell_index(*offset)?;
+                        self.get_char(i)?;
+                    }
+                    Node::MoveValue(offset, delta) => {
+                        // Growing the tape for one cell can shift the other,
+                        // so both indices are taken once the tape covers both.
+                        self.cell_index(*offset + *delta)?;
+                        let i = self.cell_index(*offset)?;
+                        let j = self.cell_index(*offset + *delta)?;
+                        self.memory[j] = self.memory[j].wrapping_add(self.memory[i]);
+                        self.memory[i] = 0;
+                        self.count_read_at(i);
+                        self.count_write_at(i);
+                        self.count_read_at(j);
+                        self.count_write_at(j);
+                    }
+                    Node::CopyValue(offset, delta, temp) => {
+                        self.cell_index(*offset + *delta)?;
+                        self.cell_index(*offset + *temp)?;
+                        let i = self.cell_index(*offset)?;
+                        let j = self.cell_index(*offset + *delta)?;
+                        let k = self.cell_index(*offset + *temp)?;
+                        self.memory[j] = self.memory[j].wrapping_add(self.memory[i]);
+                        self.memory[i] = self.memory[i].wrapping_add(self.memory[k]);
+                        self.memory[k] = 0;
+                        for c in [i, j, k] {
+                            self.count_read_at(c);
+                            self.count_write_at(c);
+                        }
+                    }
+                    Node::Scan(step) => {
+                        while self.test_cell()? {
+                            self.adress = self.cell_index(*step)?;
+                        }
+                    }
+                    Node::SwitchTape => self.switch_tape(),
+                    Node::Loop(body) => {
+                        self.enter_loop()?;
+                        while self.test_cell()? {
+                            self.eval_optimized(body)?;
+                        }
+                        self.depth -= 1;
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::cell::Cell;
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        // Serves `len` bytes of 'a', counting the reads and the bytes asked for.
+        struct CountingReader {
+            len: usize,
+            reads: Rc<Cell<usize>>,
+            requested: Rc<Cell<usize>>,
+        }
+
+        impl std::io::Read for CountingReader {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.reads.set(self.reads.get() + 1);
+                self.requested.set(self.requested.get() + buf.len());
+                if self.len == 0 || buf.is_empty() {
+                    return Ok(0);
+                }
+                self.len -= 1;
+                buf[0] = b'a';
+                Ok(1)
+            }
+        }
+
+        #[test]
+        fn profile_counts_reads_and_writes_per_cell() {
+            let mut interpreter = new(String::from("+>++<>>+")).with_profile(true);
+            interpreter.run().unwrap();
+            let counts : Vec<(u64, u64)> = interpreter.cell_access().iter().map(|a| (a.reads, a.writes)).collect();
+            assert_eq!(counts, [(1, 1), (2, 2), (1, 1)]);
+        }
+
+        fn fault(result: Result<(), Error>) -> Error {
+            match result {
+                Err(Error::At{ error, .. }) => *error,
+                other => panic!("{:?}", other),
+            }
+        }
+
+        #[test]
+        fn runaway_pointer_hits_the_tape_limit() {
+            let mut interpreter = new(String::from("+[>+]")).with_max_tape(8);
+            assert!(matches!(fault(interpreter.run()), Error::TapeLimitExceeded(8)));
+            assert_eq!(interpreter.memory.len(), 8);
+        }
+
+        fn output(source: &str, ensure_newline: bool) -> Vec<u8> {
+            let mut interpreter = new(String::from(source))
+                .with_ensure_newline(ensure_newline)
+                .with_captured_output()
+                .build()
+                .unwrap();
+            interpreter.run().unwrap();
+            interpreter.take_output()
+        }
+
+        #[test]
+        fn ensure_newline_adds_only_a_missing_newline() {
+            assert_eq!(output("+.", true), b"\x01\n");
+            assert_eq!(output("++++++++++.", true), b"\n");
+            assert_eq!(output("", true), b"");
+            assert_eq!(output("+.", false), b"\x01");
+        }
+
+        #[test]
+        fn sandbox_prints_nothing_and_reads_its_eof_byte() {
+            let run = || {
+                let mut interpreter = new(String::from(",.+.,")).with_sandbox(7);
+                interpreter.run().unwrap();
+                (interpreter.memory.clone(), interpreter.last_output)
+            };
+            assert_eq!(run(), (vec![7], None));
+            assert_eq!(run(), run());
+        }
+
+        #[test]
+        fn from_str_builds_a_valid_program() {
+            let mut interpreter : Interpreter = "+++>+".parse().unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.memory, [3, 1]);
+        }
+
+        #[test]
+        fn from_str_rejects_an_unbalanced_program() {
+            assert!(matches!("+[.".parse::<Interpreter>(), Err(Error::InvalidSource)));
+        }
+
+        #[test]
+        fn validate_on_build_fails_at_build() {
+            assert!(matches!(new(String::from("]")).with_validate_on_build(true).build(), Err(Error::InvalidSource)));
+            assert!(new(String::from("[-]")).with_validate_on_build(true).build().is_ok());
+        }
+
+        #[test]
+        fn validation_is_lazy_by_default() {
+            let mut interpreter = new(String::from("]")).build().unwrap();
+            assert!(matches!(interpreter.validate(), Err(Error::InvalidSource)));
+            assert!(matches!(interpreter.run(), Err(Error::InvalidSource)));
+        }
+
+        #[test]
+        fn disassemble_lists_ops_and_jump_targets() {
+            let mut interpreter = new(String::from("+++++[>+<-]")).build().unwrap();
+            assert_eq!(interpreter.disassemble().unwrap(), "\
+0000  Add +5
+0001  JumpIfZero -> 0007
+0002  Move +1
+0003  Add +1
+0004  Move -1
+0005  Add -1
+0006  JumpIfNonZero -> 0002
+");
+        }
+
+        #[test]
+        fn metrics_of_a_nested_program() {
+            let mut interpreter = new(String::from("++[>+[-]<-]>> comment")).build().unwrap();
+            let m = interpreter.metrics().unwrap();
+            assert_eq!((m.ops, m.loops, m.max_depth, m.loop_weight), (9, 2, 2, 16));
+        }
+
+        #[test]
+        fn run_bytes_runs_entirely_in_memory() {
+            assert_eq!(run_bytes(",[.,]", b"hello").unwrap(), b"hello");
+            assert_eq!(run_bytes("++.", b"").unwrap(), [2]);
+            assert!(matches!(run_bytes("[", b""), Err(Error::InvalidSource)));
+        }
+
+        #[test]
+        fn strict_mode_rejects_unknown_characters() {
+            let mut interpreter = new(String::from("++x")).with_strict(true).build().unwrap();
+            assert!(matches!(interpreter.run(), Err(Error::UnknownCharacter('x', 2))));
+            let mut interpreter = new(String::from("++x")).build().unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.memory(), &[2]);
+        }
+
+        #[test]
+        fn folding_blocks_keeps_output_and_input_order() {
+            let source = ",>+++<.>>++<+.,[>+++<-]>.<<.";
+            let run = |level: u8| {
+                let mut interpreter = new(String::from(source))
+                    .with_opt_level(level)
+                    .with_input(Box::new(std::io::Cursor::new(b"\x02\x03".to_vec())))
+                    .with_captured_output()
+                    .build()
+                    .unwrap();
+                interpreter.run().unwrap();
+                (interpreter.take_output(), interpreter.memory().to_vec())
+            };
+            assert_eq!(run(1), run(0));
+        }
+
+        #[test]
+        fn dump_ppm_pads_the_tape_to_the_image() {
+            let mut interpreter = new(String::from("+>++")).build().unwrap();
+            interpreter.run().unwrap();
+            let mut out = Vec::<u8>::new();
+            interpreter.dump_ppm(3, 2, &mut out).unwrap();
+            let header = b"P5\n3 2\n255\n";
+            assert!(out.starts_with(header));
+            assert_eq!(&out[header.len()..], &[1, 2, 0, 0, 0, 0]);
+            let mut out = Vec::<u8>::new();
+            interpreter.dump_ppm(1, 1, &mut out).unwrap();
+            assert_eq!(out, b"P5\n1 1\n255\n\x01");
+            let err = interpreter.dump_ppm(usize::MAX, 2, &mut Vec::<u8>::new()).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        }
+
+        #[test]
+        fn warnings_flag_an_invariant_store() {
+            let mut interpreter = new(String::from("+++[>[-]++<-]")).build().unwrap();
+            let warnings = interpreter.warnings().unwrap();
+            assert_eq!(warnings.len(), 1);
+            assert_eq!(warnings[0].offset, 3);
+            assert_eq!(warnings[0].message, "loop sets cell +1 to 2 on every iteration; it could be set once");
+            let mut interpreter = new(String::from("+++[>+<-]")).build().unwrap();
+            assert!(interpreter.warnings().unwrap().is_empty());
+        }
+
+        #[test]
+        fn initial_pointer_reads_preloaded_data_to_the_left() {
+            let mut interpreter = new(String::from("<<<<<.>."))
+                .with_initial_tape(b"Hi".to_vec())
+                .with_initial_pointer(5)
+                .with_captured_output()
+                .build()
+                .unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.take_output(), b"Hi");
+            assert_eq!(interpreter.memory().len(), 6);
+        }
+
+        #[test]
+        fn reads_past_the_input_follow_the_eof_policy() {
+            let run = |policy: EofPolicy| {
+                let mut interpreter = new(String::from("+++,>+++,>+++,<<.>.>."))
+                    .with_input(Box::new(&b"a"[..]))
+                    .with_eof_policy(policy)
+                    .with_captured_output()
+                    .build()
+                    .unwrap();
+                interpreter.run().unwrap();
+                interpreter.take_output()
+            };
+            assert_eq!(run(EofPolicy::Zero), [b'a', 0, 0]);
+            assert_eq!(run(EofPolicy::MinusOne), [b'a', 255, 255]);
+            assert_eq!(run(EofPolicy::Unchanged), [b'a', 3, 3]);
+            assert_eq!(run(EofPolicy::Value(9)), [b'a', 9, 9]);
+        }
+
+        // Has nothing ready on every other read, like a non-blocking pipe
+        // the writer is slow to fill.
+        struct SlowPipe {
+            bytes: Vec<u8>,
+            ready: bool,
+        }
+
+        impl std::io::Read for SlowPipe {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.ready = !self.ready;
+                if !self.ready {
+                    return Err(std::io::ErrorKind::WouldBlock.into());
+                }
+                if self.bytes.is_empty() {
+                    return Ok(0);
+                }
+                buf[0] = self.bytes.remove(0);
+                Ok(1)
+            }
+        }
+
+        struct BrokenPipe;
+
+        impl std::io::Read for BrokenPipe {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+        }
+
+        #[test]
+        fn unready_input_pauses_instead_of_spinning() {
+            let pipe = || Box::new(SlowPipe{ bytes: b"ab".to_vec(), ready: true });
+            let mut interpreter = new(String::from(",.,.")).with_input(pipe()).with_captured_output().build().unwrap();
+            interpreter.prepare().unwrap();
+            assert_eq!(interpreter.resume().unwrap(), Status::NeedInput);
+            assert_eq!(interpreter.steps(), 0);
+            assert_eq!(interpreter.resume().unwrap(), Status::NeedInput);
+            assert_eq!(interpreter.take_output(), b"a");
+            assert_eq!(interpreter.resume().unwrap(), Status::Finished);
+            assert_eq!(interpreter.take_output(), b"b");
+            let mut interpreter = new(String::from(",")).with_input(pipe()).build().unwrap();
+            assert!(matches!(interpreter.run(), Err(Error::InputNotReady)));
+        }
+
+        #[test]
+        fn a_failed_read_is_an_error() {
+            let mut interpreter = new(String::from("+,")).with_input(Box::new(BrokenPipe)).build().unwrap();
+            match interpreter.run() {
+                Err(Error::At{ offset: 1, error, .. }) => assert!(matches!(*error, Error::InvalidInput(_))),
+                other => panic!("{:?}", other),
+            }
+        }
+
+        #[test]
+        fn escaped_output_shows_non_printable_bytes() {
+            let run = |format: OutputFormat| {
+                let mut interpreter = new(format!("{}.{}.", "+".repeat(7), "+".repeat(58)))
+                    .with_output_format(format)
+                    .with_captured_output()
+                    .build()
+                    .unwrap();
+                interpreter.run().unwrap();
+                interpreter.take_output()
+            };
+            assert_eq!(run(OutputFormat::Escaped), b"\\x07A");
+            assert_eq!(run(OutputFormat::Hex), b"0741");
+            assert_eq!(run(OutputFormat::Raw), b"\x07A");
+        }
+
+        #[test]
+        fn a_few_reads_leave_a_huge_input_alone() {
+            let reads = Rc::new(Cell::new(0));
+            let requested = Rc::new(Cell::new(0));
+            let reader = CountingReader { len: usize::MAX, reads: reads.clone(), requested: requested.clone() };
+            let mut interpreter = new(String::from(",>,>,"))
+                .with_input(Box::new(reader))
+                .build()
+                .unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.memory(), b"aaa");
+            assert_eq!(requested.get(), 3);
+        }
+
+        #[test]
+        fn debug_summary_shows_the_op_count() {
+            let mut interpreter = new(String::from("a+ b+ c>.")).with_captured_output().build().unwrap();
+            let summary = format!("{:?}", interpreter);
+            assert!(!summary.contains("tape"), "{}", summary);
+            interpreter.run().unwrap();
+            let summary = format!("{:?}", interpreter);
+            assert!(summary.contains("ops: 4"), "{}", summary);
+            assert!(summary.contains("pointer: 1"), "{}", summary);
+            assert!(summary.contains("tape: [2, 0]"), "{}", summary);
+        }
+
+        #[test]
+        fn shrink_tape_releases_memory() {
+            let source = format!("{}{}+", ">".repeat(10_000), "<".repeat(10_000));
+            let mut interpreter = new(source).build().unwrap();
+            interpreter.run().unwrap();
+            assert!(interpreter.memory().len() > 10_000);
+            interpreter.shrink_tape();
+            assert_eq!(interpreter.memory(), &[1]);
+            assert!(interpreter.memory.capacity() < 10_000);
+            assert_eq!(interpreter.pointer(), 0);
+        }
+
+        #[test]
+        fn shrink_tape_keeps_the_logical_pointer() {
+            for source in ["<<>>", "<<+>>>>", "+<<<+>", "%>>>+<<%<<"] {
+                let mut interpreter = new(String::from(source)).with_dual_tape(true).build().unwrap();
+                interpreter.run().unwrap();
+                let logical = interpreter.pointer() as isize - interpreter.left_grown as isize;
+                let cell = interpreter.memory()[interpreter.pointer()];
+                interpreter.shrink_tape();
+                assert_eq!(interpreter.pointer() as isize - interpreter.left_grown as isize, logical, "{}", source);
+                assert_eq!(interpreter.memory()[interpreter.pointer()], cell, "{}", source);
+            }
+            let mut interpreter = new(String::from("%>+>>>><<<<%")).with_dual_tape(true).build().unwrap();
+            interpreter.run().unwrap();
+            interpreter.shrink_tape();
+            assert_eq!(interpreter.other_memory, &[0, 1]);
+            assert_eq!(interpreter.other_adress, 1);
+        }
+
+        #[test]
+        fn shrink_tape_keeps_cells_cleared_below_the_fill_value() {
+            let mut interpreter = new(String::from(">>->>>><<<<<<")).with_fill_value(1).build().unwrap();
+            interpreter.run().unwrap();
+            interpreter.shrink_tape();
+            assert_eq!(interpreter.memory(), &[1, 1, 0]);
+            let mut interpreter = new(String::from("<<->>")).with_fill_value(1).build().unwrap();
+            interpreter.run().unwrap();
+            interpreter.shrink_tape();
+            assert_eq!(interpreter.memory(), &[0, 1, 1]);
+            assert_eq!(interpreter.pointer() - interpreter.left_grown, 0);
+        }
+
+        #[test]
+        fn image_keeps_the_fill_value() {
+            let mut first = new(String::from(">.")).with_fill_value(7).with_growth_chunk(1).with_captured_output().build().unwrap();
+            first.prepare().unwrap();
+            let mut image = Vec::<u8>::new();
+            first.save_image(&mut image).unwrap();
+            let mut second = load_image(&image).unwrap().with_captured_output();
+            second.resume().unwrap();
+            assert_eq!(second.take_output(), [7]);
+        }
+
+        #[test]
+        fn random_input_repeats_for_the_same_seed() {
+            let run = |seed: u64| {
+                let mut interpreter = new(",.".repeat(64))
+                    .with_input(Box::new(RandomInput::new(seed)))
+                    .with_captured_output()
+                    .build()
+                    .unwrap();
+                interpreter.run().unwrap();
+                interpreter.take_output()
+            };
+            assert_eq!(run(7), run(7));
+            assert_ne!(run(7), run(8));
+        }
+
+        #[test]
+        fn warnings_flag_a_loop_after_a_clear() {
+            let mut interpreter = new(String::from(",[-][++]")).build().unwrap();
+            let warnings = interpreter.warnings().unwrap();
+            assert_eq!(warnings.len(), 1);
+            assert_eq!(warnings[0].offset, 4);
+            assert_eq!(warnings[0].message, "loop never runs: the cell is already zero after the preceding loop");
+            let mut interpreter = new(String::from(",[-]>+[++]")).build().unwrap();
+            assert!(interpreter.warnings().unwrap().is_empty());
+        }
+
+        #[test]
+        fn prompt_is_shown_only_for_an_interactive_stdin() {
+            let prompted = |is_tty: fn() -> bool, input: Option<&'static [u8]>| {
+                let mut interpreter = new(String::from(",")).with_prompt(String::from("? ")).with_tty_detector(is_tty);
+                if let Some(bytes) = input {
+                    interpreter = interpreter.with_input(Box::new(bytes));
+                }
+                interpreter.build().unwrap().wants_prompt()
+            };
+            assert!(prompted(|| true, None));
+            assert!(!prompted(|| false, None));
+            assert!(!prompted(|| true, Some(b"a")));
+            let interpreter = new(String::from(",")).with_tty_detector(|| true).build().unwrap();
+            assert!(!interpreter.wants_prompt());
+        }
+
+        #[test]
+        fn bytes_written_counts_printed_bytes() {
+            let mut interpreter = new(String::from("+++++[>+.<-]")).with_captured_output().build().unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.bytes_written(), 5);
+            assert_eq!(interpreter.take_output(), [1, 2, 3, 4, 5]);
+        }
+
+        #[test]
+        fn max_output_stops_an_endless_printer() {
+            let mut interpreter = new(String::from("+[.]")).with_max_output(3).with_captured_output().build().unwrap();
+            assert!(matches!(interpreter.run(), Err(Error::OutputLimitExceeded(3))));
+            assert_eq!(interpreter.bytes_written(), 3);
+            assert_eq!(interpreter.take_output(), [1, 1, 1]);
+        }
+
+        #[test]
+        fn check_reports_the_first_difference() {
+            assert!(check("+++++++[>++++++++++<-]>-.", b"", b"E").is_ok());
+            assert!(check(",[.,]", b"abc", b"abc").is_ok());
+            match check(",[.,]", b"abd", b"abc") {
+                Err(Mismatch::Output { index: 2, got: Some(b'd'), expected: Some(b'c') }) => (),
+                other => panic!("{:?}", other),
+            }
+            match check(",[.,]", b"ab", b"abc") {
+                Err(Mismatch::Output { index: 2, got: None, expected: Some(b'c') }) => (),
+                other => panic!("{:?}", other),
+            }
+            assert!(matches!(check("[", b"", b""), Err(Mismatch::Failed(Error::InvalidSource))));
+        }
+
+        #[test]
+        fn transpiled_code_maps_back_to_the_source() {
+            let mut interpreter = new(String::from("+\n >.")).build().unwrap();
+            let c = interpreter.to_c(true).unwrap();
+            let lines : Vec<&str> = c.lines().map(|l| l.trim()).filter(|l| l.starts_with("/* src")).collect();
+            assert_eq!(lines, ["/* src 1:1 */", "/* src 2:2 */", "/* src 2:3 */"]);
+            assert_eq!(interpreter.to_rust(true).unwrap().matches("/* src ").count(), 3);
+            assert!(!interpreter.to_c(false).unwrap().contains("/* src"));
+        }
+
+        #[test]
+        fn optimized_source_drops_redundant_commands() {
+            let source = "+++--><<>>.[+-]++[-]+.";
+            let mut interpreter = new(String::from(source)).with_opt_level(2).build().unwrap();
+            let lowered = interpreter.optimized_source().unwrap();
+            assert!(lowered.len() < source.len(), "{}", lowered);
+            assert_eq!(run_bytes(&lowered, b"").unwrap(), run_bytes(source, b"").unwrap());
+        }
+
+        #[test]
+        fn growth_chunk_reallocates_less_often() {
+            let reallocations = |chunk: usize| {
+                let mut interpreter = new(">".repeat(20_000)).with_growth_chunk(chunk).build().unwrap();
+                interpreter.prepare().unwrap();
+                let mut count = 0;
+                let mut capacity = interpreter.memory.capacity();
+                while interpreter.step().unwrap() == Status::Running {
+                    if interpreter.memory.capacity() != capacity {
+                        capacity = interpreter.memory.capacity();
+                        count += 1;
+                    }
+                }
+                assert!(interpreter.memory().len() > 20_000);
+                count
+            };
+            assert!(reallocations(4096) < reallocations(1));
+            assert!(reallocations(4096) <= 5);
+        }
+
+        #[test]
+        fn start_paused_waits_for_step() {
+            let mut interpreter = new(String::from("+.")).with_start_paused(true).with_captured_output().build().unwrap();
+            assert_eq!(interpreter.start().unwrap(), Status::Paused);
+            assert_eq!(interpreter.steps(), 0);
+            assert_eq!(interpreter.memory(), &[0]);
+            assert_eq!(interpreter.step().unwrap(), Status::Running);
+            assert_eq!(interpreter.memory(), &[1]);
+            assert_eq!(interpreter.resume().unwrap(), Status::Finished);
+            assert_eq!(interpreter.take_output(), [1]);
+            let mut interpreter = new(String::from("+.")).with_captured_output().build().unwrap();
+            assert_eq!(interpreter.start().unwrap(), Status::Finished);
+        }
+
+        // Records how many bytes had been written at each flush.
+        struct FlushLog {
+            written: usize,
+            flushes: Rc<RefCell<Vec<usize>>>,
+        }
+
+        impl std::io::Write for FlushLog {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.written += buf.len();
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.flushes.borrow_mut().push(self.written);
+                Ok(())
+            }
+        }
+
+        #[test]
+        fn line_buffered_output_flushes_at_newlines() {
+            let flushes = Rc::new(RefCell::new(Vec::<usize>::new()));
+            let log = FlushLog { written: 0, flushes: flushes.clone() };
+            let mut interpreter = new(String::from("++++++++++>+++[<.>-]<."))
+                .with_initial_tape(vec![0, 0])
+                .with_output(Box::new(log))
+                .with_line_buffered(true)
+                .build()
+                .unwrap();
+            interpreter.prepare().unwrap();
+            while interpreter.step().unwrap() == Status::Running {}
+            assert_eq!(flushes.borrow()[..4], [1, 2, 3, 4]);
+        }
+
+        #[test]
+        fn bracket_map_pairs_nested_brackets() {
+            let map = bracket_map("+[>[-]<c[]]").unwrap();
+            let pairs : Vec<(usize, usize)> = map.into_iter().collect();
+            assert_eq!(pairs, [(1, 10), (3, 5), (5, 3), (8, 9), (9, 8), (10, 1)]);
+            assert!(bracket_map("[[]").is_err());
+            assert!(bracket_map("]").is_err());
+        }
+
+        #[test]
+        fn ascii7_masks_the_high_bit() {
+            let run = |enable: bool| {
+                let mut interpreter = new(format!("{}.", "+".repeat(200)))
+                    .with_ascii7(enable)
+                    .with_captured_output()
+                    .build()
+                    .unwrap();
+                interpreter.run().unwrap();
+                interpreter.take_output()
+            };
+            assert_eq!(run(true), [72]);
+            assert_eq!(run(false), [200]);
+        }
+
+        #[test]
+        fn tape_json_has_the_pointer_and_cells() {
+            let mut interpreter = new(String::from("+>++>+++<.")).with_captured_output().build().unwrap();
+            interpreter.run().unwrap();
+            let mut out = Vec::<u8>::new();
+            interpreter.dump_tape_json(&mut out).unwrap();
+            let json = String::from_utf8(out).unwrap();
+            assert!(json.contains("\"pointer\":1"), "{}", json);
+            assert!(json.contains("\"bytes_written\":1"), "{}", json);
+            assert!(json.contains("\"tape\":[1,2,3]"), "{}", json);
+        }
+
+        #[test]
+        fn cooperative_input_yields_until_provided() {
+            let mut interpreter = new(String::from(",.")).with_cooperative_input(true).with_captured_output().build().unwrap();
+            interpreter.prepare().unwrap();
+            assert_eq!(interpreter.step().unwrap(), Status::NeedInput);
+            assert_eq!(interpreter.step().unwrap(), Status::NeedInput);
+            assert_eq!(interpreter.steps(), 0);
+            interpreter.provide_input(b'x');
+            assert_eq!(interpreter.resume().unwrap(), Status::Finished);
+            assert_eq!(interpreter.take_output(), b"x");
+        }
+
+        #[test]
+        fn flat_mode_builds_the_tree_only_on_demand() {
+            let mut interpreter = new(String::from("++[>+<-]>.")).with_flat(true).with_captured_output().build().unwrap();
+            interpreter.run().unwrap();
+            assert!(interpreter.inst.is_empty());
+            assert_eq!(interpreter.take_output(), [2]);
+            interpreter.warnings().unwrap();
+            assert_eq!(interpreter.inst.len(), 5);
+        }
+
+        #[test]
+        fn flat_mode_runs_like_the_tree() {
+            let source = ",[>++++[<+>-]<.,]>>+++[<++>-]<.[-]<<---.";
+            let run = |flat: bool| {
+                let mut interpreter = new(String::from(source))
+                    .with_flat(flat)
+                    .with_input(Box::new(&b"abc"[..]))
+                    .with_captured_output()
+                    .build()
+                    .unwrap();
+                interpreter.run().unwrap();
+                (interpreter.take_output(), interpreter.memory().to_vec(), interpreter.pointer())
+            };
+            assert_eq!(run(true), run(false));
+        }
+
+        fn nested(depth: usize) -> String {
+            format!("+{}-{}", "[".repeat(depth), "]".repeat(depth))
+        }
+
+        #[test]
+        fn deep_nesting_runs_unoptimized() {
+            let mut interpreter = new(nested(200_000)).build().unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.memory(), &[0]);
+        }
+
+        #[test]
+        fn deep_nesting_is_refused_when_optimizing() {
+            let mut interpreter = new(nested(MAX_OPT_NESTING + 1)).with_opt_level(1).build().unwrap();
+            match interpreter.run() {
+                Err(Error::NestingLimitExceeded(max, offset)) => assert_eq!((max, offset), (MAX_OPT_NESTING, MAX_OPT_NESTING + 1)),
+                other => panic!("{:?}", other),
+            }
+        }
+
+        #[test]
+        fn runtime_depth_cap_stops_deep_loops() {
+            for flat in [false, true] {
+                let mut interpreter = new(nested(50)).with_flat(flat).with_max_runtime_depth(10).build().unwrap();
+                assert!(matches!(interpreter.run(), Err(Error::DepthLimitExceeded(10))));
+                let mut interpreter = new(nested(10)).with_flat(flat).with_max_runtime_depth(10).build().unwrap();
+                interpreter.run().unwrap();
+                let mut interpreter = new(format!("{}+", "[-]".repeat(20))).with_flat(flat).with_max_runtime_depth(1).build().unwrap();
+                interpreter.run().unwrap();
+            }
+        }
+
+        #[test]
+        fn newline_ends_the_input() {
+            let run = |enable: bool| {
+                let mut interpreter = new(String::from(",[.,]"))
+                    .with_newline_is_eof(enable)
+                    .with_input(Box::new(&b"ab\ncd"[..]))
+                    .with_captured_output()
+                    .build()
+                    .unwrap();
+                interpreter.run().unwrap();
+                interpreter.take_output()
+            };
+            assert_eq!(run(true), b"ab");
+            assert_eq!(run(false), b"ab\ncd");
+        }
+
+        #[test]
+        fn fixed_tape_faults_carry_the_source_location() {
+            let mut interpreter = new(String::from("+>\n>>+")).with_max_tape(3).build().unwrap();
+            match interpreter.run() {
+                Err(Error::At{ offset: 4, command: '>', error }) => assert!(matches!(*error, Error::TapeLimitExceeded(3))),
+                other => panic!("{:?}", other),
+            }
+            let mut interpreter = new(String::from("+ <")).with_left_growth(false).build().unwrap();
+            match interpreter.run() {
+                Err(Error::At{ offset: 2, command: '<', error }) => assert!(matches!(*error, Error::PointerUnderflow)),
+                other => panic!("{:?}", other),
+            }
+        }
+
+        // Counts the bytes written to it.
+        struct ByteCounter(Rc<Cell<usize>>);
+
+        impl std::io::Write for ByteCounter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.set(self.0.get() + buf.len());
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        #[test]
+        fn bench_runs_the_program_repeatedly() {
+            let written = Rc::new(Cell::new(0));
+            let mut interpreter = new(String::from("++[>+<-]>.")).with_output(Box::new(ByteCounter(written.clone()))).build().unwrap();
+            let times = interpreter.bench(7).unwrap();
+            assert_eq!(times.len(), 7);
+            assert_eq!(written.get(), 7);
+            assert_eq!(interpreter.memory(), &[0, 2]);
+        }
+
+        #[test]
+        fn get_char_reads_one_byte_per_command() {
+            let reads = Rc::new(Cell::new(0));
+            let requested = Rc::new(Cell::new(0));
+            let reader = CountingReader { len: 100_000, reads: reads.clone(), requested: requested.clone() };
+            let mut interpreter = new(",".repeat(10_000))
+                .with_input(Box::new(reader))
+                .build()
+                .unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(reads.get(), 10_000);
+            assert_eq!(requested.get(), 10_000);
+        }
+
+        #[test]
+        fn loops_lists_spans_and_depths() {
+            let mut interpreter = new(String::from("+[>[-]<[x[-]]]>[-]")).build().unwrap();
+            let spans : Vec<(usize, usize, usize)> = interpreter.loops().unwrap().iter().map(|l| (l.start, l.end, l.depth)).collect();
+            assert_eq!(spans, [(1, 13, 1), (3, 5, 2), (7, 12, 2), (9, 11, 3), (15, 17, 1)]);
+        }
+
+        #[test]
+        fn input_encoding_decides_the_bytes_of_e_acute() {
+            let run = |encoding: InputEncoding, input: &'static [u8]| {
+                let mut interpreter = new(String::from(",[.,]"))
+                    .with_input_encoding(encoding)
+                    .with_input(Box::new(input))
+                    .with_captured_output()
+                    .build()
+                    .unwrap();
+                interpreter.run().map(|_| interpreter.take_output())
+            };
+            assert_eq!(run(InputEncoding::Utf8, "é".as_bytes()).unwrap(), [0xc3, 0xa9]);
+            assert_eq!(run(InputEncoding::Latin1, "é".as_bytes()).unwrap(), [0xe9]);
+            assert!(run(InputEncoding::Latin1, "€".as_bytes()).is_err());
+        }
+
+        #[test]
+        fn io_bound_tells_echo_from_compute() {
+            let mut interpreter = new(String::from("++++++++[>++++++++[>++++<-]<-]>>.")).with_captured_output().build().unwrap();
+            interpreter.run().unwrap();
+            assert!(!interpreter.is_io_bound());
+            let mut interpreter = new(String::from(",[.,]"))
+                .with_input(Box::new(&b"hello, world"[..]))
+                .with_captured_output()
+                .build()
+                .unwrap();
+            interpreter.run().unwrap();
+            assert!(interpreter.is_io_bound());
+        }
+
+        #[test]
+        fn require_input_fails_instead_of_eof() {
+            let build = |require: bool| {
+                new(String::from("+++,."))
+                    .with_require_input(require)
+                    .with_input(Box::new(std::io::empty()))
+                    .with_eof_policy(EofPolicy::Unchanged)
+                    .with_captured_output()
+                    .build()
+                    .unwrap()
+            };
+            let mut interpreter = build(true);
+            assert!(matches!(fault(interpreter.run()), Error::InputRequired));
+            let mut interpreter = build(false);
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.take_output(), [3]);
+        }
+
+        #[test]
+        fn checked_arith_fails_on_overflow() {
+            let mut interpreter = new(String::from(">+<+")).with_initial_tape(vec![255]).with_arith_mode(ArithMode::Checked).build().unwrap();
+            match interpreter.run() {
+                Err(Error::At{ offset: 3, command: '+', error }) => assert!(matches!(*error, Error::ArithmeticOverflow)),
+                other => panic!("{:?}", other),
+            }
+            let mut interpreter = new(String::from("-")).with_arith_mode(ArithMode::Checked).build().unwrap();
+            assert!(matches!(fault(interpreter.run()), Error::ArithmeticOverflow));
+            let mut interpreter = new(String::from("+")).with_initial_tape(vec![255]).build().unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.memory(), &[0]);
+        }
+
+        #[test]
+        fn loop_tree_shows_siblings_and_nesting() {
+            let mut interpreter = new(String::from("+[>[-]<[-]-]>[-]")).build().unwrap();
+            assert_eq!(interpreter.loop_tree().unwrap(), "loop 1..11 (7 ops)\n  loop 3..5 (1 ops)\n  loop 7..9 (1 ops)\nloop 13..15 (1 ops)\n");
+        }
+
+        #[test]
+        fn cells_touched_counts_distinct_cells() {
+            let mut interpreter = new(String::from("+>+>+<<.")).with_profile(true).build().unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.cells_touched(), 3);
+        }
+
+        #[test]
+        fn ptr_modulo_wraps_right_to_zero() {
+            let mut interpreter = new(String::from("+>++>+++>++++<<<<+")).with_ptr_modulo(3).build().unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.memory(), &[5, 2, 4]);
+            assert_eq!(interpreter.pointer(), 2);
+        }
+
+        #[test]
+        fn stats_json_counts_input_bytes_not_reads() {
+            let mut interpreter = new(String::from(",[.,]"))
+                .with_input(Box::new(std::io::Cursor::new(b"ab".to_vec())))
+                .with_captured_output()
+                .build()
+                .unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.input_ops(), 3);
+            let json = interpreter.stats_json(std::time::Duration::ZERO);
+            assert!(json.contains("\"bytes_in\":2,"), "{}", json);
+        }
+
+        #[test]
+        fn stats_json_has_numeric_keys() {
+            let mut interpreter = new(String::from("++[>+<-]>.")).with_captured_output().build().unwrap();
+            interpreter.run().unwrap();
+            let json = interpreter.stats_json(std::time::Duration::from_millis(5));
+            let body = json.strip_prefix('{').and_then(|s| s.strip_suffix('}')).unwrap();
+            let fields : Vec<(&str, &str)> = body.split(',').map(|f| f.split_once(':').unwrap()).collect();
+            let keys : Vec<&str> = fields.iter().map(|f| f.0).collect();
+            assert_eq!(keys, ["\"steps\"", "\"loop_iterations\"", "\"bytes_in\"", "\"bytes_out\"",
+                "\"tape_high_water\"", "\"cells_touched\"", "\"wall_time_ms\""]);
+            for (key, value) in fields.iter() {
+                assert!(value.parse::<f64>().is_ok(), "{} = {}", key, value);
+            }
+            assert!(json.contains("\"loop_iterations\":2,"), "{}", json);
+            assert!(json.contains("\"bytes_out\":1,"), "{}", json);
+            assert!(json.ends_with("\"wall_time_ms\":5.000}"), "{}", json);
+        }
+
+        #[test]
+        fn keep_going_skips_a_recoverable_fault() {
+            let build = |keep_going: bool| {
+                new(String::from("+<+.")).with_left_growth(false).with_keep_going(keep_going).with_captured_output().build().unwrap()
+            };
+            let mut interpreter = build(true);
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.take_output(), [2]);
+            let mut interpreter = build(false);
+            assert!(matches!(fault(interpreter.run()), Error::PointerUnderflow));
+            let mut interpreter = new(String::from(">")).with_max_tape(1).with_keep_going(true).build().unwrap();
+            assert!(interpreter.run().is_err());
+        }
+
+        #[test]
+        fn same_structure_ignores_comments_and_layout() {
+            let mut original = new(String::from("read: ,[->+<] then print >.")).build().unwrap();
+            let mut reformatted = new(String::from(",\n[\n  -\n  >+<\n]\n>.\n")).build().unwrap();
+            assert!(original.same_structure(&mut reformatted).unwrap());
+            let mut other = new(String::from(",[-<+>]>.")).build().unwrap();
+            assert!(!original.same_structure(&mut other).unwrap());
+            let mut shallower = new(String::from(",[->+<]>[.]")).build().unwrap();
+            assert!(!original.same_structure(&mut shallower).unwrap());
+        }
+
+        #[test]
+        fn loop_timing_ranks_the_dominant_loop_first() {
+            let source = "+[-]>++++++++++[>++++++++++[>++++++++++[-]<-]<-]";
+            let mut interpreter = new(String::from(source)).with_loop_timing(true).build().unwrap();
+            interpreter.run().unwrap();
+            let times = interpreter.loop_times();
+            assert_eq!(times.len(), 4);
+            assert_eq!(times[0].0, 15);
+            assert!(times.windows(2).all(|w| w[0].1 >= w[1].1));
+            let mut interpreter = new(String::from(source)).build().unwrap();
+            interpreter.run().unwrap();
+            assert!(interpreter.loop_times().is_empty());
+        }
+
+        #[test]
+        fn max_source_bytes_rejects_only_oversized_sources() {
+            let mut interpreter = new("+".repeat(11)).with_max_source_bytes(10).build().unwrap();
+            assert!(matches!(interpreter.run(), Err(Error::SourceTooLarge(10))));
+            let result = new("+".repeat(11)).with_max_source_bytes(10).with_validate_on_build(true).build();
+            assert!(matches!(result, Err(Error::SourceTooLarge(10))));
+            let mut interpreter = new("+".repeat(10)).with_max_source_bytes(10).build().unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.memory(), &[10]);
+        }
+
+        #[test]
+        fn the_last_step_finishes_the_output() {
+            let mut interpreter = new(String::from("+++."))
+                .with_output_format(OutputFormat::Rle)
+                .with_ensure_newline(true)
+                .with_captured_output()
+                .build()
+                .unwrap();
+            interpreter.run().unwrap();
+            let expected = interpreter.take_output();
+            assert!(!expected.is_empty());
+            interpreter.prepare().unwrap();
+            while interpreter.step().unwrap() == Status::Running {}
+            assert_eq!(interpreter.take_output(), expected);
+        }
+
+        #[test]
+        fn rle_output_collapses_a_run() {
+            let source = format!("{}{}+.+.", "+".repeat(72), ".".repeat(10));
+            let mut interpreter = new(source).with_output_format(OutputFormat::Rle).with_captured_output().build().unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.take_output(), b"72x10 73x1 74x1");
+        }
+
+        #[test]
+        fn step_back_takes_back_printed_bytes() {
+            let mut interpreter = new(String::from("+.+.")).with_undo(true).with_captured_output().build().unwrap();
+            interpreter.prepare().unwrap();
+            while interpreter.step().unwrap() == Status::Running {}
+            assert!(interpreter.step_back());
+            assert_eq!(interpreter.bytes_written(), 1);
+            assert!(interpreter.step_back());
+            assert_eq!(interpreter.memory(), &[1]);
+            assert_eq!(interpreter.take_output(), [1]);
+        }
+
+        #[test]
+        fn malformed_op_streams_are_internal_errors() {
+            for ops in [vec![Op::Increment, Op::LoopEnd], vec![Op::LoopStart, Op::Increment]] {
+                let mut interpreter = new(String::new()).build().unwrap();
+                interpreter.offsets = (0..ops.len()).collect();
+                interpreter.ops = ops;
+                assert!(matches!(interpreter.build_instruction(), Err(Error::Internal(_))));
+                assert!(matches!(interpreter.build_jumps(), Err(Error::Internal(_))));
+                assert!(matches!(compile(&interpreter.ops), Err(Error::Internal(_))));
+                assert!(matches!(compile_spans(&interpreter.ops), Err(Error::Internal(_))));
+            }
+        }
+
+        #[test]
+        fn seeded_tape_repeats_for_the_same_seed() {
+            let tape = |seed: u64, len: usize| {
+                let mut interpreter = new(String::new()).with_seeded_tape(seed, len).build().unwrap();
+                interpreter.run().unwrap();
+                interpreter.memory().to_vec()
+            };
+            assert_eq!(tape(42, 32), tape(42, 32));
+            assert_eq!(tape(42, 32).len(), 32);
+            assert_ne!(tape(42, 32), tape(43, 32));
+            assert_eq!(tape(42, 8), tape(42, 32)[..8]);
+        }
+
+        #[test]
+        fn optimized_c_counts_runs() {
+            let mut interpreter = new(String::from("+++++>>>.")).with_opt_level(1).build().unwrap();
+            let c = interpreter.to_c(false).unwrap();
+            assert!(c.contains("+= 5"), "{}", c);
+            assert!(c.contains("p += 3"), "{}", c);
+            let mut interpreter = new(String::from("+++++>>>.")).build().unwrap();
+            assert!(!interpreter.to_c(false).unwrap().contains("+= 5"));
+        }
+
+        #[test]
+        fn check_interval_bounds_the_overshoot() {
+            let steps = |interval: u64| {
+                let mut interpreter = new(String::from("+[]")).with_max_steps(1000).with_check_interval(interval).build().unwrap();
+                assert!(matches!(interpreter.run(), Err(Error::StepLimitExceeded(1000))));
+                interpreter.steps()
+            };
+            assert_eq!(steps(1), 1001);
+            let overshoot = steps(300);
+            assert!((1001..1300).contains(&overshoot), "{}", overshoot);
+        }
+
+        #[test]
+        fn check_interval_saves_clock_reads() {
+            let checks = |interval: u64| {
+                let mut interpreter = new(String::from("+[]"))
+                    .with_max_steps(300_000)
+                    .with_check_interval(interval)
+                    .build()
+                    .unwrap();
+                assert!(matches!(interpreter.run(), Err(Error::StepLimitExceeded(_))));
+                interpreter.limit_checks()
+            };
+            // Steps 1, 10_001, ... 300_001, against every step up to the one
+            // past the limit.
+            assert_eq!(checks(10_000), 31);
+            assert_eq!(checks(1), 300_001);
+        }
+
+        #[test]
+        fn tokens_skip_comments_and_keep_offsets() {
+            let tokens = tokens_with_offsets("add + then\n[-] ok.");
+            assert_eq!(tokens, [(4, Op::Increment), (11, Op::LoopStart), (12, Op::Decrement), (13, Op::LoopEnd), (17, Op::PrintChar)]);
+            assert!(tokens_with_offsets("no commands").is_empty());
+        }
+
+        #[test]
+        fn signed_display_shows_negative_cells() {
+            let mut interpreter = new("+".repeat(200)).with_signed_display(true).build().unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.display_cell(interpreter.memory()[0]), "-56");
+            assert_eq!(interpreter.display_cell(100), "100");
+            assert_eq!(interpreter.memory(), &[200]);
+            let interpreter = interpreter.with_signed_display(false);
+            assert_eq!(interpreter.display_cell(200), "200");
+        }
+
+        #[test]
+        fn state_hash_is_stable_per_program() {
+            let hash = |source: &str| {
+                let mut interpreter = new(String::from(source)).with_captured_output().build().unwrap();
+                interpreter.run().unwrap();
+                interpreter.state_hash()
+            };
+            assert_eq!(hash("++[>+++<-]>."), hash("++[>+++<-]>."));
+            assert_eq!(hash("++[>+++<-]>."), hash("++[>+++<-]>.>>>><<<<"));
+            assert_ne!(hash("++[>+++<-]>."), hash("++[>+++<-]>+."));
+            assert_ne!(hash("+>"), hash("+"));
+        }
+
+        #[test]
+        fn step_back_rewinds_replayable_input() {
+            let mut interpreter = new(String::from(",.,."))
+                .with_replayable_input(true)
+                .with_undo(true)
+                .with_input(Box::new(&b"xy"[..]))
+                .with_captured_output()
+                .build()
+                .unwrap();
+            interpreter.prepare().unwrap();
+            interpreter.step().unwrap();
+            interpreter.step().unwrap();
+            interpreter.step().unwrap();
+            assert_eq!(interpreter.memory(), b"y");
+            assert!(interpreter.step_back());
+            assert_eq!(interpreter.memory(), b"x");
+            interpreter.step().unwrap();
+            assert_eq!(interpreter.memory(), b"y");
+            assert_eq!(interpreter.resume().unwrap(), Status::Finished);
+            assert_eq!(interpreter.take_output(), b"xy");
+        }
+
+        #[test]
+        fn sexpr_nests_loops() {
+            let mut interpreter = new(String::from("++[->[.<]],")).build().unwrap();
+            assert_eq!(interpreter.to_sexpr().unwrap(), "(seq + + (loop - > (loop . <)) ,)");
+        }
+
+        #[test]
+        fn max_nesting_refuses_deeper_sources() {
+            let mut interpreter = new(String::from("+[[>[-]]<]")).with_max_nesting(2).build().unwrap();
+            assert!(matches!(interpreter.run(), Err(Error::NestingLimitExceeded(2, 4))));
+            let mut interpreter = new(String::from("+[[-]]")).with_max_nesting(2).build().unwrap();
+            interpreter.run().unwrap();
+        }
+
+        #[test]
+        fn comment_density_counts_comment_characters() {
+            // Whitespace is layout, not comment.
+            let mut interpreter = new(String::from("add: ++ print: .")).build().unwrap();
+            let metrics = interpreter.metrics().unwrap();
+            assert_eq!(metrics.comment_chars, 10);
+            assert!((metrics.comment_density() - 10.0 / 3.0).abs() < 1e-9);
+        }
+
+        #[test]
+        fn dual_tapes_keep_their_own_cells() {
+            let mut interpreter = new(String::from("+++>+%++>>+%<.")).with_dual_tape(true).with_captured_output().build().unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.memory(), &[3, 1]);
+            assert_eq!(interpreter.other_memory, &[2, 0, 1]);
+            assert_eq!(interpreter.take_output(), [3]);
+            // Without the mode `%` is a comment.
+            let mut interpreter = new(String::from("+%+")).build().unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.memory(), &[2]);
+        }
+
+        #[test]
+        fn step_hook_records_the_ops() {
+            let seen = Rc::new(RefCell::new(Vec::<(Op, usize, u8, u64)>::new()));
+            let mut interpreter = new(String::from("+[->+<]")).build().unwrap();
+            let log = seen.clone();
+            interpreter.set_hook(Box::new(move |info: &StepInfo| log.borrow_mut().push((info.op, info.pointer, info.cell, info.step))));
+            interpreter.prepare().unwrap();
+            while interpreter.step().unwrap() == Status::Running {}
+            let ops : Vec<Op> = seen.borrow().iter().map(|s| s.0).collect();
+            assert_eq!(ops, [Op::Increment, Op::LoopStart, Op::Decrement, Op::ShiftRight, Op::Increment,
+                Op::ShiftLeft, Op::LoopEnd]);
+            assert_eq!(seen.borrow()[4], (Op::Increment, 1, 1, 5));
+        }
+
+        #[test]
+        fn summarize_reports_cell_deltas() {
+            let mut interpreter = new(String::from("+++>++<")).build().unwrap();
+            let summary = interpreter.summarize().unwrap().unwrap();
+            assert_eq!(summary.deltas.into_iter().collect::<Vec<_>>(), [(0, 3), (1, 2)]);
+            assert_eq!(summary.pointer, 0);
+            let mut interpreter = new(String::from("+>-->")).build().unwrap();
+            let summary = interpreter.summarize().unwrap().unwrap();
+            assert_eq!(summary.deltas.into_iter().collect::<Vec<_>>(), [(0, 1), (1, 254)]);
+            assert_eq!(summary.pointer, 2);
+            let mut interpreter = new(String::from("+.")).build().unwrap();
+            assert!(interpreter.summarize().unwrap().is_none());
+        }
+
+        #[test]
+        fn locked_tape_refuses_writes() {
+            let mut interpreter = new(String::from("+++>++<.>.+")).with_captured_output().build().unwrap();
+            interpreter.prepare().unwrap();
+            for _ in 0..6 {
+                interpreter.step().unwrap();
+            }
+            interpreter.lock_tape();
+            assert!(matches!(interpreter.resume(), Err(Error::At{ offset: 10, command: '+', .. })));
+            assert_eq!(interpreter.take_output(), [3, 2]);
+            assert_eq!(interpreter.memory(), &[3, 2]);
+        }
+
+        #[test]
+        fn locked_tape_holds_in_every_evaluator() {
+            for (flat, level) in [(false, 0), (true, 0), (false, 3)] {
+                let mut interpreter = new(String::from(".>[-]+")).with_flat(flat).with_opt_level(level).with_captured_output().build().unwrap();
+                interpreter.prepare().unwrap();
+                interpreter.lock_tape();
+                assert!(matches!(interpreter.eval_instruction(), Err(Error::At{ offset: 5, command: '+', .. })));
+                assert_eq!(interpreter.take_output(), [0]);
+            }
+        }
+
+        const COMPUTE : &str = "-[>-[>+>++<<-]<-]>>.>.";
+
+        fn timed(interpreter: &mut Interpreter) -> (Vec<u8>, std::time::Duration) {
+            let start = std::time::Instant::now();
+            interpreter.run().unwrap();
+            (interpreter.take_output(), start.elapsed())
+        }
+
+        #[test]
+        fn fast_path_matches_the_generic_evaluator() {
+            let mut fast = new(String::from(COMPUTE)).with_flat(true).with_captured_output().build().unwrap();
+            // A step limit forces the generic flat evaluator.
+            let mut generic = new(String::from(COMPUTE)).with_flat(true).with_max_steps(u64::MAX).with_captured_output().build().unwrap();
+            assert_eq!(timed(&mut fast).0, timed(&mut generic).0);
+            assert_eq!(fast.memory(), generic.memory());
+            assert_eq!(fast.steps(), generic.steps());
+        }
+
+        // A wall-clock benchmark, so it is left out of the normal run:
+        // `cargo test --release -- --ignored fast_path_is_faster`.
+        #[test]
+        #[ignore]
+        fn fast_path_is_faster() {
+            let best = |max_steps: Option<u64>| {
+                (0..3).map(|_| {
+                    let mut interpreter = new(String::from(COMPUTE)).with_flat(true).with_captured_output();
+                    if let Some(max) = max_steps {
+                        interpreter = interpreter.with_max_steps(max);
+                    }
+                    timed(&mut interpreter.build().unwrap()).1
+                }).min().unwrap()
+            };
+            let (fast, generic) = (best(None), best(Some(u64::MAX)));
+            eprintln!("fast path: {:?}, generic evaluator: {:?}", fast, generic);
+            assert!(fast < generic);
+        }
+
+        #[test]
+        fn image_resumes_where_it_stopped() {
+            let source = "++++++++[>++++++++<-]>+.+.+.";
+            let mut first = new(String::from(source)).with_captured_output().build().unwrap();
+            first.prepare().unwrap();
+            for _ in 0..40 {
+                first.step().unwrap();
+            }
+            let mut image = Vec::<u8>::new();
+            first.save_image(&mut image).unwrap();
+            let mut second = load_image(&image).unwrap().with_captured_output();
+            assert_eq!(second.resume().unwrap(), Status::Finished);
+            assert_eq!(first.resume().unwrap(), Status::Finished);
+            assert_eq!(second.take_output(), b"ABC");
+            assert_eq!(second.memory(), first.memory());
+            assert_eq!(second.steps(), first.steps());
+            assert!(load_image(&image[..image.len() - 1]).is_err());
+        }
+
+        #[test]
+        fn image_keeps_left_growth_and_rejects_bad_positions() {
+            let source = "<<++[->+<]>.";
+            let mut first = new(String::from(source)).with_captured_output().build().unwrap();
+            first.prepare().unwrap();
+            for _ in 0..6 {
+                first.step().unwrap();
+            }
+            let mut image = Vec::<u8>::new();
+            first.save_image(&mut image).unwrap();
+            let mut second = load_image(&image).unwrap().with_captured_output();
+            assert_eq!(second.pointer(), first.pointer());
+            second.resume().unwrap();
+            first.resume().unwrap();
+            assert_eq!((second.pointer(), second.memory()), (first.pointer(), first.memory()));
+            assert_eq!(second.take_output(), first.take_output());
+            // The positions follow the magic and the program's commands:
+            // pc, depth, pointer, second pointer and left growth.
+            let positions = IMAGE_MAGIC.len() + 8 + source.len();
+            for (field, value) in [(0, 100), (1, 2), (2, 1 << 20), (4, 1 << 20)] {
+                let mut bad = image.clone();
+                let at = positions + field * 8;
+                bad[at..at + 8].copy_from_slice(&(value as u64).to_le_bytes());
+                assert!(matches!(load_image(&bad), Err(Error::InvalidImage)), "{}", field);
+            }
+        }
+
+        #[test]
+        fn tree_evaluator_matches_the_flat_one() {
+            let programs = [
+                String::from("++[>++[>++[>+<-]<-]<-]>>>.,[.,]"),
+                format!("{}.", nested(5_000)),
+                format!("+{}+.", "[>+[-]<-]".repeat(100)),
+            ];
+            for source in programs.iter() {
+                let run = |flat: bool| {
+                    let mut interpreter = new(source.clone())
+                        .with_flat(flat)
+                        .with_input(Box::new(&b"xyz"[..]))
+                        .with_captured_output()
+                        .build()
+                        .unwrap();
+                    interpreter.run().unwrap();
+                    (interpreter.take_output(), interpreter.memory().to_vec(), interpreter.pointer())
+                };
+                assert_eq!(run(false), run(true));
+            }
+        }
+
+        #[test]
+        fn run_sequence_shares_the_tape() {
+            let mut interpreter = new(String::new()).with_captured_output().build().unwrap();
+            interpreter.run_sequence(&["++++++++[>++++++++<-]>+>", "<.+."]).unwrap();
+            assert_eq!(interpreter.take_output(), b"AB");
+            assert_eq!(interpreter.pointer(), 1);
+            assert!(interpreter.run_sequence(&["+", "]"]).is_err());
+        }
+
+        #[test]
+        fn leading_bom_is_stripped() {
+            let mut source = b"\xef\xbb\xbf".to_vec();
+            source.extend_from_slice(b"++<.");
+            let mut interpreter = from_bytes(source).with_strict(true).with_left_growth(false).with_captured_output().build().unwrap();
+            match interpreter.run() {
+                Err(Error::At{ offset: 2, command: '<', .. }) => (),
+                other => panic!("{:?}", other),
+            }
+            assert_eq!(interpreter.source(), b"++<.");
+            assert_eq!(run_bytes(b"\xef\xbb\xbf+++.", b"").unwrap(), [3]);
+        }
+
+        #[test]
+        fn pointer_range_is_static_or_unbounded() {
+            let mut interpreter = new(String::from(">>+[<+>-]<<<>")).build().unwrap();
+            assert_eq!(interpreter.pointer_range().unwrap(), Some(PointerRange{ min: -1, max: 2 }));
+            assert_eq!(interpreter.pointer_range().unwrap().unwrap().cells(), 4);
+            let mut interpreter = new(String::from("+[>+]")).build().unwrap();
+            assert_eq!(interpreter.pointer_range().unwrap(), None);
+        }
+
+        #[test]
+        fn clock_is_read_only_when_needed() {
+            let mut interpreter = from_bytes(b"+.".to_vec()).with_captured_output().build().unwrap();
+            interpreter.run().unwrap();
+            assert!(interpreter.epoch.is_none());
+            let mut interpreter = interpreter.with_max_time(std::time::Duration::from_secs(60));
+            interpreter.run().unwrap();
+            assert!(interpreter.epoch.is_some());
+        }
+
+        // Collects everything written to it in a buffer shared with the test.
+        struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+        impl std::io::Write for SharedBuf {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        #[test]
+        fn transcript_interleaves_reads_and_prints() {
+            let log = Rc::new(RefCell::new(Vec::<u8>::new()));
+            let mut interpreter = new(String::from(",+.,+.,"))
+                .with_input(Box::new(&b"ab"[..]))
+                .with_transcript(Box::new(SharedBuf(log.clone())))
+                .with_captured_output()
+                .build()
+                .unwrap();
+            interpreter.run().unwrap();
+            let text = String::from_utf8(log.borrow().clone()).unwrap();
+            let events : Vec<&str> = text.lines().map(|l| l.split_once(' ').unwrap().1).collect();
+            assert_eq!(events, ["in 97 'a'", "out 98 'b'", "in 98 'b'", "out 99 'c'", "in EOF"]);
+            assert!(text.lines().all(|l| l.split_once("ms ").is_some()));
+        }
+
+        #[test]
+        fn a_failed_transcript_write_is_an_error() {
+            // A transcript with room for eight bytes fills up on the first line.
+            let mut interpreter = new(String::from("+.+."))
+                .with_transcript(Box::new(std::io::Cursor::new([0u8; 8])))
+                .with_captured_output()
+                .build()
+                .unwrap();
+            assert!(matches!(interpreter.run(), Err(Error::Io(_))));
+            assert_eq!(interpreter.bytes_written(), 1);
+        }
+
+        #[test]
+        fn dry_count_matches_a_hand_count() {
+            // `+++`, then three passes of `[-]` each a test and a `-` plus
+            // the final test, then `.,.`: 3 + 3 * 2 + 1 + 3.
+            let mut interpreter = new(String::from("+++[-].,.")).with_captured_output().build().unwrap();
+            assert_eq!(interpreter.dry_count(b"").unwrap(), 13);
+            assert!(interpreter.take_output().is_empty());
+            let mut interpreter = new(String::from(",[.,]")).with_opt_level(3).build().unwrap();
+            assert_eq!(interpreter.dry_count(b"ab").unwrap(), 1 + 3 + 3 + 1);
+        }
+
+        struct NoInput;
+
+        impl std::io::Read for NoInput {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                panic!("the sandbox read real input");
+            }
+        }
+
+        #[test]
+        fn sandbox_cycles_through_its_buffer() {
+            let run = |input: SandboxInput| {
+                let mut interpreter = new(String::from(",>,>,>,>,"))
+                    .with_sandbox_input(input)
+                    .with_input(Box::new(NoInput))
+                    .build()
+                    .unwrap();
+                interpreter.run().unwrap();
+                interpreter.memory().to_vec()
+            };
+            assert_eq!(run(SandboxInput::Cycle(b"xy".to_vec())), b"xyxyx");
+            assert_eq!(run(SandboxInput::Cycle(b"xy".to_vec())), run(SandboxInput::Cycle(b"xy".to_vec())));
+            assert_eq!(run(SandboxInput::Byte(7)), [7; 5]);
+            assert_eq!(run(SandboxInput::Eof), [0; 5]);
+            assert_eq!(run(SandboxInput::Cycle(Vec::<u8>::new())), [0; 5]);
+        }
+
+        #[test]
+        fn explain_opt_pairs_spans_with_fused_ops() {
+            let mut interpreter = new(String::from("go +++++.")).build().unwrap();
+            let listing = interpreter.explain_opt().unwrap();
+            let lines : Vec<&str> = listing.lines().collect();
+            assert_eq!(lines.len(), 2, "{}", listing);
+            assert!(lines[0].starts_with("src[3..8] '+++++'  =>  "), "{}", listing);
+            assert!(lines[0].contains('5'), "{}", listing);
+            assert!(lines[1].starts_with("src[8..9] '.'  =>  "), "{}", listing);
+        }
+
+        #[test]
+        fn code_after_an_endless_loop_is_unreachable() {
+            let mut interpreter = new(String::from("+[]>+.")).build().unwrap();
+            assert_eq!(interpreter.unreachable().unwrap(), [lint::Span{ start: 3, end: 5 }]);
+            let mut interpreter = new(String::from("+[-]>+.")).build().unwrap();
+            assert!(interpreter.unreachable().unwrap().is_empty());
+        }
+
+        #[test]
+        fn fill_value_sets_fresh_cells() {
+            let mut interpreter = new(String::from(".>.>>.<<<<.")).with_fill_value(1).with_initial_tape(vec![5]).with_captured_output().build().unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.take_output(), [5, 1, 1, 1]);
+            let mut interpreter = new(String::from("+[-]>,")).with_fill_value(1).with_input(Box::new(std::io::empty())).build().unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.memory(), &[0, 0]);
+        }
+
+        #[test]
+        fn cancel_flag_stops_the_run() {
+            use std::sync::atomic::{AtomicBool, Ordering};
+            let flag = std::sync::Arc::new(AtomicBool::new(false));
+            let setter = flag.clone();
+            let handle = std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                setter.store(true, Ordering::Relaxed);
+            });
+            let mut interpreter = new(String::from("+[]")).with_cancel(flag).build().unwrap();
+            let start = std::time::Instant::now();
+            assert!(matches!(interpreter.run(), Err(Error::Cancelled)));
+            assert!(start.elapsed() < std::time::Duration::from_secs(10));
+            handle.join().unwrap();
+        }
+
+        #[test]
+        fn size_table_shrinks_level_by_level() {
+            let mut interpreter = new(String::from("[-]+++--[->+<]>[-]+[>++<-]")).build().unwrap();
+            let sizes = interpreter.size_table(3).unwrap();
+            assert_eq!(sizes.len(), 4);
+            assert!(sizes.windows(2).all(|w| w[0] > w[1]), "{:?}", sizes);
+        }
+
+        #[test]
+        fn leading_loop_is_never_entered() {
+            let mut interpreter = new(String::from("[.],[-]")).build().unwrap();
+            let warnings = interpreter.warnings().unwrap();
+            assert_eq!(warnings.len(), 1);
+            assert_eq!(warnings[0].offset, 0);
+            assert_eq!(warnings[0].message, "loop never runs: the cell is always zero when it is reached");
+            let mut interpreter = new(String::from("[.]")).with_initial_tape(vec![1]).build().unwrap();
+            assert!(interpreter.warnings().unwrap().is_empty());
+        }
+
+        #[test]
+        fn instructions_expose_the_tree() {
+            let mut interpreter = new(String::from("+[->+<].")).build().unwrap();
+            interpreter.prepare().unwrap();
+            let inst = interpreter.instructions();
+            assert_eq!(inst.len(), 3);
+            assert_eq!(inst[0], Instruction::Increment);
+            match &inst[1] {
+                Instruction::Loop(body) => assert_eq!(body, &[Instruction::Decrement, Instruction::ShiftRight,
+                    Instruction::Increment, Instruction::ShiftLeft]),
+                other => panic!("{:?}", other),
+            }
+            assert_eq!(inst[2], Instruction::PrintChar);
+        }
+
+        #[test]
+        fn annotated_source_runs_the_same() {
+            let source = "++[>++[>+<-]<-]>>.";
+            let mut interpreter = new(String::from(source)).build().unwrap();
+            let annotated = interpreter.annotated_source().unwrap();
+            assert!(annotated.contains("](loop#2 depth2)\n"), "{}", annotated);
+            assert!(annotated.contains("](loop#1 depth1)\n"), "{}", annotated);
+            assert_eq!(run_bytes(&annotated, b"").unwrap(), run_bytes(source, b"").unwrap());
+        }
+
+        #[test]
+        fn assembly_round_trip_runs_the_same() {
+            let source = "++++++++[>++++++++<-]>+.<,[>.<-]";
+            let asm = new(String::from(source)).build().unwrap().to_asm().unwrap();
+            assert!(asm.contains("add 8"), "{}", asm);
+            let run = |interpreter: Interpreter| {
+                let mut interpreter = interpreter.with_input(Box::new(&b"\x03"[..])).with_captured_output().build().unwrap();
+                interpreter.run().unwrap();
+                interpreter.take_output()
+            };
+            assert_eq!(run(from_asm(&asm).unwrap()), run(new(String::from(source))));
+            assert_eq!(run(from_asm(&asm).unwrap()), b"AAAA");
+            assert!(matches!(from_asm("add 1\nloop\n"), Err(Error::InvalidAsm(2, _))));
+        }
+
+        #[test]
+        fn work_weighs_nested_steps_more() {
+            let work = |source: &str| {
+                let mut interpreter = new(String::from(source)).with_work(true).build().unwrap();
+                interpreter.run().unwrap();
+                (interpreter.work().unwrap(), interpreter.steps())
+            };
+            assert_eq!(work("+++"), (3, 3));
+            let (weighted, steps) = work("++[>+++[>+<-]<-]");
+            // `++` at depth 0, three outer tests and twelve body ops at
+            // depth 1, then per outer pass four inner tests and twelve
+            // body ops at depth 2: 2 + 2 * 15 + 3 * 32.
+            assert_eq!((weighted, steps), (128, 49));
+            let mut interpreter = new(String::from("+")).build().unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.work(), None);
+        }
+
+        #[test]
+        fn cleared_buffers_do_not_leak_into_the_next_run() {
+            let mut interpreter = new(String::from(",."))
+                .with_cooperative_input(true)
+                .with_captured_output()
+                .build()
+                .unwrap();
+            interpreter.prepare().unwrap();
+            interpreter.provide_input(b'a');
+            interpreter.provide_input(b'b');
+            interpreter.resume().unwrap();
+            assert_eq!(interpreter.take_output(), b"a");
+            assert_eq!(interpreter.buffered_input(), b"b");
+            interpreter.clear_io_buffers();
+            assert!(interpreter.buffered_input().is_empty());
+            interpreter.prepare().unwrap();
+            assert_eq!(interpreter.resume().unwrap(), Status::NeedInput);
+            interpreter.provide_input(b'c');
+            interpreter.resume().unwrap();
+            assert_eq!(interpreter.take_output(), b"c");
+        }
+
+        #[test]
+        fn repeated_runs_skip_the_parse() {
+            let mut interpreter = new(String::from(",[.,]")).with_captured_output().build().unwrap();
+            for input in [&b"one"[..], b"two", b"three"] {
+                interpreter = interpreter.with_input(Box::new(input));
+                interpreter.run().unwrap();
+                assert_eq!(interpreter.take_output(), input);
+            }
+            assert_eq!(interpreter.parse_count(), 1);
+            let mut interpreter = interpreter.with_opt_level(2);
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.parse_count(), 2);
+        }
+
+        #[test]
+        fn saturating_cells_clamp() {
+            let mut interpreter = new(String::from("++>--")).with_initial_tape(vec![254, 1]).with_arith_mode(ArithMode::Saturating).build().unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.memory(), &[255, 0]);
+        }
+
+        #[test]
+        fn consumed_input_is_the_read_prefix() {
+            let mut interpreter = new(String::from(",>,>,"))
+                .with_consumed_input(true)
+                .with_input(Box::new(&b"abcdef"[..]))
+                .build()
+                .unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.consumed_input(), b"abc");
+            let mut interpreter = new(String::from(",,")).with_input(Box::new(&b"x"[..])).with_consumed_input(true).build().unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.consumed_input(), b"x");
+        }
+
+        #[test]
+        fn counted_source_shows_how_often_each_command_ran() {
+            let mut interpreter = new(String::from("++\n[-]")).with_op_counts(true).build().unwrap();
+            interpreter.run().unwrap();
+            let counts : Vec<(u64, String, char)> = interpreter.counted_source().unwrap().lines().map(|l| {
+                let f : Vec<&str> = l.split_whitespace().collect();
+                (f[0].parse().unwrap(), String::from(f[1]), f[2].chars().next().unwrap())
+            }).collect();
+            let expected = [(1, "1:1", '+'), (1, "1:2", '+'), (1, "2:1", '['), (2, "2:2", '-'), (2, "2:3", ']')];
+            assert_eq!(counts.len(), expected.len());
+            for (got, want) in counts.iter().zip(expected.iter()) {
+                assert_eq!((got.0, got.1.as_str(), got.2), *want);
+            }
+        }
+    }
+}
+
+pub mod optimizer {
+    use crate::interpreter::Instruction;
+    use std::collections::BTreeMap;
+
+    // Optimized form of the instruction tree. Offsets are relative to the
+    // pointer at the start of the enclosing loop-free block; `Move` ends
+    // the block and starts a new one at the new pointer.
+    #[derive(Clone, PartialEq, Debug)]
+    pub enum Node {
+        Add(isize, u8),
+        Move(isize),
+        Print(isize),
+        Read(isize),
+        SetZero(isize),
+        // Adds the first cell to the cell `.1` away from it and zeroes it.
+        MoveValue(isize, isize),
+        // Adds the first cell to the cell `.1` away from it through the
+        // temporary `.2` away, as `[->+>+<<]>>[-<<+>>]` does: the temporary
+        // is then added back to the first cell and zeroed.
+        CopyValue(isize, isize, isize),
+        // Moves the pointer by the given step until it rests on a zero cell.
+        Scan(isize),
+        SwitchTape,
+        Loop(Vec<Node>),
+    }
+
+    // A single rewrite of the node tree. Every pass applies inside loops
+    // too, and each keeps the program's meaning on its own, so they can be
+    // run in any order.
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+    pub enum Pass {
+        // Collapses loop-free runs into one `Add` per cell and one `Move`.
+        FuseAdd,
+        // `[-]` and `[+]` become `SetZero`.
+        ClearLoop,
+        // Balanced loops that move the counter into one other cell, such
+        // as `[->+<]`, become `MoveValue`.
+        MoveLoop,
+        // A move loop into two cells followed by a move loop from the
+        // second back to the first, such as `[->+>+<<]>>[-<<+>>]`, becomes
+        // `CopyValue`. Needs `MoveLoop` to have run.
+        CopyLoop,
+        // `[>]`, `[<<]` and the like become `Scan`.
+        ScanLoop,
+        // Drops loops that follow a loop and so can never be entered.
+        DeadLoop,
+        // Inlines loops entered with the counter known to be one whose body
+        // always leaves it zero, such as the `[...-]` in `[-]+[...-]`.
+        OnceLoop,
+    }
+
+    impl std::str::FromStr for Pass {
+        type Err = String;
+        fn from_str(s: &str) -> Result<Pass, String> {
+            match s {
+                "fuse-add" => Ok(Pass::FuseAdd),
+                "clear-loop" => Ok(Pass::ClearLoop),
+                "move-loop" => Ok(Pass::MoveLoop),
+                "copy-loop" => Ok(Pass::CopyLoop),
+                "scan-loop" => Ok(Pass::ScanLoop),
+                "dead-loop" => Ok(Pass::DeadLoop),
+                "once-loop" => Ok(Pass::OnceLoop),
+                _ => Err(format!(
+                    "Unknown pass: {} (expected fuse-add, clear-loop, move-loop, copy-loop, scan-loop, dead-loop or once-loop)", s)),
+            }
+        }
+    }
+
+    impl std::fmt::Display for Pass {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            let name = match self {
+                Pass::FuseAdd => "fuse-add",
+                Pass::ClearLoop => "clear-loop",
+                Pass::MoveLoop => "move-loop",
+                Pass::CopyLoop => "copy-loop",
+                Pass::ScanLoop => "scan-loop",
+                Pass::DeadLoop => "dead-loop",
+                Pass::OnceLoop => "once-loop",
+            };
+            write!(f, "{}", name)
+        }
+    }
+
+    // The node count of the tree before and after one pass, loops counted
+    // as one node plus their bodies.
+    #[derive(Clone, Copy, Debug)]
+    pub struct PassReport {
+        pub pass: Pass,
+        pub before: usize,
+        pub after: usize,
+    }
+
+    // Level 1 fuses loop-free blocks, level 2 also rewrites clear, move,
+    // copy and scan loops, and level 3 also drops loops that can never be
+    // entered and inlines those that run exactly once.
+    pub fn level_passes(level: u8) -> Vec<Pass> {
+        match level {
+            0 => Vec::<Pass>::new(),
+            1 => vec![Pass::FuseAdd],
+            2 => vec![Pass::ClearLoop, Pass::MoveLoop, Pass::CopyLoop, Pass::ScanLoop, Pass::FuseAdd],
+            _ => vec![Pass::ClearLoop, Pass::MoveLoop, Pass::CopyLoop, Pass::ScanLoop, Pass::DeadLoop, Pass::OnceLoop,
+                Pass::FuseAdd],
+        }
+    }
+
+    pub(crate) fn optimize(inst: &[Instruction], passes: &[Pass]) -> Vec<Node> {
+        optimize_report(inst, passes).0
+    }
+
+    pub(crate) fn optimize_report(inst: &[Instruction], passes: &[Pass]) -> (Vec<Node>, Vec<PassReport>) {
+        let mut nodes = from_instructions(inst);
+        let mut report = Vec::<PassReport>::new();
+        for pass in passes.iter() {
+            let before = count_nodes(&nodes);
+            nodes = apply(*pass, nodes);
+            report.push(PassReport{ pass: *pass, before, after: count_nodes(&nodes) });
+        }
+        (nodes, report)
+    }
+
+    pub(crate) fn count_nodes(nodes: &[Node]) -> usize {
+        nodes.iter().map(|n| match n {
+            Node::Loop(body) => 1 + count_nodes(body),
+            _ => 1,
+        }).sum()
+    }
+
+    // One node per command, before any pass has run. Markers are dropped.
+    fn from_instructions(inst: &[Instruction]) -> Vec<Node> {
+        inst.iter().filter_map(|i| match i {
+            Instruction::Increment => Some(Node::Add(0, 1)),
+            Instruction::Decrement => Some(Node::Add(0, 255)),
+            Instruction::ShiftLeft => Some(Node::Move(-1)),
+            Instruction::ShiftRight => Some(Node::Move(1)),
+            Instruction::PrintChar => Some(Node::Print(0)),
+            Instruction::GetChar => Some(Node::Read(0)),
+            Instruction::SwitchTape => Some(Node::SwitchTape),
+            Instruction::Nop{ .. } => None,
+            Instruction::Loop(body) => Some(Node::Loop(from_instructions(body))),
+        }).collect()
+    }
+
+    pub fn apply(pass: Pass, nodes: Vec<Node>) -> Vec<Node> {
+        match pass {
+            Pass::FuseAdd => fuse_add(nodes),
+            Pass::DeadLoop => dead_loop(nodes),
+            Pass::OnceLoop => once_loop(nodes),
+            Pass::CopyLoop => copy_loop(nodes),
+            _ => nodes.into_iter().map(|n| match n {
+                Node::Loop(body) => rewrite_loop(pass, apply(pass, body)),
+                n => n,
+            }).collect(),
+        }
+    }
+
+    fn rewrite_loop(pass: Pass, body: Vec<Node>) -> Node {
+        match (pass, body.as_slice()) {
+            (Pass::ClearLoop, [Node::Add(0, 1)] | [Node::Add(0, 255)]) => Node::SetZero(0),
+            (Pass::ScanLoop, [Node::Move(step)]) => Node::Scan(*step),
+            (Pass::MoveLoop, _) => match move_target(&body) {
+                Some(delta) => Node::MoveValue(0, delta),
+                None => Node::Loop(body),
+            },
+            _ => Node::Loop(body),
+        }
+    }
+
+    // For a body made only of `Add`s and `Move`s that returns to where it
+    // started, subtracts one from the counter and adds one to exactly one
+    // other cell, the offset of that cell.
+    fn move_target(body: &[Node]) -> Option<isize> {
+        match move_targets(body)?.as_slice() {
+            [delta] => Some(*delta),
+            _ => None,
+        }
+    }
+
+    // Like `move_target`, but for any number of other cells, each getting
+    // one added.
+    fn move_targets(body: &[Node]) -> Option<Vec<isize>> {
+        let mut deltas = BTreeMap::<isize, u8>::new();
+        let mut ptr : isize = 0;
+        for n in body.iter() {
+            match n {
+                Node::Add(offset, amount) => {
+                    let v = deltas.entry(ptr + offset).or_insert(0);
+                    *v = v.wrapping_add(*amount);
+                }
+                Node::Move(offset) => ptr += offset,
+                _ => return None,
+            }
+        }
+        deltas.retain(|_, v| *v != 0);
+        if ptr != 0 || deltas.remove(&0) != Some(255) || deltas.values().any(|v| *v != 1) {
+            return None;
+        }
+        Some(deltas.into_keys().collect())
+    }
+
+    fn copy_loop(nodes: Vec<Node>) -> Vec<Node> {
+        let mut out = Vec::<Node>::new();
+        let mut i = 0;
+        while i < nodes.len() {
+            if let Some((delta, temp, len)) = copy_at(&nodes[i..]) {
+                out.push(Node::CopyValue(0, delta, temp));
+                out.push(Node::Move(temp));
+                i += len;
+                continue;
+            }
+            out.push(match &nodes[i] {
+                Node::Loop(body) => Node::Loop(copy_loop(body.clone())),
+                n => n.clone(),
+            });
+            i += 1;
+        }
+        out
+    }
+
+    // Whether `nodes` start with a loop moving the counter into two cells,
+    // then moves to one of them and moves it back into the counter. If so,
+    // the offsets of the other cell and of the temporary, and how many
+    // nodes that took.
+    fn copy_at(nodes: &[Node]) -> Option<(isize, isize, usize)> {
+        let targets = match nodes.first()? {
+            Node::Loop(body) => move_targets(body)?,
+            _ => return None,
+        };
+        let mut temp = 0;
+        let mut len = 1;
+        while let Some(Node::Move(step)) = nodes.get(len) {
+            temp += step;
+            len += 1;
+        }
+        match (nodes.get(len)?, targets.as_slice()) {
+            (Node::MoveValue(0, back), [a, b]) if *back == -temp && (*a == temp || *b == temp) => {
+                let delta = if *a == temp { *b } else { *a };
+                Some((delta, temp, len + 1))
+            }
+            _ => None,
+        }
+    }
+
+    // The cell under the pointer is known to be zero right after a loop or
+    // scan exits, or once it has been cleared or moved out, until something
+    // moves the pointer or may write the cell. Output changes nothing.
+    fn dead_loop(nodes: Vec<Node>) -> Vec<Node> {
+        let mut out = Vec::<Node>::new();
+        let mut known_zero = false;
+        for n in nodes.into_iter() {
+            let was_zero = known_zero;
+            known_zero = match n {
+                Node::Loop(_) | Node::Scan(_) | Node::SetZero(0) | Node::MoveValue(0, _) => true,
+                Node::CopyValue(offset, _, temp) => offset + temp == 0,
+                Node::Print(_) => known_zero,
+                _ => false,
+            };
+            match n {
+                Node::Loop(_) if was_zero => (),
+                Node::Loop(body) => out.push(Node::Loop(dead_loop(body))),
+                n => out.push(n),
+            }
+        }
+        out
+    }
+
+    // Tracks the value of the cell under the pointer where it is known: zero
+    // after a loop, scan, clear or move out, then changed by `Add`s to it.
+    // A loop entered with the value one whose body runs exactly once is
+    // replaced by its body.
+    fn once_loop(nodes: Vec<Node>) -> Vec<Node> {
+        let mut out = Vec::<Node>::new();
+        let mut known : Option<u8> = None;
+        for n in nodes.into_iter() {
+            let was = known;
+            known = match n {
+                Node::Loop(_) | Node::Scan(_) | Node::SetZero(0) | Node::MoveValue(0, _) => Some(0),
+                Node::Add(0, amount) => known.map(|v| v.wrapping_add(amount)),
+                Node::Move(_) | Node::Read(0) | Node::SwitchTape => None,
+                Node::MoveValue(offset, delta) if offset + delta == 0 => None,
+                Node::CopyValue(offset, _, temp) if offset + temp == 0 => Some(0),
+                Node::CopyValue(offset, delta, _) if offset == 0 || offset + delta == 0 => None,
+                _ => known,
+            };
+            match n {
+                Node::Loop(body) => {
+                    let body = once_loop(body);
+                    if was == Some(1) && runs_once(&body) {
+                        out.extend(body);
+                    } else {
+                        out.push(Node::Loop(body));
+                    }
+                }
+                n => out.push(n),
+            }
+        }
+        out
+    }
+
+    // Whether a body entered with the counter at one certainly leaves the
+    // pointer where it started and the counter at zero: it must not read
+    // into or scan over the counter, and inner loops must return to where
+    // they started without touching it.
+    fn runs_once(body: &[Node]) -> bool {
+        let mut ptr : isize = 0;
+        let mut counter : u8 = 1;
+        for n in body.iter() {
+            match n {
+                Node::Add(offset, amount) if ptr + offset == 0 => counter = counter.wrapping_add(*amount),
+                Node::SetZero(offset) | Node::MoveValue(offset, _) if ptr + offset == 0 => counter = 0,
+                Node::Read(offset) if ptr + offset == 0 => return false,
+                Node::MoveValue(offset, delta) if ptr + offset + delta == 0 => return false,
+                Node::CopyValue(offset, _, temp) if ptr + offset + temp == 0 => counter = 0,
+                Node::CopyValue(offset, delta, _) if ptr + offset == 0 || ptr + offset + delta == 0 => return false,
+                Node::Move(offset) => ptr += offset,
+                Node::Scan(_) | Node::SwitchTape => return false,
+                Node::Loop(inner) if ptr == 0 || !avoids(inner, -ptr) => return false,
+                _ => (),
+            }
+        }
+        ptr == 0 && counter == 0
+    }
+
+    // Whether `nodes` return the pointer to where they started and never
+    // write the cell `at` away from it.
+    fn avoids(nodes: &[Node], at: isize) -> bool {
+        let mut ptr : isize = 0;
+        for n in nodes.iter() {
+            match n {
+                Node::Add(offset, _) | Node::Read(offset) | Node::SetZero(offset) if ptr + offset == at => return false,
+                Node::MoveValue(offset, delta) if ptr + offset == at || ptr + offset + delta == at => return false,
+                Node::CopyValue(offset, delta, temp) if [0, *delta, *temp].contains(&(at - ptr - offset)) => return false,
+                Node::Move(offset) => ptr += offset,
+                Node::Scan(_) | Node::SwitchTape => return false,
+                Node::Loop(inner) if ptr == at || !avoids(inner, at - ptr) => return false,
+                _ => (),
+            }
+        }
+        ptr == 0
+    }
+
+    // Whether `body`, entered with its counter at one, runs exactly once.
+    pub(crate) fn loop_runs_once(body: &[Instruction]) -> bool {
+        runs_once(&apply(Pass::ClearLoop, from_instructions(body)))
+    }
+
+    fn flush(out: &mut Vec<Node>, pending: &mut BTreeMap<isize, u8>, offset: isize) {
+        if let Some(amount) = pending.remove(&offset) {
+            if amount != 0 {
+                out.push(Node::Add(offset, amount));
+            }
+        }
+    }
+
+    fn flush_all(out: &mut Vec<Node>, pending: &mut BTreeMap<isize, u8>, ptr: isize) {
+        for (offset, amount) in std::mem::take(pending).into_iter() {
+            if amount != 0 {
+                out.push(Node::Add(offset, amount));
+            }
+        }
+        if ptr != 0 {
+            out.push(Node::Move(ptr));
+        }
+    }
+
+    // Collapses each maximal loop-free run into one `Add` per touched cell
+    // and a single trailing `Move`. Pending additions to a cell are flushed
+    // before anything else reads or replaces it, so I/O sees the same values
+    // in the same order as the unoptimized program.
+    fn fuse_add(nodes: Vec<Node>) -> Vec<Node> {
+        let mut out = Vec::<Node>::new();
+        let mut pending = BTreeMap::<isize, u8>::new();
+        let mut ptr : isize = 0;
+        for n in nodes.into_iter() {
+            match n {
+                Node::Add(offset, amount) => {
+                    let v = pending.entry(ptr + offset).or_insert(0);
+                    *v = v.wrapping_add(amount);
+                }
+                Node::Move(offset) => ptr += offset,
+                Node::Print(offset) => {
+                    flush(&mut out, &mut pending, ptr + offset);
+                    out.push(Node::Print(ptr + offset));
+                }
+                Node::Read(offset) => {
+                    flush(&mut out, &mut pending, ptr + offset);
+                    out.push(Node::Read(ptr + offset));
+                }
+                Node::SetZero(offset) => {
+                    pending.remove(&(ptr + offset));
+                    out.push(Node::SetZero(ptr + offset));
+                }
+                Node::MoveValue(offset, delta) => {
+                    flush(&mut out, &mut pending, ptr + offset);
+                    flush(&mut out, &mut pending, ptr + offset + delta);
+                    out.push(Node::MoveValue(ptr + offset, delta));
+                }
+                Node::CopyValue(offset, delta, temp) => {
+                    flush(&mut out, &mut pending, ptr + offset);
+                    flush(&mut out, &mut pending, ptr + offset + delta);
+                    flush(&mut out, &mut pending, ptr + offset + temp);
+                    out.push(Node::CopyValue(ptr + offset, delta, temp));
+                }
+                Node::Scan(step) => {
+                    flush_all(&mut out, &mut pending, ptr);
+                    ptr = 0;
+                    out.push(Node::Scan(step));
+                }
+                Node::SwitchTape => {
+                    flush_all(&mut out, &mut pending, ptr);
+                    ptr = 0;
+                    out.push(Node::SwitchTape);
+                }
+                Node::Loop(body) => {
+                    flush_all(&mut out, &mut pending, ptr);
+                    ptr = 0;
+                    out.push(Node::Loop(fuse_add(body)));
+                }
+            }
+        }
+        flush_all(&mut out, &mut pending, ptr);
+        out
+    }
+
+    fn move_to(out: &mut String, cur: &mut isize, target: isize) {
+        let c = if target < *cur { '<' } else { '>' };
+        out.extend(std::iter::repeat_n(c, (target - *cur).unsigned_abs()));
+        *cur = target;
+    }
+
+    // Lowers optimized nodes back to brainfuck. `SetZero` becomes `[-]` and
+    // each `Add` is written with whichever of `+` or `-` is shorter.
+    pub(crate) fn to_source(nodes: &[Node]) -> String {
+        let mut out = String::new();
+        lower(nodes, &mut out);
+        out
+    }
+
+    fn lower(nodes: &[Node], out: &mut String) {
+        let mut cur : isize = 0;
+        for n in nodes.iter() {
+            match n {
+                Node::Add(offset, amount) => {
+                    move_to(out, &mut cur, *offset);
+                    if *amount <= 128 {
+                        out.extend(std::iter::repeat_n('+', *amount as usize));
+                    } else {
+                        out.extend(std::iter::repeat_n('-', 256 - *amount as usize));
+                    }
+                }
+                Node::Move(offset) => {
+                    move_to(out, &mut cur, *offset);
+                    cur = 0;
+                }
+                Node::Print(offset) => {
+                    move_to(out, &mut cur, *offset);
+                    out.push('.');
+                }
+                Node::Read(offset) => {
+                    move_to(out, &mut cur, *offset);
+                    out.push(',');
+                }
+                Node::SetZero(offset) => {
+                    move_to(out, &mut cur, *offset);
+                    out.push_str("[-]");
+                }
+                Node::MoveValue(offset, delta) => {
+                    move_to(out, &mut cur, *offset);
+                    out.push_str("[-");
+                    move_to(out, &mut cur, *offset + *delta);
+                    out.push('+');
+                    move_to(out, &mut cur, *offset);
+                    out.push(']');
+                }
+                Node::CopyValue(offset, delta, temp) => {
+                    move_to(out, &mut cur, *offset);
+                    out.push_str("[-");
+                    move_to(out, &mut cur, *offset + *delta);
+                    out.push('+');
+                    move_to(out, &mut cur, *offset + *temp);
+                    out.push('+');
+                    move_to(out, &mut cur, *offset);
+                    out.push(']');
+                    move_to(out, &mut cur, *offset + *temp);
+                    out.push_str("[-");
+                    move_to(out, &mut cur, *offset);
+                    out.push('+');
+                    move_to(out, &mut cur, *offset + *temp);
+                    out.push(']');
+                }
+                Node::Scan(step) => {
+                    move_to(out, &mut cur, 0);
+                    out.push('[');
+                    move_to(out, &mut cur, *step);
+                    out.push(']');
+                    cur = 0;
+                }
+                Node::SwitchTape => {
+                    move_to(out, &mut cur, 0);
+                    out.push('%');
+                }
+                Node::Loop(body) => {
+                    move_to(out, &mut cur, 0);
+                    out.push('[');
+                    lower(body, out);
+                    out.push(']');
+                }
+            }
+        }
+        move_to(out, &mut cur, 0);
+    }
+
+    // The whole effect of a program without loops or I/O: what it adds to
+    // each cell, keyed by the cell's offset from the starting pointer, and
+    // where it leaves the pointer.
+    #[derive(Clone, PartialEq, Debug)]
+    pub struct Summary {
+        pub deltas: BTreeMap<isize, u8>,
+        pub pointer: isize,
+    }
+
+    // None if anything besides `Add` and `Move` is left.
+    pub fn summarize(nodes: &[Node]) -> Option<Summary> {
+        let mut deltas = BTreeMap::<isize, u8>::new();
+        let mut pointer = 0;
+        for n in nodes.iter() {
+            match n {
+                Node::Add(offset, v) => {
+                    let cell = deltas.entry(pointer + offset).or_insert(0);
+                    *cell = cell.wrapping_add(*v);
+                }
+                Node::Move(step) => pointer += step,
+                _ => return None,
+            }
+        }
+        deltas.retain(|_, v| *v != 0);
+        Some(Summary{ deltas, pointer })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use crate::interpreter;
+        use super::Pass;
+
+        fn output(source: &str, level: u8) -> Vec<u8> {
+            let mut interpreter = interpreter::new(String::from(source))
+                .with_opt_level(level)
+                .with_captured_output()
+                .build()
+                .unwrap();
+            interpreter.run().unwrap();
+            interpreter.take_output()
+        }
+
+        #[test]
+        fn unmatched_loops_run_as_plain_loops() {
+            // A multiply loop the passes rewrite, then a loop that prints
+            // inside its body and has to stay a loop.
+            let source = "+++++[>+++++++++++++<-]+++[>.+<-]";
+            assert_eq!(output(source, 3), output(source, 0));
+            assert_eq!(output(source, 3), b"ABC");
+        }
+
+        #[test]
+        fn move_loops_match_the_plain_loop() {
+            for (value, target) in [(0, 0), (1, 0), (7, 3), (200, 100), (255, 255)] {
+                let plus = |n: usize| "+".repeat(n);
+                let right = format!("{}>{}<[->+<]>.<.", plus(value), plus(target));
+                let left = format!(">{}<{}>[-<+>]<.>.", plus(value), plus(target));
+                for source in [right, left] {
+                    assert_eq!(output(&source, 2), output(&source, 0), "{}", source);
+                }
+            }
+            assert_eq!(output(&format!("{}>+++<[->+<]>.", "+".repeat(255)), 2), [2]);
+        }
+
+        #[test]
+        fn custom_pipeline_keeps_the_output() {
+            let source = "++[->+<]>[-]<<[<]+++[>+++<-]>.";
+            let passes : Vec<Pass> = "fuse-add,clear-loop,scan-loop".split(',').map(|p| p.parse().unwrap()).collect();
+            let mut interpreter = interpreter::new(String::from(source))
+                .with_passes(passes.clone())
+                .with_captured_output()
+                .build()
+                .unwrap();
+            interpreter.run().unwrap();
+            assert_eq!(interpreter.take_output(), output(source, 0));
+            let lowered = interpreter::new(String::from(source)).with_passes(passes).optimized_source().unwrap();
+            assert_eq!(output(&lowered, 0), output(source, 0));
+            assert!("fuse-add,bogus".split(',').map(|p| p.parse::<Pass>()).any(|p| p.is_err()));
+        }
+
+        #[test]
+        fn run_once_wrappers_are_inlined() {
+            let source = "[-]+[>+++++.<-]>.";
+            let mut interpreter = interpreter::new(String::from(source)).with_opt_level(3).build().unwrap();
+            let lowered = interpreter.optimized_source().unwrap();
+            assert_eq!(lowered.matches('[').count(), 1, "{}", lowered);
+            assert_eq!(output(source, 3), output(source, 0));
+            // Entered with 2, the loop runs twice and must stay.
+            let source = "[-]++[>+++++.<-]>.";
+            let mut interpreter = interpreter::new(String::from(source)).with_opt_level(3).build().unwrap();
+            assert_eq!(interpreter.optimized_source().unwrap().matches('[').count(), 2);
+            assert_eq!(output(source, 3), output(source, 0));
+        }
+
+        #[test]
+        fn opt_report_lists_the_clear_loop_pass() {
+            let mut interpreter = interpreter::new(String::from("+[-]>+[-]>+[-]>+[+]")).with_opt_level(2).build().unwrap();
+            let report = interpreter.opt_report().unwrap();
+            let clear = report.iter().find(|r| r.pass == Pass::ClearLoop).unwrap();
+            assert_eq!(clear.before - clear.after, 4);
+            assert_eq!(report[0].pass, Pass::ClearLoop);
+        }
+
+        #[test]
+        fn copy_loops_match_the_plain_loops() {
+            for value in [0, 1, 2, 100, 255] {
+                let source = format!("{}[->+>+<<]>>[-<<+>>]<<.>.>.", "+".repeat(value));
+                assert_eq!(output(&source, 2), output(&source, 0), "{}", value);
+                assert_eq!(output(&source, 2), [value as u8, value as u8, 0]);
+            }
+            let source = "+++[->+>+<<]>>[-<<+>>]";
+            let mut plain = interpreter::new(String::from(source)).with_opt_level(0).build().unwrap();
+            let mut copied = interpreter::new(String::from(source)).with_opt_level(2).build().unwrap();
+            plain.run().unwrap();
+            copied.run().unwrap();
+            assert_eq!(plain.memory(), copied.memory());
+            assert!(copied.steps() < plain.steps());
+        }
+    }
+}
+
+pub mod codegen {
+    use crate::interpreter::Instruction;
+    use crate::optimizer::Node;
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Lang {
+        C,
+        Rust,
+    }
+
+    fn statement(lang: Lang, inst: &Instruction) -> &'static str {
+        match (lang, inst) {
+            (Lang::C, Instruction::Increment) => "++*p;",
+            (Lang::C, Instruction::Decrement) => "--*p;",
+            (Lang::C, Instruction::ShiftLeft) => "--p;",
+            (Lang::C, Instruction::ShiftRight) => "++p;",
+            (Lang::C, Instruction::PrintChar) => "putchar(*p);",
+            (Lang::C, Instruction::GetChar) => "{ int c = getchar(); *p = c == EOF ? 0 : c; }",
+            (Lang::C, Instruction::SwitchTape) => "{ unsigned char *t = p; p = q; q = t; }",
+            (Lang::C, Instruction::Loop(_)) => "while (*p) {",
+            (_, Instruction::Nop{ .. }) => "",
+            (Lang::Rust, Instruction::Increment) => "tape[p] = tape[p].wrapping_add(1);",
+            (Lang::Rust, Instruction::Decrement) => "tape[p] = tape[p].wrapping_sub(1);",
+            (Lang::Rust, Instruction::ShiftLeft) => "p -= 1;",
+            (Lang::Rust, Instruction::ShiftRight) => "p += 1;",
+            (Lang::Rust, Instruction::PrintChar) => "output.write_all(&tape[p..p + 1])?;",
+            (Lang::Rust, Instruction::GetChar) =>
+                "let mut b = [0u8]; tape[p] = if input.read(&mut b)? == 0 { 0 } else { b[0] };",
+            (Lang::Rust, Instruction::SwitchTape) => "std::mem::swap(&mut tape, &mut other); std::mem::swap(&mut p, &mut q);",
+            (Lang::Rust, Instruction::Loop(_)) => "while tape[p] != 0 {",
+        }
+    }
+
+    // `positions` holds the (line, column) of each lexed command, both
+    // 1-based; when given, every statement is preceded by its position.
+    struct Emitter<'a> {
+        lang: Lang,
+        positions: Option<&'a [(usize, usize)]>,
+        index: usize,
+        out: String,
+    }
+
+    impl Emitter<'_> {
+        fn line(&mut self, depth: usize, text: &str) {
+            for _ in 0..depth {
+                self.out.push_str("    ");
+            }
+            self.out.push_str(text);
+            self.out.push('\n');
+        }
+        fn emit(&mut self, inst: &[Instruction], depth: usize) {
+            for i in inst.iter() {
+                if let Instruction::Nop{ src_offset } = i {
+                    self.line(depth, &format!("/* mark {} */", src_offset));
+                    continue;
+                }
+                if let Some(positions) = self.positions {
+                    let (line, column) = positions[self.index];
+                    self.line(depth, &format!("/* src {}:{} */", line, column));
+                }
+                self.index += 1;
+                self.line(depth, statement(self.lang, i));
+                if let Instruction::Loop(body) = i {
+                    self.emit(body, depth + 1);
+                    self.index += 1;
+                    self.line(depth, "}");
+                }
+            }
+        }
+    }
+
+    fn body(lang: Lang, inst: &[Instruction], positions: Option<&[(usize, usize)]>) -> String {
+        let mut e = Emitter{ lang, positions, index: 0, out: String::new() };
+        e.emit(inst, 1);
+        e.out
+    }
+
+    // A complete C program. The tape is a fixed 65536 cells and the pointer
+    // starts at its left end.
+    pub(crate) fn to_c(inst: &[Instruction], positions: Option<&[(usize, usize)]>) -> String {
+        let mut out = c_header(switches(inst));
+        out.push_str(&body(Lang::C, inst, positions));
+        out.push_str("    return 0;\n}\n");
+        out
+    }
+
+    // Whether the program uses the second tape of dual-tape mode.
+    fn switches(inst: &[Instruction]) -> bool {
+        inst.iter().any(|i| match i {
+            Instruction::SwitchTape => true,
+            Instruction::Loop(body) => switches(body),
+            _ => false,
+        })
+    }
+
+    fn nodes_switch(nodes: &[Node]) -> bool {
+        nodes.iter().any(|n| match n {
+            Node::SwitchTape => true,
+            Node::Loop(body) => nodes_switch(body),
+            _ => false,
+        })
+    }
+
+    // Everything before the first statement; `q` points into the inactive
+    // tape when there are two.
+    fn c_header(dual: bool) -> String {
+        let mut out = String::from("#include <stdio.h>\n\nstatic unsigned char tape[65536];\n");
+        if dual {
+            out.push_str("static unsigned char tape2[65536];\n");
+        }
+        out.push_str("\nint main(void) {\n    unsigned char *p = tape;\n");
+        if dual {
+            out.push_str("    unsigned char *q = tape2;\n");
+        }
+        out
+    }
+
+    fn c_cell(offset: isize) -> String {
+        if offset == 0 { String::from("*p") } else { format!("p[{}]", offset) }
+    }
+
+    fn c_nodes(nodes: &[Node], depth: usize, out: &mut String) {
+        let indent = "    ".repeat(depth);
+        for n in nodes.iter() {
+            let text = match n {
+                Node::Add(offset, amount) if *amount <= 128 => format!("{} += {};", c_cell(*offset), amount),
+                Node::Add(offset, amount) => format!("{} -= {};", c_cell(*offset), 256 - *amount as usize),
+                Node::Move(offset) if *offset < 0 => format!("p -= {};", -offset),
+                Node::Move(offset) => format!("p += {};", offset),
+                Node::Print(offset) => format!("putchar({});", c_cell(*offset)),
+                Node::Read(offset) => format!("{{ int c = getchar(); {} = c == EOF ? 0 : c; }}", c_cell(*offset)),
+                Node::SetZero(offset) => format!("{} = 0;", c_cell(*offset)),
+                Node::MoveValue(offset, delta) =>
+                    format!("{} += {}; {} = 0;", c_cell(offset + delta), c_cell(*offset), c_cell(*offset)),
+                Node::CopyValue(offset, delta, temp) => format!("{} += {}; {} += {}; {} = 0;",
+                    c_cell(offset + delta), c_cell(*offset), c_cell(*offset), c_cell(offset + temp), c_cell(offset + temp)),
+                Node::Scan(step) => format!("while (*p) p += {};", step),
+                Node::SwitchTape => String::from("{ unsigned char *t = p; p = q; q = t; }"),
+                Node::Loop(body) => {
+                    out.push_str(&format!("{}while (*p) {{\n", indent));
+                    c_nodes(body, depth + 1, out);
+                    String::from("}")
+                }
+            };
+            out.push_str(&indent);
+            out.push_str(&text);
+            out.push('\n');
+        }
+    }
+
+    // Like `to_c`, but from the optimized form, so fused runs become a
+    // single `+=` and a single pointer adjustment.
+    pub(crate) fn nodes_to_c(nodes: &[Node]) -> String {
+        let mut out = c_header(nodes_switch(nodes));
+        c_nodes(nodes, 1, &mut out);
+        out.push_str("    return 0;\n}\n");
+        out
+    }
+
+    fn sexpr(head: &str, inst: &[Instruction], out: &mut String) {
+        out.push('(');
+        out.push_str(head);
+        for i in inst.iter() {
+            out.push(' ');
+            match i {
+                Instruction::Increment => out.push('+'),
+                Instruction::Decrement => out.push('-'),
+                Instruction::ShiftLeft => out.push('<'),
+                Instruction::ShiftRight => out.push('>'),
+                Instruction::PrintChar => out.push('.'),
+                Instruction::GetChar => out.push(','),
+                Instruction::SwitchTape => out.push('%'),
+                Instruction::Nop{ src_offset } => out.push_str(&format!("@{}", src_offset)),
+                Instruction::Loop(body) => sexpr("loop", body, out),
+            }
+        }
+        out.push(')');
+    }
+
+    fn annotate(inst: &[Instruction], depth: usize, count: &mut usize, out: &mut String) {
+        for i in inst.iter() {
+            match i {
+                Instruction::Increment => out.push('+'),
+                Instruction::Decrement => out.push('-'),
+                Instruction::ShiftLeft => out.push('<'),
+                Instruction::ShiftRight => out.push('>'),
+                Instruction::PrintChar => out.push('.'),
+                Instruction::GetChar => out.push(','),
+                Instruction::SwitchTape => out.push('%'),
+                Instruction::Nop{ .. } => (),
+                Instruction::Loop(body) => {
+                    *count += 1;
+                    let n = *count;
+                    out.push('[');
+                    annotate(body, depth + 1, count, out);
+                    out.push_str(&format!("](loop#{} depth{})\n", n, depth));
+                }
+            }
+        }
+    }
+
+    // The program as brainfuck with a comment such as `(loop#3 depth2)`
+    // and a line break after each `]`, numbering loops from 1 in the order
+    // they open. Comments hold no commands, so the program runs the same
+    // (though `--strict` would reject it).
+    pub(crate) fn to_annotated(inst: &[Instruction]) -> String {
+        let mut out = String::new();
+        annotate(inst, 1, &mut 0, &mut out);
+        if !out.ends_with('\n') {
+            out.push('\n');
+        }
+        out
+    }
+
+    // The instruction tree as an S-expression, such as
+    // `(seq + + (loop - >))` for `++[->]`.
+    pub(crate) fn to_sexpr(inst: &[Instruction]) -> String {
+        let mut out = String::new();
+        sexpr("seq", inst, &mut out);
+        out
+    }
+
+    // A complete Rust program that `rustc` builds as-is: `run` executes
+    // the program on `tape` from cell 0, and `main` runs it on a fixed
+    // 65536-cell tape over stdin and stdout.
+    pub(crate) fn to_rust(inst: &[Instruction], positions: Option<&[(usize, usize)]>) -> String {
+        let mut out = String::from("use std::io::{Read, Write};\n\n#[allow(unused_mut, unused_variables)]\nfn run(tape: &mut [u8], input: &mut dyn Read, output: &mut dyn Write) -> std::io::Result<()> {\n    let mut p = 0;\n");
+        if switches(inst) {
+            out.push_str("    let mut tape2 = vec![0u8; tape.len()];\n    let mut tape : &mut [u8] = tape;\n    let mut other : &mut [u8] = &mut tape2;\n    let mut q = 0;\n");
+        }
+        out.push_str(&body(Lang::Rust, inst, positions));
+        out.push_str("    output.flush()\n}\n\n");
+        out.push_str("fn main() -> std::io::Result<()> {\n    let mut tape = vec![0u8; 65536];\n    run(&mut tape, &mut std::io::stdin().lock(), &mut std::io::BufWriter::new(std::io::stdout().lock()))\n}\n");
+        out
+    }
+}
+
+pub mod lint {
+    use crate::interpreter::Instruction;
+    use crate::optimizer;
+    use std::collections::BTreeMap;
+
+    #[derive(Clone, Debug)]
+    pub struct Warning {
+        pub offset: usize,
+        pub message: String,
+    }
+
+    // Source offsets of the first and last command of a stretch of code.
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub struct Span {
+        pub start: usize,
+        pub end: usize,
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Effect {
+        Delta(u8),
+        Set(u8),
+    }
+
+    // `offsets` maps each lexed command to its source offset; the tree is
+    // walked in lexing order to recover the offset of every loop. `start`
+    // is the value of the first cell, if known.
+    pub(crate) fn check(inst: &[Instruction], offsets: &[usize], start: Option<u8>) -> Vec<Warning> {
+        let mut warnings = Vec::<Warning>::new();
+        let mut index = 0;
+        walk(inst, offsets, &mut index, start, &mut warnings);
+        warnings
+    }
+
+    fn walk(inst: &[Instruction], offsets: &[usize], index: &mut usize, start: Option<u8>, warnings: &mut Vec<Warning>) {
+        // The cell under the pointer is known to be zero right after a loop
+        // exits, and then known while only `+` and `-` change it, until
+        // something moves the pointer or reads into the cell.
+        let mut known = start;
+        let mut after_loop = false;
+        for i in inst.iter() {
+            match i {
+                Instruction::Loop(body) => {
+                    let offset = offsets[*index];
+                    if known == Some(0) {
+                        let message = if after_loop {
+                            "loop never runs: the cell is already zero after the preceding loop"
+                        } else {
+                            "loop never runs: the cell is always zero when it is reached"
+                        };
+                        warnings.push(Warning {
+                            offset,
+                            message: String::from(message),
+                        });
+                    } else if known == Some(1) && optimizer::loop_runs_once(body) {
+                        warnings.push(Warning {
+                            offset,
+                            message: String::from("loop runs exactly once: it is entered with the cell at 1 and always clears it"),
+                        });
+                    }
+                    *index += 1;
+                    check_invariant(body, offset, warnings);
+                    walk(body, offsets, index, None, warnings);
+                    *index += 1;
+                    known = Some(0);
+                    after_loop = true;
+                }
+                Instruction::PrintChar => *index += 1,
+                Instruction::Nop{ .. } => (),
+                Instruction::Increment | Instruction::Decrement => {
+                    *index += 1;
+                    let step = if matches!(i, Instruction::Increment) { 1 } else { 255 };
+                    known = known.map(|v| v.wrapping_add(step));
+                    after_loop = false;
+                }
+                _ => {
+                    *index += 1;
+                    known = None;
+                }
+            }
+        }
+    }
+
+    // Number of lexed commands in `inst`, brackets included.
+    fn op_count(inst: &[Instruction]) -> usize {
+        inst.iter().map(|i| match i {
+            Instruction::Loop(body) => 2 + op_count(body),
+            Instruction::Nop{ .. } => 0,
+            _ => 1,
+        }).sum()
+    }
+
+    // A loop-free, input-free body that leaves the pointer and the
+    // controlling cell where they were. If `bounded`, it must not move the
+    // pointer at all, since a tape limit could end the run instead.
+    fn never_exits(body: &[Instruction], bounded: bool) -> bool {
+        let mut ptr : isize = 0;
+        let mut delta : u8 = 0;
+        for i in body.iter() {
+            match i {
+                Instruction::Increment if ptr == 0 => delta = delta.wrapping_add(1),
+                Instruction::Decrement if ptr == 0 => delta = delta.wrapping_sub(1),
+                Instruction::Increment | Instruction::Decrement | Instruction::PrintChar => (),
+                Instruction::Nop{ .. } => (),
+                Instruction::ShiftLeft | Instruction::ShiftRight if bounded => return false,
+                Instruction::ShiftLeft => ptr -= 1,
+                Instruction::ShiftRight => ptr += 1,
+                Instruction::GetChar | Instruction::SwitchTape | Instruction::Loop(_) => return false,
+            }
+        }
+        ptr == 0 && delta == 0
+    }
+
+    // Source offset of the first top-level loop that is certainly entered
+    // and can never exit. Straight-line code is simulated exactly from the
+    // initial `tape` and `pointer`, other cells holding `fill`; after any
+    // other loop only the cell it leaves zero is known.
+    pub(crate) fn endless_loop(
+        inst: &[Instruction],
+        offsets: &[usize],
+        tape: &[u8],
+        pointer: usize,
+        fill: u8,
+        bounded: bool) -> Option<usize> {
+        // Cells absent from `known` hold `default`; `None` means unknown.
+        let mut known = BTreeMap::<isize, Option<u8>>::new();
+        for (i, v) in tape.iter().enumerate() {
+            known.insert(i as isize - pointer as isize, Some(*v));
+        }
+        let mut default = Some(fill);
+        let mut ptr : isize = 0;
+        let mut index = 0;
+        for i in inst.iter() {
+            let value = known.get(&ptr).copied().unwrap_or(default);
+            match i {
+                Instruction::Increment => {
+                    known.insert(ptr, value.map(|v| v.wrapping_add(1)));
+                }
+                Instruction::Decrement => {
+                    known.insert(ptr, value.map(|v| v.wrapping_sub(1)));
+                }
+                Instruction::ShiftLeft => ptr -= 1,
+                Instruction::ShiftRight => ptr += 1,
+                Instruction::PrintChar | Instruction::Nop{ .. } => (),
+                Instruction::GetChar => {
+                    known.insert(ptr, None);
+                }
+                Instruction::SwitchTape => {
+                    known.clear();
+                    default = None;
+                }
+                Instruction::Loop(body) => match value {
+                    Some(0) => (),
+                    Some(_) if never_exits(body, bounded) => return Some(offsets[index]),
+                    _ => {
+                        known.clear();
+                        known.insert(0, Some(0));
+                        default = None;
+                        ptr = 0;
+                    }
+                },
+            }
+            index += op_count(std::slice::from_ref(i));
+        }
+        None
+    }
+
+    // Code that can never run: the bodies of loops entered with the cell
+    // known to be zero, and everything after a top-level loop that
+    // `endless_loop` finds can never exit. In source order.
+    pub(crate) fn unreachable(
+        inst: &[Instruction],
+        offsets: &[usize],
+        tape: &[u8],
+        pointer: usize,
+        fill: u8,
+        bounded: bool) -> Vec<Span> {
+        let mut spans = Vec::<Span>::new();
+        let mut index = 0;
+        dead_bodies(inst, offsets, &mut index, Some(tape.get(pointer).copied().unwrap_or(fill)), &mut spans);
+        let start = match endless_loop(inst, offsets, tape, pointer, fill, bounded) {
+            Some(start) => start,
+            None => return spans,
+        };
+        let mut index = 0;
+        for i in inst.iter() {
+            let found = matches!(i, Instruction::Loop(_)) && offsets[index] == start;
+            index += op_count(std::slice::from_ref(i));
+            if found {
+                break;
+            }
+        }
+        if index < offsets.len() {
+            spans.retain(|s| s.start < offsets[index]);
+            spans.push(Span{ start: offsets[index], end: offsets[offsets.len() - 1] });
+        }
+        spans
+    }
+
+    // The same knowledge of the cell under the pointer as `walk`, starting
+    // from `known`.
+    fn dead_bodies(inst: &[Instruction], offsets: &[usize], index: &mut usize, mut known: Option<u8>, spans: &mut Vec<Span>) {
+        for i in inst.iter() {
+            match i {
+                Instruction::Loop(body) => {
+                    let len = op_count(body);
+                    if known == Some(0) {
+                        if len > 0 {
+                            spans.push(Span{ start: offsets[*index + 1], end: offsets[*index + len] });
+                        }
+                        *index += len + 2;
+                    } else {
+                        *index += 1;
+                        dead_bodies(body, offsets, index, None, spans);
+                        *index += 1;
+                    }
+                    known = Some(0);
+                }
+                Instruction::PrintChar => *index += 1,
+                Instruction::Nop{ .. } => (),
+                Instruction::Increment | Instruction::Decrement => {
+                    *index += 1;
+                    let step = if matches!(i, Instruction::Increment) { 1 } else { 255 };
+                    known = known.map(|v| v.wrapping_add(step));
+                }
+                _ => {
+                    *index += 1;
+                    known = None;
+                }
+            }
+        }
+    }
+
+    // Flags counted loops that assign the same constant to another cell on
+    // every iteration. Only fires for balanced, input-free bodies whose
+    // counter steps by one and whose only inner loops are clears.
+    fn check_invariant(body: &[Instruction], offset: usize, warnings: &mut Vec<Warning>) {
+        let mut effects = BTreeMap::<isize, Effect>::new();
+        let mut ptr : isize = 0;
+        for i in body.iter() {
+            match i {
+                Instruction::Increment | Instruction::Decrement => {
+                    let step = if matches!(i, Instruction::Increment) { 1 } else { 255 };
+                    let e = effects.entry(ptr).or_insert(Effect::Delta(0));
+                    *e = match *e {
+                        Effect::Delta(d) => Effect::Delta(d.wrapping_add(step)),
+                        Effect::Set(v) => Effect::Set(v.wrapping_add(step)),
+                    };
+                }
+                Instruction::ShiftLeft => ptr -= 1,
+                Instruction::ShiftRight => ptr += 1,
+                Instruction::PrintChar | Instruction::Nop{ .. } => (),
+                Instruction::Loop(_) if i.is_clear_loop() => {
+                    effects.insert(ptr, Effect::Set(0));
+                }
+                Instruction::GetChar | Instruction::SwitchTape | Instruction::Loop(_) => return,
+            }
+        }
+        if ptr != 0 {
+            return;
+        }
+        match effects.get(&0) {
+            Some(Effect::Delta(1)) | Some(Effect::Delta(255)) => (),
+            _ => return,
+        }
+        for (cell, effect) in effects.iter() {
+            if let Effect::Set(v) = effect {
+                warnings.push(Warning {
+                    offset,
+                    message: format!(
+                        "loop sets cell {:+} to {} on every iteration; it could be set once",
+                        cell, v),
+                });
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use crate::interpreter::{self, Error};
+
+        fn terminates(source: &str) -> Result<(), Error> {
+            interpreter::new(String::from(source)).build().unwrap().check_termination()
+        }
+
+        #[test]
+        fn certainly_endless_loops_are_refused() {
+            assert!(matches!(terminates("+[]"), Err(Error::EndlessLoop(1))));
+            assert!(matches!(terminates("+[>+<]"), Err(Error::EndlessLoop(1))));
+            // Counting up wraps around to zero, so this one ends.
+            assert!(terminates("+[+]").is_ok());
+        }
+    }
+}
+
+pub mod generator {
+    fn repeat(code: &mut String, c: char, n: u32) {
+        for _ in 0..n {
+            code.push(c);
+        }
+    }
+
+    // Emits code that changes the current cell by `delta`. Large changes
+    // use a multiply loop with the cell to the right as the counter, which
+    // is zero again when the loop ends.
+    fn adjust(code: &mut String, delta: i32) {
+        let (up, down) = if delta >= 0 { ('+', '-') } else { ('-', '+') };
+        let n = delta.unsigned_abs();
+        let mut best = (n, 0, 0, n);
+        for a in 2..=n {
+            let b = n / a;
+            if b < 2 {
+                break;
+            }
+            for (b, r) in [(b, n - a * b), (b + 1, a * (b + 1) - n)] {
+                let cost = a + b + r + 6;
+                if cost < best.0 {
+                    best = (cost, a, b, r);
+                }
+            }
+        }
+        let (_, a, b, r) = best;
+        if a == 0 {
+            repeat(code, up, n);
+            return;
+        }
+        code.push('>');
+        repeat(code, '+', a);
+        code.push_str("[<");
+        repeat(code, up, b);
+        code.push_str(">-]<");
+        if a * b > n {
+            repeat(code, down, r);
+        } else {
+            repeat(code, up, r);
+        }
+    }
+
+    // Generates a program that prints `text`, using cell 0 for the value
+    // and cell 1 as the loop counter.
+    pub fn generate(text: &[u8]) -> String {
+        let mut code = String::new();
+        let mut current : u8 = 0;
+        for &byte in text.iter() {
+            let delta = byte.wrapping_sub(current) as i8 as i32;
+            adjust(&mut code, delta);
+            code.push('.');
+            current = byte;
+        }
+        code
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::interpreter;
+
+        #[test]
+        fn generated_program_prints_the_text() {
+            for text in [&b"A"[..], b"Hello, World!\n", b"\x00~ z"] {
+                let mut interpreter = interpreter::new(generate(text)).with_captured_output().build().unwrap();
+                interpreter.run().unwrap();
+                assert_eq!(interpreter.take_output(), text);
+            }
+        }
+    }
+}
+
+pub mod debugger {
+    use crate::interpreter::{Interpreter, Snapshot, Status};
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::io::BufRead;
+
+    fn print_location(interpreter: &Interpreter, status: Status) {
+        match (status, interpreter.source_offset()) {
+            (Status::Running | Status::Paused, Some(offset)) => {
+                let c = interpreter.source()[offset] as char;
+                println!("at {}: '{}'", offset, c);
+            }
+            _ => println!("finished"),
+        }
+    }
+
+    // Steps until the program finishes or waits for input, `until` says
+    // to stop, or a breakpoint is reached after at least one step. `until`
+    // is also asked after the last step.
+    fn run_until(
+        interpreter: &mut Interpreter,
+        breakpoints: &BTreeSet<usize>,
+        until: &dyn Fn(&Interpreter) -> bool) -> Result<Status, String> {
+        loop {
+            let status = interpreter.step().map_err(|e| e.to_string())?;
+            if until(interpreter) || status != Status::Running {
+                return Ok(status);
+            }
+            match interpreter.source_offset() {
+                Some(offset) if breakpoints.contains(&offset) => return Ok(status),
+                _ => (),
+            }
+        }
+    }
+
+    // Runs `interpreter` until the command at source `offset` is next, or
+    // to the end if it is never reached.
+    pub fn run_to(interpreter: &mut Interpreter, offset: usize) -> Result<Status, String> {
+        interpreter.prepare().map_err(|e| e.to_string())?;
+        match interpreter.source_offset() {
+            None => Ok(Status::Finished),
+            Some(o) if o == offset => Ok(Status::Running),
+            Some(_) => run_until(interpreter, &BTreeSet::<usize>::new(), &|i| i.source_offset() == Some(offset)),
+        }
+    }
+
+    // Runs `interpreter` until the pointer is on cell `index`, counted as
+    // by `logical_pointer`, or to the end if it never gets there. Returns
+    // whether it got there; the last step may be the one that does.
+    pub fn run_to_pointer(interpreter: &mut Interpreter, index: isize) -> Result<bool, String> {
+        interpreter.prepare().map_err(|e| e.to_string())?;
+        if interpreter.logical_pointer() != index {
+            run_until(interpreter, &BTreeSet::<usize>::new(), &|i| i.logical_pointer() == index)?;
+        }
+        Ok(interpreter.logical_pointer() == index)
+    }
+
+    // Runs `interpreter` until a `.` has printed `byte`, or to the end if
+    // none ever does.
+    pub fn run_to_output(interpreter: &mut Interpreter, byte: u8) -> Result<Status, String> {
+        interpreter.prepare().map_err(|e| e.to_string())?;
+        loop {
+            let prints = match interpreter.source_offset() {
+                Some(offset) => interpreter.source()[offset] == b'.' && interpreter.memory()[interpreter.pointer()] == byte,
+                None => false,
+            };
+            let status = interpreter.step().map_err(|e| e.to_string())?;
+            if status != Status::Running || prints {
+                return Ok(status);
+            }
+        }
+    }
+
+    // The source around `offset` on one line, with the command at `offset`
+    // wrapped in parentheses.
+    fn source_context(source: &[u8], offset: usize) -> String {
+        let flat = |s: &[u8]| String::from_utf8_lossy(s).replace(['\n', '\r', '\t'], " ");
+        let before = flat(&source[offset.saturating_sub(8)..offset]);
+        let after = flat(&source[offset + 1..source.len().min(offset + 9)]);
+        format!("{:>8}({}){:<8}", before, source[offset] as char, after)
+    }
+
+    // Cells around the pointer, with the pointed cell in brackets.
+    fn tape_window(interpreter: &Interpreter) -> String {
+        let memory = interpreter.memory();
+        let pointer = interpreter.pointer();
+        let cells : Vec<String> = (pointer.saturating_sub(3)..memory.len().min(pointer + 4))
+            .map(|i| {
+                let cell = interpreter.display_cell(memory[i]);
+                if i == pointer { format!("[{}]", cell) } else { cell }
+            })
+            .collect();
+        cells.join(" ")
+    }
+
+    // Kinds of command an --explain trace can be limited to.
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum TraceCategory {
+        Arith,
+        Move,
+        Io,
+        Loop,
+    }
+
+    impl TraceCategory {
+        pub const ALL: [TraceCategory; 4] = [TraceCategory::Arith, TraceCategory::Move, TraceCategory::Io, TraceCategory::Loop];
+
+        fn of(command: u8) -> Option<TraceCategory> {
+            match command {
+                b'+' | b'-' => Some(TraceCategory::Arith),
+                b'<' | b'>' => Some(TraceCategory::Move),
+                b'.' | b',' => Some(TraceCategory::Io),
+                b'[' | b']' => Some(TraceCategory::Loop),
+                _ => None,
+            }
+        }
+    }
+
+    impl std::fmt::Display for TraceCategory {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            let name = match self {
+                TraceCategory::Arith => "arith",
+                TraceCategory::Move => "move",
+                TraceCategory::Io => "io",
+                TraceCategory::Loop => "loop",
+            };
+            write!(f, "{}", name)
+        }
+    }
+
+    impl std::str::FromStr for TraceCategory {
+        type Err = String;
+        fn from_str(s: &str) -> Result<TraceCategory, String> {
+            match s {
+                "arith" => Ok(TraceCategory::Arith),
+                "move" => Ok(TraceCategory::Move),
+                "io" => Ok(TraceCategory::Io),
+                "loop" => Ok(TraceCategory::Loop),
+                _ => Err(format!("Unknown trace category: {} (expected arith, move, io or loop)", s)),
+            }
+        }
+    }
+
+    // Runs `interpreter` one step at a time, writing a line per step to
+    // `out` with the command just executed, the pointer and the nearby tape.
+    // With a filter, only commands of the listed categories are written;
+    // steps are still numbered counting every command.
+    pub fn explain(
+        interpreter: &mut Interpreter,
+        out: &mut dyn std::io::Write,
+        filter: Option<&[TraceCategory]>,
+    ) -> Result<(), String> {
+        interpreter.prepare().map_err(|e| e.to_string())?;
+        let mut step = 0;
+        while let Some(offset) = interpreter.source_offset() {
+            if interpreter.step().map_err(|e| e.to_string())? == Status::NeedInput {
+                break;
+            }
+            step += 1;
+            if let Some(filter) = filter {
+                match TraceCategory::of(interpreter.source()[offset]) {
+                    Some(category) if filter.contains(&category) => (),
+                    _ => continue,
+                }
+            }
+            writeln!(out, "{:>6}  {}  ptr {:<4} {}",
+                step, source_context(interpreter.source(), offset), interpreter.pointer(), tape_window(interpreter))
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    // What one command of each category costs in `estimate_cost`.
+    #[derive(Clone, Copy, Debug)]
+    pub struct CostTable {
+        costs: [u64; 4],
+    }
+
+    impl Default for CostTable {
+        fn default() -> CostTable {
+            CostTable{ costs: [1; 4] }
+        }
+    }
+
+    impl CostTable {
+        pub fn cost(&self, category: TraceCategory) -> u64 {
+            self.costs[category as usize]
+        }
+        pub fn set(&mut self, category: TraceCategory, cost: u64) {
+            self.costs[category as usize] = cost;
+        }
+    }
+
+    // One `category = cost` per line; `#` starts a comment, and categories
+    // not listed cost 1.
+    impl std::str::FromStr for CostTable {
+        type Err = String;
+        fn from_str(s: &str) -> Result<CostTable, String> {
+            let mut table = CostTable::default();
+            for (n, line) in s.lines().enumerate() {
+                let line = line.split('#').next().unwrap().trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let (name, cost) = line.split_once('=')
+                    .ok_or_else(|| format!("Line {}: expected \"category = cost\"", n + 1))?;
+                let cost = cost.trim().parse().map_err(|e| format!("Line {}: {}", n + 1, e))?;
+                table.set(name.trim().parse()?, cost);
+            }
+            Ok(table)
+        }
+    }
+
+    // How many commands of each category a run executed, and their total
+    // cost by some table.
+    #[derive(Clone, Copy, Default, Debug)]
+    pub struct CostReport {
+        counts: [u64; 4],
+        pub total: u64,
+    }
+
+    impl CostReport {
+        pub fn count(&self, category: TraceCategory) -> u64 {
+            self.counts[category as usize]
+        }
+    }
+
+    // Runs `interpreter` one step at a time, counting the commands of each
+    // category and pricing them by `table`.
+    pub fn estimate_cost(interpreter: &mut Interpreter, table: &CostTable) -> Result<CostReport, String> {
+        interpreter.prepare().map_err(|e| e.to_string())?;
+        let mut report = CostReport::default();
+        while let Some(offset) = interpreter.source_offset() {
+            if interpreter.step().map_err(|e| e.to_string())? == Status::NeedInput {
+                break;
+            }
+            if let Some(category) = TraceCategory::of(interpreter.source()[offset]) {
+                report.counts[category as usize] += 1;
+                report.total = report.total.saturating_add(table.cost(category));
+            }
+        }
+        Ok(report)
+    }
+
+    // One redraw of the tape view. Without ANSI support the line is
+    // rewritten after a carriage return and padded to clear what was there.
+    fn watch_frame(interpreter: &Interpreter, ansi: bool, width: &mut usize) -> String {
+        let line = format!("ptr {:<4} {}", interpreter.pointer(), tape_window(interpreter));
+        if ansi {
+            return format!("\x1b[2K\r{}", line);
+        }
+        let pad = width.saturating_sub(line.len());
+        *width = line.len();
+        format!("\r{}{}", line, " ".repeat(pad))
+    }
+
+    // Steps `interpreter`, redrawing the tape around the pointer in place on
+    // `out` after every step and sleeping `delay` between steps.
+    pub fn watch(
+        interpreter: &mut Interpreter,
+        out: &mut dyn std::io::Write,
+        delay: std::time::Duration,
+        ansi: bool) -> Result<(), String> {
+        interpreter.prepare().map_err(|e| e.to_string())?;
+        let mut width = 0;
+        loop {
+            write!(out, "{}", watch_frame(interpreter, ansi, &mut width)).map_err(|e| e.to_string())?;
+            out.flush().map_err(|e| e.to_string())?;
+            if interpreter.step().map_err(|e| e.to_string())? != Status::Running {
+                break;
+            }
+            std::thread::sleep(delay);
+        }
+        write!(out, "{}", watch_frame(interpreter, ansi, &mut width)).map_err(|e| e.to_string())?;
+        writeln!(out).map_err(|e| e.to_string())
+    }
+
+    // Steps `interpreter` to the end, writing a snapshot line to `out` after
+    // every `every` steps: the step count, the pointer and each nonzero cell
+    // as "index:value".
+    pub fn tape_log(interpreter: &mut Interpreter, out: &mut dyn std::io::Write, every: u64) -> Result<(), String> {
+        interpreter.prepare().map_err(|e| e.to_string())?;
+        let every = every.max(1);
+        let mut step = 0;
+        while interpreter.source_offset().is_some() {
+            let status = interpreter.step().map_err(|e| e.to_string())?;
+            step += 1;
+            if step % every == 0 {
+                let cells : Vec<String> = interpreter.memory().iter().enumerate()
+                    .filter(|(_, &v)| v != 0)
+                    .map(|(i, v)| format!("{}:{}", i, interpreter.display_cell(*v)))
+                    .collect();
+                writeln!(out, "step {} ptr {} {}", step, interpreter.pointer(), cells.join(" "))
+                    .map_err(|e| e.to_string())?;
+            }
+            if status != Status::Running {
+                break;
+            }
+        }
+        out.flush().map_err(|e| e.to_string())
+    }
+
+    // Steps `interpreter` from where it is to the end, saving its image
+    // (see `save_image`) over `path` after every `every` steps, so a run
+    // that dies can go on from the last one with `load_image`.
+    pub fn checkpoint(interpreter: &mut Interpreter, every: u64, path: &std::path::Path) -> Result<(), String> {
+        let every = every.max(1);
+        let mut step = 0;
+        let mut image = Vec::<u8>::new();
+        while interpreter.source_offset().is_some() {
+            let status = interpreter.step().map_err(|e| e.to_string())?;
+            step += 1;
+            if step % every == 0 {
+                image.clear();
+                interpreter.save_image(&mut image).map_err(|e| e.to_string())?;
+                std::fs::write(path, &image).map_err(|e| format!("{}: {}", path.display(), e))?;
+            }
+            if status != Status::Running {
+                break;
+            }
+        }
+        interpreter.resume().map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    // Runs `interpreter` under a line-oriented command loop read from
+    // `commands`. Execution starts stopped before the first instruction.
+    pub fn run(interpreter: &mut Interpreter, commands: &mut dyn BufRead) -> Result<(), String> {
+        interpreter.prepare().map_err(|e| e.to_string())?;
+        let mut status = if interpreter.source_offset().is_some() {
+            Status::Running
+        } else {
+            Status::Finished
+        };
+        let mut breakpoints = BTreeSet::<usize>::new();
+        let mut checkpoints = BTreeMap::<String, Snapshot>::new();
+        let mut line = String::new();
+        while status == Status::Running {
+            line.clear();
+            match commands.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) => (),
+                Err(msg) => return Err(msg.to_string()),
+            }
+            let words : Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => (),
+                [":set", index, value] => {
+                    let index = match index.parse::<usize>() {
+                        Ok(i) => i,
+                        Err(_) => {
+                            println!("Invalid cell index: {}", index);
+                            continue;
+                        }
+                    };
+                    let value = match value.parse::<u8>() {
+                        Ok(v) => v,
+                        Err(_) => {
+                            println!("Value does not fit in a cell: {}", value);
+                            continue;
+                        }
+                    };
+                    if let Err(msg) = interpreter.set_cell(index, value) {
+                        println!("{}", msg);
+                    }
+                }
+                [":save", name] => {
+                    checkpoints.insert(name.to_string(), interpreter.snapshot());
+                }
+                [":restore", name] => match checkpoints.get(*name) {
+                    Some(snapshot) => {
+                        interpreter.restore(snapshot);
+                        print_location(interpreter, status);
+                    }
+                    None => println!("No checkpoint named {}", name),
+                },
+                [":tape"] => {
+                    let cells : Vec<String> = interpreter.memory().iter().map(|v| interpreter.display_cell(*v)).collect();
+                    println!("pointer: {}  tape: [{}]", interpreter.pointer(), cells.join(", "));
+                }
+                ["break", offset] | ["b", offset] => {
+                    match offset.parse::<usize>() {
+                        Ok(o) => {
+                            breakpoints.insert(o);
+                        }
+                        Err(_) => println!("Invalid source offset: {}", offset),
+                    }
+                }
+                ["step"] | ["s"] => {
+                    status = interpreter.step().map_err(|e| e.to_string())?;
+                    print_location(interpreter, status);
+                }
+                ["back"] => {
+                    if !interpreter.step_back() {
+                        println!("Nothing to step back over");
+                    }
+                    print_location(interpreter, status);
+                }
+                ["next"] | ["n"] => {
+                    status = match interpreter.loop_exit() {
+                        Some(exit) => run_until(interpreter, &breakpoints, &|i| i.position() == exit)?,
+                        None => interpreter.step().map_err(|e| e.to_string())?,
+                    };
+                    print_location(interpreter, status);
+                }
+                [":runto-input"] => {
+                    let at_input = |i: &Interpreter| i.source_offset().is_some_and(|o| i.source()[o] == b',');
+                    status = run_until(interpreter, &breakpoints, &at_input)?;
+                    print_location(interpreter, status);
+                }
+                ["continue"] | ["c"] => {
+                    status = run_until(interpreter, &breakpoints, &|_| false)?;
+                    print_location(interpreter, status);
+                }
+                ["quit"] | ["q"] => return Ok(()),
+                _ => println!("Unknown command: {}", line.trim()),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn build(source: &str) -> Interpreter {
+            crate::interpreter::new(String::from(source)).with_captured_output().build().unwrap()
+        }
+
+        #[test]
+        fn set_changes_a_cell_before_the_run_goes_on() {
+            let mut interpreter = crate::interpreter::new(String::from("+>+")).build().unwrap();
+            let mut commands : &[u8] = b":set 0 300\n:set 0 64\n:set 9 1\nc\n";
+            run(&mut interpreter, &mut commands).unwrap();
+            assert_eq!(interpreter.memory(), &[65, 1]);
+        }
+
+        #[test]
+        fn step_next_and_continue() {
+            let mut interpreter = crate::interpreter::new(String::from("+++[-]++")).build().unwrap();
+            let mut commands : &[u8] = b"step\nstep\nstep\nnext\n";
+            run(&mut interpreter, &mut commands).unwrap();
+            // `next` ran the whole loop and stopped past its `]`.
+            assert_eq!(interpreter.position(), 6);
+            assert_eq!(interpreter.memory(), &[0]);
+            let mut commands : &[u8] = b"b 7\nstep\nnext\ncontinue\n";
+            run(&mut interpreter, &mut commands).unwrap();
+            assert_eq!(interpreter.position(), 7);
+            assert_eq!(interpreter.memory(), &[1]);
+        }
+
+        #[test]
+        fn next_runs_nested_loops_as_one_step() {
+            let mut interpreter = build("++[>++[->+<]<-]>>.");
+            let mut commands : &[u8] = b"step\nstep\nnext\n";
+            run(&mut interpreter, &mut commands).unwrap();
+            assert_eq!(interpreter.source_offset(), Some(15));
+            assert_eq!(interpreter.memory(), &[0, 0, 4]);
+        }
+
+        #[test]
+        fn next_over_an_endless_loop_hits_the_step_limit() {
+            let mut interpreter = crate::interpreter::new(String::from("+[]")).with_max_steps(1000).build().unwrap();
+            let mut commands : &[u8] = b"step\nnext\n";
+            assert!(run(&mut interpreter, &mut commands).is_err());
+            // The limit trips on the first step past it.
+            assert_eq!(interpreter.steps(), 1001);
+        }
+
+        #[test]
+        fn explain_prints_one_line_per_step() {
+            let mut interpreter = build("++>+");
+            let mut out = Vec::<u8>::new();
+            explain(&mut interpreter, &mut out, None).unwrap();
+            let text = String::from_utf8(out).unwrap();
+            let lines : Vec<&str> = text.lines().collect();
+            assert_eq!(lines.len(), 4, "{}", text);
+            for (n, line) in lines.iter().enumerate() {
+                assert!(line.trim_start().starts_with(&(n + 1).to_string()), "{}", line);
+            }
+            assert!(lines[0].contains("(+)+>+") && lines[0].contains("ptr 0") && lines[0].ends_with("[1]"), "{}", lines[0]);
+            assert!(lines[2].contains("++(>)+") && lines[2].contains("ptr 1") && lines[2].ends_with("2 [0]"), "{}", lines[2]);
+            assert!(lines[3].ends_with("2 [1]"), "{}", lines[3]);
+        }
+
+        #[test]
+        fn watch_redraws_frames_with_carriage_returns() {
+            let mut interpreter = build("+>++++++++++");
+            let mut out = Vec::<u8>::new();
+            watch(&mut interpreter, &mut out, std::time::Duration::ZERO, false).unwrap();
+            let text = String::from_utf8(out).unwrap();
+            assert!(!text.contains('\x1b'));
+            let frames : Vec<&str> = text.trim_end_matches('\n').split('\r').skip(1).collect();
+            assert_eq!(frames.len(), 13);
+            assert_eq!(frames[0], "ptr 0    [0]");
+            assert_eq!(frames[1], "ptr 0    [1]");
+            assert_eq!(frames[2], "ptr 1    1 [0]");
+            assert_eq!(frames[12], "ptr 1    1 [10]");
+            // A shorter frame blanks out what is left of the longer one.
+            let mut interpreter = build("++++++++++[-]");
+            let mut out = Vec::<u8>::new();
+            watch(&mut interpreter, &mut out, std::time::Duration::ZERO, false).unwrap();
+            let text = String::from_utf8(out).unwrap();
+            assert!(text.contains("\rptr 0    [10]\rptr 0    [9] \r"), "{:?}", text);
+        }
+
+        #[test]
+        fn trace_filter_keeps_only_io_steps() {
+            let mut interpreter = build("++[>+.<-]>.");
+            let mut out = Vec::<u8>::new();
+            explain(&mut interpreter, &mut out, Some(&[TraceCategory::Io])).unwrap();
+            let text = String::from_utf8(out).unwrap();
+            let commands : Vec<char> = text.lines().map(|l| {
+                let open = l.find('(').unwrap();
+                l[open + 1..].chars().next().unwrap()
+            }).collect();
+            assert_eq!(commands, ['.', '.', '.'], "{}", text);
+            assert_eq!("io,loop".split(',').map(|c| c.parse::<TraceCategory>()).collect::<Result<Vec<_>, _>>().unwrap(),
+                [TraceCategory::Io, TraceCategory::Loop]);
+        }
+
+        #[test]
+        fn tape_log_writes_one_snapshot_per_interval() {
+            let mut interpreter = build("+++++>++<");
+            let mut out = Vec::<u8>::new();
+            tape_log(&mut interpreter, &mut out, 3).unwrap();
+            let text = String::from_utf8(out).unwrap();
+            assert_eq!(text, "step 3 ptr 0 0:3\nstep 6 ptr 1 0:5\nstep 9 ptr 0 0:5 1:2\n");
+            let mut interpreter = build("+++++>++<");
+            let mut out = Vec::<u8>::new();
+            tape_log(&mut interpreter, &mut out, 4).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap().lines().count(), 2);
+        }
+
+        #[test]
+        fn run_to_stops_before_the_offset() {
+            let mut interpreter = build("++>+++ stop here >++++.");
+            assert_eq!(run_to(&mut interpreter, 17).unwrap(), Status::Running);
+            assert_eq!(interpreter.memory(), &[2, 3]);
+            assert_eq!(interpreter.pointer(), 1);
+            assert!(interpreter.take_output().is_empty());
+            let mut interpreter = build("+++.");
+            assert_eq!(run_to(&mut interpreter, 100).unwrap(), Status::Finished);
+        }
+
+        #[test]
+        fn restore_brings_back_a_saved_checkpoint() {
+            let mut interpreter = build("+>++>+++.");
+            let mut commands : &[u8] = b"s\ns\n:save one\ns\ns\ns\n:save two\n:set 0 9\n:restore one\n";
+            run(&mut interpreter, &mut commands).unwrap();
+            assert_eq!(interpreter.memory(), &[1, 0]);
+            assert_eq!(interpreter.pointer(), 1);
+            let mut commands : &[u8] = b":save one\ns\n:restore nothing\n:restore one\n";
+            run(&mut interpreter, &mut commands).unwrap();
+            assert_eq!(interpreter.memory(), &[0]);
+        }
+
+        #[test]
+        fn runto_input_stops_before_the_first_read() {
+            let mut interpreter = crate::interpreter::new(String::from("++[>+<-]>.,."))
+                .with_input(Box::new(&b"z"[..]))
+                .with_captured_output()
+                .build()
+                .unwrap();
+            let mut commands : &[u8] = b":runto-input\n";
+            run(&mut interpreter, &mut commands).unwrap();
+            assert_eq!(interpreter.source_offset(), Some(10));
+            assert_eq!(interpreter.memory(), &[0, 2]);
+            assert_eq!(interpreter.take_output(), [2]);
+        }
+
+        #[test]
+        fn estimate_cost_weights_each_category() {
+            let table : CostTable = "loop = 5\nio = 10 # output\n".parse().unwrap();
+            let mut interpreter = build("++[>+<-].");
+            let report = estimate_cost(&mut interpreter, &table).unwrap();
+            assert_eq!(report.count(TraceCategory::Arith), 6);
+            assert_eq!(report.count(TraceCategory::Move), 4);
+            assert_eq!(report.count(TraceCategory::Loop), 3);
+            assert_eq!(report.count(TraceCategory::Io), 1);
+            assert_eq!(report.total, 6 + 4 + 3 * 5 + 10);
+            assert!("loop 5".parse::<CostTable>().is_err());
+        }
+
+        #[test]
+        fn markers_change_neither_output_nor_trace() {
+            let trace = |markers: Vec<usize>| {
+                let mut interpreter = crate::interpreter::new(String::from("++[>+<-] >."))
+                    .with_markers(markers)
+                    .with_captured_output()
+                    .build()
+                    .unwrap();
+                let mut out = Vec::<u8>::new();
+                explain(&mut interpreter, &mut out, None).unwrap();
+                (out, interpreter.take_output())
+            };
+            assert_eq!(trace(vec![2, 8, 100]), trace(Vec::<usize>::new()));
+            let mut interpreter = crate::interpreter::new(String::from("++[>+<-] >.")).with_markers(vec![8, 2]).build().unwrap();
+            let c = interpreter.to_c(false).unwrap();
+            let mark2 = c.find("/* mark 2 */").unwrap();
+            let mark8 = c.find("/* mark 8 */").unwrap();
+            assert!(mark2 < c.find("while").unwrap() && c.find("while").unwrap() < mark8);
+        }
+
+        #[test]
+        fn run_to_output_pauses_after_the_byte() {
+            let mut interpreter = build("++++++++[>++++++++<-]>+.+.-.");
+            assert_eq!(run_to_output(&mut interpreter, 65).unwrap(), Status::Running);
+            assert_eq!(interpreter.take_output(), b"A");
+            assert_eq!(interpreter.source_offset(), Some(24));
+            let mut interpreter = build("+.");
+            assert_eq!(run_to_output(&mut interpreter, 65).unwrap(), Status::Finished);
+        }
+
+        #[test]
+        fn checkpoint_saves_every_n_steps() {
+            let path = std::env::temp_dir().join(format!("bf-checkpoint-{}.img", std::process::id()));
+            let source = "++++++[>+++++++++++<-]>-.+.";
+            let mut interpreter = build(source);
+            interpreter.prepare().unwrap();
+            checkpoint(&mut interpreter, 10, &path).unwrap();
+            let total = interpreter.steps();
+            let image = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            let mut restored = crate::interpreter::load_image(&image).unwrap().with_captured_output();
+            assert_eq!(restored.steps(), total / 10 * 10);
+            restored.resume().unwrap();
+            assert_eq!(restored.steps(), total);
+            assert_eq!(restored.memory(), interpreter.memory());
+            assert_eq!(interpreter.take_output(), b"AB");
+        }
+
+        #[test]
+        fn run_to_pointer_pauses_when_the_pointer_first_reaches_a_cell() {
+            let mut interpreter = build("+>++>+++>++++>+++++<<<<[>]");
+            assert!(run_to_pointer(&mut interpreter, 3).unwrap());
+            assert_eq!(interpreter.steps(), 9);
+            assert_eq!(interpreter.pointer(), 3);
+            assert_eq!(interpreter.memory(), &[1, 2, 3, 0]);
+        }
+
+        #[test]
+        fn run_to_pointer_sees_the_last_step() {
+            let mut interpreter = build(">");
+            assert!(run_to_pointer(&mut interpreter, 1).unwrap());
+            let mut interpreter = build(">");
+            assert!(!run_to_pointer(&mut interpreter, 2).unwrap());
+        }
+
+        #[test]
+        fn run_to_pointer_counts_from_the_initial_tape() {
+            let mut interpreter = build("<>>");
+            assert!(run_to_pointer(&mut interpreter, 1).unwrap());
+            assert_eq!(interpreter.steps(), 3);
+            assert_eq!(interpreter.pointer(), 2);
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "signal"))]
+pub mod signal {
+    use std::os::raw::c_int;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
+    const SIGUSR1: c_int = 30;
+    #[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd")))]
+    const SIGUSR1: c_int = 10;
+
+    static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" {
+        fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    }
+
+    extern "C" fn on_usr1(_: c_int) {
+        request_dump();
+    }
+
+    // Makes SIGUSR1 request a progress dump.
+    pub fn install() {
+        unsafe {
+            signal(SIGUSR1, on_usr1);
+        }
+    }
+
+    // Asks the running interpreter to print its progress to stderr at the
+    // next step; this is what the SIGUSR1 handler does.
+    pub fn request_dump() {
+        DUMP_REQUESTED.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn take_request() -> bool {
+        DUMP_REQUESTED.load(Ordering::Relaxed) && DUMP_REQUESTED.swap(false, Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::interpreter;
+
+        #[test]
+        fn dump_request_is_taken_at_the_next_poll() {
+            assert!(!take_request());
+            request_dump();
+            let mut interpreter = interpreter::new("+".repeat(100)).build().unwrap();
+            interpreter.run().unwrap();
+            assert!(!take_request());
+        }
+    }
+}
+
+#[cfg(feature = "capi")]
+pub mod capi {
+    use std::ffi::CStr;
+    use std::os::raw::{c_char, c_int};
+
+    pub const BF_ERR_ARGUMENT: c_int = -1;
+    pub const BF_ERR_PROGRAM: c_int = -2;
+    pub const BF_ERR_BUFFER: c_int = -3;
+
+    /// Runs the NUL-terminated `source` with `input_len` bytes of `input`,
+    /// writing its output to `out`. Returns the number of bytes written, or
+    /// one of the negative `BF_ERR_*` codes.
+    ///
+    /// # Safety
+    ///
+    /// `source` must be null or point to a NUL-terminated string. `input`
+    /// must be valid for `input_len` bytes and `out` for `out_cap` bytes,
+    /// unless the respective length is zero.
+    #[no_mangle]
+    pub unsafe extern "C" fn bf_run(
+        source: *const c_char,
+        input: *const u8,
+        input_len: usize,
+        out: *mut u8,
+        out_cap: usize) -> c_int {
+        if source.is_null() || (input.is_null() && input_len != 0) || (out.is_null() && out_cap != 0) {
+            return BF_ERR_ARGUMENT;
+        }
+        let source = CStr::from_ptr(source).to_bytes();
+        let input = if input_len == 0 {
+            &[]
+        } else {
+            std::slice::from_raw_parts(input, input_len)
+        };
+        let output = match crate::interpreter::run_bytes(source, input) {
+            Ok(v) => v,
+            Err(_) => return BF_ERR_PROGRAM,
+        };
+        if output.len() > out_cap || output.len() > c_int::MAX as usize {
+            return BF_ERR_BUFFER;
+        }
+        std::ptr::copy_nonoverlapping(output.as_ptr(), out, output.len());
+        output.len() as c_int
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn bf_run_writes_the_output() {
+            let source = b",[.,]\0";
+            let input = b"hi";
+            let mut out = [0u8; 8];
+            let n = unsafe { bf_run(source.as_ptr() as *const c_char, input.as_ptr(), input.len(), out.as_mut_ptr(), out.len()) };
+            assert_eq!(n, 2);
+            assert_eq!(&out[..2], b"hi");
+        }
+
+        #[test]
+        fn bf_run_reports_errors() {
+            let mut out = [0u8; 1];
+            let run = |source: &[u8], out: &mut [u8]| unsafe {
+                bf_run(source.as_ptr() as *const c_char, std::ptr::null(), 0, out.as_mut_ptr(), out.len())
+            };
+            assert_eq!(unsafe { bf_run(std::ptr::null(), std::ptr::null(), 0, out.as_mut_ptr(), 1) }, BF_ERR_ARGUMENT);
+            assert_eq!(run(b"[\0", &mut out), BF_ERR_PROGRAM);
+            assert_eq!(run(b"+.+.\0", &mut out), BF_ERR_BUFFER);
+            assert_eq!(run(b"+.\0", &mut out), 1);
+        }
+    }
+}
@@ -1,248 +1,993 @@
-mod interpreter {
-    enum Op {
-        Increment,
-        Decrement,
-        ShiftLeft,
-        ShiftRight,
-        PrintChar,
-        GetChar,
-        LoopStart,
-        LoopEnd
-    }
+use brainfuck::{debugger, interpreter, optimizer};
 
-    impl Op {
-        #[allow(dead_code)]
-        fn to_string(&self) -> String {
-            return match self {
-                Op::Increment => String::from("Increment"),
-                Op::Decrement => String::from("Decrement"),
-                Op::ShiftLeft => String::from("ShiftLeft"),
-                Op::ShiftRight => String::from("ShiftRight"),
-                Op::PrintChar => String::from("PrintChar"),
-                Op::GetChar => String::from("GetChar"),
-                Op::LoopStart => String::from("LoopStart"),
-                Op::LoopEnd => String::from("LoopEnd"),
-            };
-        }
+fn print_usage() {
+    let usage = [
+    "Usage: ./bf [options] <source-file> [-- <input>...]",
+    "       ./bf [options] --run-asm <file> [-- <input>...]",
+    "       ./bf --gen <text>",
+    "    argument:",
+    "        <source-file>    Run brainfuck program.",
+    "        <input>...       Feed the arguments, joined by spaces, to ','.",
+    "    options:",
+    "        --strict         Reject characters that are not commands or whitespace.",
+    "        --classic        Classic semantics: the tape starts at cell 0 and only",
+    "                         grows right, and ',' leaves the cell unchanged at EOF.",
+    "        --warn-left      Warn once when the pointer first moves left of cell 0.",
+    "        -O<level>        Optimize before running (0: none, 1: fold straight-line",
+    "                         code, 2: also clear, move, copy and scan loops, 3: also",
+    "                         drop dead loops and inline loops that run once).",
+    "                         -O alone selects the highest level.",
+    "        --passes <list>  Run these comma-separated optimization passes in order",
+    "                         instead: fuse-add, clear-loop, move-loop, copy-loop,",
+    "                         scan-loop, dead-loop, once-loop.",
+    "        --opt-report     Print each optimization pass with the node count of the",
+    "                         program before and after it to stderr before running.",
+    "        --flat           Run the fused bytecode shown by --disasm; -O is ignored.",
+    "        --checked-arith  Fail when '+' or '-' would wrap a cell around.",
+    "        --saturating-arith",
+    "                         Keep cells at 255 or 0 instead of wrapping around.",
+    "        --keep-going     Report recoverable errors (pointer underflow, cell",
+    "                         overflow, missing or invalid input) and skip the",
+    "                         command instead of stopping.",
+    "        --dual-tape      Add a second tape with its own pointer; '%' switches",
+    "                         between the two.",
+    "        --max-tape <n>   Abort when the tape would grow beyond n cells.",
+    "        --max-nesting <n>",
+    "                         Refuse a source whose loops nest more than n deep.",
+    "        --max-source-bytes <n>",
+    "                         Refuse a source file larger than n bytes.",
+    "        --ptr-modulo <w> Wrap the pointer around within cells 0 to w-1.",
+    "        --tape-chunk <n> Grow the tape n cells at a time.",
+    "        --max-steps <n>  Abort after executing n steps.",
+    "        --check-interval <n>",
+    "                         Check --max-steps only every n steps, which is faster",
+    "                         but may overshoot it by up to n-1 steps.",
+    "        --max-depth <n>  Abort when running loops nest deeper than n levels.",
+    "        --max-output <n> Abort when the program prints more than n bytes.",
+    "        --max-time <ms>  Abort when the run takes longer than ms milliseconds.",
+    "        --assert-terminates",
+    "                         Fail unless the program finishes within the limits,",
+    "                         naming the limit it ran into. Needs --max-steps or",
+    "                         --max-time.",
+    "        --initial-pointer <n>",
+    "                         Start with the pointer at cell n.",
+    "        --fill-value <byte>",
+    "                         Start every cell at byte instead of 0.",
+    "        --sandbox        Discard output and read no input; ',' yields 0.",
+    "        --sandbox-eof <n>",
+    "                         Like --sandbox, but ',' yields n.",
+    "        --sandbox-cycle <file>",
+    "                         Like --sandbox, but ',' reads the bytes of file over",
+    "                         and over.",
+    "        --input <file>   Feed the contents of file to ','.",
+    "        --transcript <file>",
+    "                         Log each byte read by ',' and printed by '.' to file,",
+    "                         in order and timestamped.",
+    "        --input-env <name>",
+    "                         Feed the value of environment variable name to ','.",
+    "        --prompt <str>   Print str to stderr when ',' waits on a terminal.",
+    "        --random-input <seed>",
+    "                         Feed ',' pseudo-random bytes generated from seed.",
+    "        --eof <policy>   What ',' stores at end of input: zero (default),",
+    "                         minus-one or unchanged.",
+    "        --eof-value <byte>",
+    "                         Store byte at end of input instead; overrides --eof.",
+    "        --input-encoding <encoding>",
+    "                         Deliver input as raw utf8 bytes (default) or as one",
+    "                         latin1 byte per character.",
+    "        --require-input  Fail if ',' reads past the end of input.",
+    "        --newline-is-eof Treat a newline read by ',' as end of input.",
+    "        --output-format <format>",
+    "                         Render output as raw (default), hex, escaped or rle",
+    "                         (runs of a byte as \"value x count\", e.g. 72x10).",
+    "        --rle-output     Same as --output-format rle.",
+    "        --ascii7         Mask each output byte to 7 bits.",
+    "        --line-buffered  Buffer output and flush it at each newline.",
+    "        --ensure-newline Print a trailing newline if the output lacks one.",
+    "        --disasm         Print the compiled bytecode instead of running.",
+    "        --emit-asm       Print the compiled bytecode as assembly text, one op per",
+    "                         line, instead of running.",
+    "        --run-asm <file> Assemble and run file, written as --emit-asm prints,",
+    "                         instead of a source file; implies --flat.",
+    "        --explain-opt    Print each bytecode op beside the source span and",
+    "                         commands it was fused from instead of running.",
+    "        --emit-c         Print the program translated to C instead of running.",
+    "                         With -O, the optimized program is translated.",
+    "        --emit-rust      Print the program translated to Rust instead of running.",
+    "        --emit-sexpr     Print the parsed program as an S-expression instead of",
+    "                         running.",
+    "        --show-ast       Print the parsed program as an S-expression to stderr,",
+    "                         then run it.",
+    "        --annotate       Print the program with a (loop#N depthD) comment after",
+    "                         each ']' instead of running.",
+    "        --emit-optimized-bf",
+    "                         Print the optimized program as brainfuck instead of",
+    "                         running; implies -O if no level is given.",
+    "        --source-map     Mark emitted C/Rust statements with source positions.",
+    "                         C is then translated without optimization.",
+    "        --check-termination",
+    "                         Refuse to run a program that certainly loops forever.",
+    "        --warn           Report suspicious code to stderr before running.",
+    "        --report-unreachable",
+    "                         Report code that can never run, such as the body of a",
+    "                         loop entered with a zero cell or code after a loop",
+    "                         that never exits, to stderr before running.",
+    "        --metrics        Print static program metrics instead of running.",
+    "        --size-table     Print the program's size in nodes at each level up to",
+    "                         the -O level (all levels without -O) instead of",
+    "                         running.",
+    "        --estimate-tape  Print how many cells the pointer can reach, found without",
+    "                         running, or \"unbounded\" if a loop may move it further",
+    "                         each time round.",
+    "        --summarize      Print the net change to each cell and the final pointer",
+    "                         of a program free of loops and I/O after optimization,",
+    "                         instead of running it.",
+    "        --explain        Run step by step, printing each command with the",
+    "                         pointer and nearby tape to stderr.",
+    "        --trace-filter <list>",
+    "                         Limit --explain to these comma-separated kinds of",
+    "                         command: arith, move, io, loop. Implies --explain.",
+    "        --cost-table <file>",
+    "                         Run step by step and print the commands executed per",
+    "                         category and their total cost to stderr. The file has",
+    "                         a \"category = cost\" line per category; others cost 1.",
+    "        --watch-tape     Run step by step, redrawing the tape on stderr in place.",
+    "        --delay <ms>     Pause between --watch-tape steps (default 50).",
+    "        --tape-log <file>",
+    "                         Run step by step, appending the pointer and nonzero",
+    "                         cells to file every --log-every steps.",
+    "        --log-every <n>  Steps between --tape-log snapshots (default 1000).",
+    "        --checkpoint-every <n> <file>",
+    "                         Run step by step, saving the machine to file every n",
+    "                         steps, each time over the last.",
+    "        --resume <file>  Go on from a --checkpoint-every file instead of running",
+    "                         a source file. Give the same input as the first run.",
+    "        --cfg            Print the loop nesting tree instead of running.",
+    "        --run-until <offset>",
+    "                         Stop before executing the command at this source",
+    "                         offset; the tape can then be dumped as usual.",
+    "        --run-until-ptr <index>",
+    "                         Stop once the pointer first reaches this cell, counted",
+    "                         from the start of the tape (cells grown on the left",
+    "                         are negative); the tape can then be dumped as usual.",
+    "        --run-until-output <byte>",
+    "                         Stop just after the program first prints byte; the",
+    "                         tape can then be dumped as usual.",
+    "        --lock-at <offset>",
+    "                         Make the tape read-only once the command at this",
+    "                         source offset is reached; later writes are errors.",
+    "        --debug          Run under the debugger, reading commands from stdin.",
+    "                         Supply program input with -- when debugging.",
+    "        --signed-display Show cells as signed bytes (200 as -56) in tape dumps",
+    "                         and views; arithmetic is unchanged.",
+    "        --dump-ppm <w> <h> <file>",
+    "                         Write the final tape as a w x h grayscale PPM image.",
+    "        --dump-tape-json <file>",
+    "                         Write the final pointer and tape as JSON to file",
+    "                         (- for stdout).",
+    "        --bench          Time the program with output and input disabled,",
+    "                         printing min/median/max to stderr.",
+    "        --repeat <n>     Number of --bench runs (default 10).",
+    "        --testdir <dir>  Run once per N.in file in dir, comparing the output with",
+    "                         N.out, and print a pass/fail summary instead of running.",
+    "        --expect <file>  Compare the output with file, reporting the first byte",
+    "                         that differs and failing if there is one.",
+    "        --pin            Pin the output to the --out-golden file: write it there",
+    "                         if the file does not exist, else compare as --expect.",
+    "        --out-golden <file>",
+    "                         The golden file for --pin.",
+    "        --annotated-source",
+    "                         After running, print each command with how many",
+    "                         times it ran and its line and column, to stderr.",
+    "        --stats          Print execution statistics to stderr after running.",
+    "        --show-consumed  With --stats, also print the input bytes ',' consumed.",
+    "        --stats-json <file>",
+    "                         Write execution statistics as JSON to file (- for stdout).",
+    "        --state-hash     Print a hash of the final pointer and tape to stderr.",
+    "        --value-histogram",
+    "                         Print how many cells of the final tape hold each value",
+    "                         to stderr, as \"value: count cells\".",
+    "        --profile        Print per-cell read/write counts to stderr",
+    "                         after running, as \"index,reads,writes\".",
+    "        --loop-timing    Print the time spent in each loop to stderr after",
+    "                         running, slowest first. Timing slows the run down",
+    "                         and disables -O.",
+    "        --work           Print to stderr after running a step count where each",
+    "                         step weighs 1 plus the number of loops it is inside.",
+    "                         Disables -O.",
+    "        --gen <text>     Print a brainfuck program that prints text.",
+    "        -h|--help        Show this help"
+    ];
+    for s in usage.iter() {
+        println!("{}", s);
     }
+}
 
-    enum Instruction {
-        Increment,
-        Decrement,
-        ShiftLeft,
-        ShiftRight,
-        PrintChar,
-        GetChar,
-        Loop(Vec<Instruction>),
-    }
+// Up to 16 bytes on either side of `index`, escaped onto one line.
+fn context(bytes: &[u8], index: usize) -> String {
+    let start = index.saturating_sub(16).min(bytes.len());
+    let end = (index + 16).min(bytes.len());
+    format!("{:?}", String::from_utf8_lossy(&bytes[start..end]))
+}
 
-    pub struct Interpreter {
-        source: String,
-        ops: Vec<Op>,
-        inst: Vec<Instruction>,
+// A reader for the input given by --input, --input-env or --, the last
+// taking precedence. A file is read as ',' asks for it, not loaded first.
+fn given_input(file: Option<&str>, env: Option<&str>, argv: Option<&str>) -> Result<Option<Box<dyn std::io::Read>>, String> {
+    if let Some(input) = argv {
+        return Ok(Some(Box::new(std::io::Cursor::new(input.as_bytes().to_vec()))));
     }
-
-    pub fn new(s: String) -> Interpreter {
-        return Interpreter{
-            source: s,
-            ops: Vec::<Op>::new(),
-            inst: Vec::<Instruction>::new(),
-        };
+    if let Some(name) = env {
+        let input = std::env::var(name).map_err(|e| format!("{}: {}", name, e))?;
+        return Ok(Some(Box::new(std::io::Cursor::new(input.into_bytes()))));
     }
-
-    #[allow(dead_code)]
-    fn print_instruction(v: &Vec<Instruction>) {
-        print_instruction_with_indent(v, 0);
+    match file {
+        Some(file) => {
+            let input = std::fs::File::open(file).map_err(|e| format!("{}: {}", file, e))?;
+            Ok(Some(Box::new(std::io::BufReader::new(input))))
+        }
+        None => Ok(None),
     }
+}
 
-    #[allow(dead_code)]
-    fn print_instruction_with_indent(v: &Vec<Instruction>, depth: u8) {
-        let mut indent = String::from("");
-        for _ in 0..depth {
-            indent.push_str("  ");
+// Runs the program once for each N.in in `dir`, comparing the output with
+// N.out, and prints a line per case and a summary. Fails if any case does.
+fn run_testdir(interpreter: interpreter::Interpreter, dir: &str) -> Result<(), String> {
+    let mut cases = Vec::<(String, std::path::PathBuf)>::new();
+    for entry in std::fs::read_dir(dir).map_err(|e| format!("{}: {}", dir, e))? {
+        let path = entry.map_err(|e| format!("{}: {}", dir, e))?.path();
+        if path.extension().is_some_and(|e| e == "in") {
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            cases.push((name, path));
         }
-
-        for e in v.iter() {
-            let label = match e {
-                Instruction::Increment =>  Some(String::from("Increment")),
-                Instruction::Decrement =>  Some(String::from("Decrement")),
-                Instruction::ShiftLeft =>  Some(String::from("ShiftLeft")),
-                Instruction::ShiftRight => Some(String::from("ShiftRight")),
-                Instruction::PrintChar =>  Some(String::from("PrintChar")),
-                Instruction::GetChar =>  Some(String::from("GetChar")),
-                Instruction::Loop(child) => {
-                    println!("{}Loop:", indent);
-                    print_instruction_with_indent(child, depth + 1);
-                    None
-                }
-            };
-
-            match label {
-                Some(v) => println!("{}{}", indent, v),
-                None => (),
+    }
+    cases.sort_by(|a, b| (a.0.parse::<u64>().ok(), &a.0).cmp(&(b.0.parse::<u64>().ok(), &b.0)));
+    let mut interpreter = interpreter.with_captured_output();
+    let mut failed = 0;
+    for (name, path) in cases.iter() {
+        let input = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let out = path.with_extension("out");
+        let expected = match std::fs::read(&out) {
+            Ok(expected) => expected,
+            Err(e) => {
+                println!("{}: FAIL ({}: {})", name, out.display(), e);
+                failed += 1;
+                continue;
+            }
+        };
+        interpreter.clear_io_buffers();
+        interpreter = interpreter.with_input(Box::new(std::io::Cursor::new(input)));
+        let result = match interpreter.run() {
+            Ok(()) => interpreter::first_mismatch(&interpreter.take_output(), &expected),
+            Err(e) => Some(interpreter::Mismatch::Failed(e)),
+        };
+        match result {
+            Some(m) => {
+                println!("{}: FAIL ({})", name, m);
+                failed += 1;
             }
+            None => println!("{}: ok", name),
         }
     }
+    println!("{} passed, {} failed", cases.len() - failed, failed);
+    if failed > 0 {
+        return Err(format!("{} of {} cases failed.", failed, cases.len()));
+    }
+    Ok(())
+}
 
-    impl Interpreter {
-        pub fn run(&mut self) -> Result<(), String> {
-            self.validate()?;
-            self.lex_code();
-            self.build_instruction();
-            self.eval_instruction();
-            Ok(())
-        }
-        fn validate(&self) -> Result<(), String> {
-            let mut count = 0;
-            for c in self.source.chars() {
-                match c {
-                    '[' => count += 1,
-                    ']' => count -= 1,
-                    _ => (),
-                }
-                if count < 0 {
-                    return Err(String::from("Invalid source."));
-                }
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    match value {
+        Some(v) => v.parse::<T>().map_err(|_| format!("Invalid value for {}: {}", flag, v)),
+        None => Err(format!("Missing value for {}", flag)),
+    }
+}
+
+fn main() -> Result<(), String> {
+    #[cfg(all(unix, feature = "signal"))]
+    brainfuck::signal::install();
+    let args : Vec<String> = std::env::args().collect();
+    let mut sourcefile : Option<&String> = None;
+    let mut strict = false;
+    let mut classic = false;
+    let mut warn_left = false;
+    let mut opt_level = 0;
+    let mut passes : Option<Vec<optimizer::Pass>> = None;
+    let mut opt_report = false;
+    let mut flat = false;
+    let mut arith_mode = interpreter::ArithMode::Wrapping;
+    let mut keep_going = false;
+    let mut dual_tape = false;
+    let mut max_tape : Option<usize> = None;
+    let mut max_source : Option<usize> = None;
+    let mut max_nesting : Option<usize> = None;
+    let mut ptr_modulo : Option<usize> = None;
+    let mut tape_chunk : Option<usize> = None;
+    let mut max_steps : Option<u64> = None;
+    let mut max_time : Option<u64> = None;
+    let mut assert_terminates = false;
+    let mut check_interval : Option<u64> = None;
+    let mut max_depth : Option<usize> = None;
+    let mut max_output : Option<u64> = None;
+    let mut initial_pointer = 0;
+    let mut fill_value = 0;
+    let mut sandbox : Option<interpreter::SandboxInput> = None;
+    let mut prompt : Option<String> = None;
+    let mut random_input : Option<u64> = None;
+    let mut eof_policy = interpreter::EofPolicy::Zero;
+    let mut eof_value : Option<u8> = None;
+    let mut input_encoding = interpreter::InputEncoding::Utf8;
+    let mut require_input = false;
+    let mut newline_is_eof = false;
+    let mut output_format = interpreter::OutputFormat::Raw;
+    let mut ascii7 = false;
+    let mut line_buffered = false;
+    let mut ensure_newline = false;
+    let mut signed_display = false;
+    let mut disasm = false;
+    let mut explain_opt = false;
+    let mut emit_asm = false;
+    let mut run_asm : Option<String> = None;
+    let mut emit_c = false;
+    let mut emit_rust = false;
+    let mut emit_sexpr = false;
+    let mut show_ast = false;
+    let mut emit_bf = false;
+    let mut annotate = false;
+    let mut source_map = false;
+    let mut check_termination = false;
+    let mut warn = false;
+    let mut report_unreachable = false;
+    let mut metrics = false;
+    let mut size_table = false;
+    let mut summarize = false;
+    let mut estimate_tape = false;
+    let mut cfg = false;
+    let mut explain = false;
+    let mut trace_filter : Option<Vec<debugger::TraceCategory>> = None;
+    let mut cost_table : Option<String> = None;
+    let mut watch_tape = false;
+    let mut delay = 50;
+    let mut tape_log : Option<String> = None;
+    let mut log_every = 1000;
+    let mut checkpoint : Option<(u64, String)> = None;
+    let mut testdir : Option<String> = None;
+    let mut resume : Option<String> = None;
+    let mut debug = false;
+    let mut run_until : Option<usize> = None;
+    let mut run_until_ptr : Option<isize> = None;
+    let mut run_until_output : Option<u8> = None;
+    let mut lock_at : Option<usize> = None;
+    let mut dump_ppm : Option<(usize, usize, String)> = None;
+    let mut dump_json : Option<String> = None;
+    let mut bench = false;
+    let mut repeat = 10;
+    let mut stats = false;
+    let mut annotated_source = false;
+    let mut show_consumed = false;
+    let mut stats_json : Option<String> = None;
+    let mut profile = false;
+    let mut state_hash = false;
+    let mut value_histogram = false;
+    let mut loop_timing = false;
+    let mut work = false;
+    let mut argv_input : Option<String> = None;
+    let mut input_file : Option<String> = None;
+    let mut input_env : Option<String> = None;
+    let mut transcript : Option<String> = None;
+    let mut expect : Option<String> = None;
+    let mut pin = false;
+    let mut out_golden : Option<String> = None;
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print_usage();
+                return Ok(());
+            }
+            "--strict" => strict = true,
+            "--classic" => {
+                classic = true;
+                eof_policy = interpreter::EofPolicy::Unchanged;
+            }
+            "--warn-left" => warn_left = true,
+            "-O" => opt_level = interpreter::MAX_OPT_LEVEL,
+            _ if arg.starts_with("-O") => {
+                opt_level = parse_value(arg, Some(&arg[2..].to_string()))?;
+            }
+            "--passes" => {
+                let list : String = parse_value(arg, iter.next())?;
+                passes = Some(list.split(',').map(|p| p.parse()).collect::<Result<_, _>>()?);
+            }
+            "--opt-report" => opt_report = true,
+            "--flat" => flat = true,
+            "--checked-arith" => arith_mode = interpreter::ArithMode::Checked,
+            "--saturating-arith" => arith_mode = interpreter::ArithMode::Saturating,
+            "--keep-going" => keep_going = true,
+            "--dual-tape" => dual_tape = true,
+            "--max-tape" => {
+                max_tape = Some(parse_value(arg, iter.next())?);
+            }
+            "--max-nesting" => {
+                max_nesting = Some(parse_value(arg, iter.next())?);
+            }
+            "--max-source-bytes" => {
+                max_source = Some(parse_value(arg, iter.next())?);
+            }
+            "--ptr-modulo" => {
+                ptr_modulo = Some(parse_value(arg, iter.next())?);
+            }
+            "--tape-chunk" => {
+                tape_chunk = Some(parse_value(arg, iter.next())?);
+            }
+            "--max-steps" => {
+                max_steps = Some(parse_value(arg, iter.next())?);
+            }
+            "--max-time" => {
+                max_time = Some(parse_value(arg, iter.next())?);
+            }
+            "--assert-terminates" => assert_terminates = true,
+            "--check-interval" => {
+                check_interval = Some(parse_value(arg, iter.next())?);
+            }
+            "--max-depth" => {
+                max_depth = Some(parse_value(arg, iter.next())?);
+            }
+            "--max-output" => {
+                max_output = Some(parse_value(arg, iter.next())?);
+            }
+            "--initial-pointer" => {
+                initial_pointer = parse_value(arg, iter.next())?;
+            }
+            "--fill-value" => {
+                fill_value = parse_value(arg, iter.next())?;
+            }
+            "--sandbox" => sandbox = Some(interpreter::SandboxInput::Byte(0)),
+            "--sandbox-eof" => {
+                sandbox = Some(interpreter::SandboxInput::Byte(parse_value(arg, iter.next())?));
+            }
+            "--sandbox-cycle" => {
+                let file : String = parse_value(arg, iter.next())?;
+                let bytes = std::fs::read(&file).map_err(|e| format!("{}: {}", file, e))?;
+                sandbox = Some(interpreter::SandboxInput::Cycle(bytes));
+            }
+            "--input" => {
+                input_file = Some(parse_value(arg, iter.next())?);
+            }
+            "--transcript" => {
+                transcript = Some(parse_value(arg, iter.next())?);
+            }
+            "--input-env" => {
+                input_env = Some(parse_value(arg, iter.next())?);
             }
-            if count != 0 {
-                return Err(String::from("Invalid source."));
-            }
-            Ok(())
-        }
-        fn lex_code(&mut self) {
-            for c in self.source.chars() {
-                let op = match c {
-                    '+' => Some(Op::Increment),
-                    '-' => Some(Op::Decrement),
-                    '<' => Some(Op::ShiftLeft),
-                    '>' => Some(Op::ShiftRight),
-                    '.' => Some(Op::PrintChar),
-                    ',' => Some(Op::GetChar),
-                    '[' => Some(Op::LoopStart),
-                    ']' => Some(Op::LoopEnd),
-                    _ => None,
+            "--expect" => {
+                expect = Some(parse_value(arg, iter.next())?);
+            }
+            "--pin" => pin = true,
+            "--out-golden" => {
+                out_golden = Some(parse_value(arg, iter.next())?);
+            }
+            "--prompt" => {
+                prompt = Some(parse_value(arg, iter.next())?);
+            }
+            "--random-input" => {
+                random_input = Some(parse_value(arg, iter.next())?);
+            }
+            "--eof" => {
+                eof_policy = match iter.next().map(|s| s.as_str()) {
+                    Some("zero") => interpreter::EofPolicy::Zero,
+                    Some("minus-one") => interpreter::EofPolicy::MinusOne,
+                    Some("unchanged") => interpreter::EofPolicy::Unchanged,
+                    Some(v) => return Err(format!("Invalid value for {}: {}", arg, v)),
+                    None => return Err(format!("Missing value for {}", arg)),
                 };
-                match op {
-                    Some(v) => self.ops.push(v),
-                    None => (),
-                }
             }
-        }
-        fn build_instruction(&mut self) {
-            let mut queue = Vec::<Vec::<Instruction>>::new();
-            let mut inst = Vec::<Instruction>::new();
-            for op in self.ops.iter() {
-                let i = match op {
-                    Op::Increment => Some(Instruction::Increment),
-                    Op::Decrement => Some(Instruction::Decrement),
-                    Op::ShiftLeft => Some(Instruction::ShiftLeft),
-                    Op::ShiftRight => Some(Instruction::ShiftRight),
-                    Op::PrintChar => Some(Instruction::PrintChar),
-                    Op::GetChar => Some(Instruction::GetChar),
-                    Op::LoopStart => {
-                        queue.push(inst);
-                        inst = Vec::<Instruction>::new();
-                        None
-                    }
-                    Op::LoopEnd => {
-                        let mut v = queue.pop().unwrap();
-                        v.push(Instruction::Loop(inst));
-                        inst = v;
-                        None
-                    }
+            "--eof-value" => {
+                eof_value = Some(parse_value(arg, iter.next())?);
+            }
+            "--input-encoding" => {
+                input_encoding = match iter.next().map(|s| s.as_str()) {
+                    Some("utf8") => interpreter::InputEncoding::Utf8,
+                    Some("latin1") => interpreter::InputEncoding::Latin1,
+                    Some(v) => return Err(format!("Invalid value for {}: {}", arg, v)),
+                    None => return Err(format!("Missing value for {}", arg)),
+                };
+            }
+            "--require-input" => require_input = true,
+            "--newline-is-eof" => newline_is_eof = true,
+            "--output-format" => {
+                output_format = match iter.next().map(|s| s.as_str()) {
+                    Some("raw") => interpreter::OutputFormat::Raw,
+                    Some("hex") => interpreter::OutputFormat::Hex,
+                    Some("escaped") => interpreter::OutputFormat::Escaped,
+                    Some("rle") => interpreter::OutputFormat::Rle,
+                    Some(v) => return Err(format!("Invalid value for {}: {}", arg, v)),
+                    None => return Err(format!("Missing value for {}", arg)),
                 };
-                match i {
-                    Some(v) => inst.push(v),
-                    None => (),
+            }
+            "--rle-output" => output_format = interpreter::OutputFormat::Rle,
+            "--ascii7" => ascii7 = true,
+            "--line-buffered" => line_buffered = true,
+            "--ensure-newline" => ensure_newline = true,
+            "--signed-display" => signed_display = true,
+            "--disasm" => disasm = true,
+            "--explain-opt" => explain_opt = true,
+            "--emit-asm" => emit_asm = true,
+            "--run-asm" => {
+                run_asm = Some(parse_value(arg, iter.next())?);
+            }
+            "--emit-c" => emit_c = true,
+            "--emit-rust" => emit_rust = true,
+            "--emit-sexpr" => emit_sexpr = true,
+            "--show-ast" => show_ast = true,
+            "--emit-optimized-bf" => emit_bf = true,
+            "--annotate" => annotate = true,
+            "--source-map" => source_map = true,
+            "--check-termination" => check_termination = true,
+            "--warn" => warn = true,
+            "--report-unreachable" => report_unreachable = true,
+            "--metrics" => metrics = true,
+            "--size-table" => size_table = true,
+            "--summarize" => summarize = true,
+            "--estimate-tape" => estimate_tape = true,
+            "--cfg" => cfg = true,
+            "--explain" => explain = true,
+            "--cost-table" => {
+                cost_table = Some(parse_value(arg, iter.next())?);
+            }
+            "--trace-filter" => {
+                let list : String = parse_value(arg, iter.next())?;
+                trace_filter = Some(list.split(',').map(|c| c.parse()).collect::<Result<_, _>>()?);
+                explain = true;
+            }
+            "--watch-tape" => watch_tape = true,
+            "--tape-log" => {
+                tape_log = Some(parse_value(arg, iter.next())?);
+            }
+            "--log-every" => {
+                log_every = parse_value(arg, iter.next())?;
+            }
+            "--testdir" => {
+                testdir = Some(parse_value(arg, iter.next())?);
+            }
+            "--checkpoint-every" => {
+                let every = parse_value(arg, iter.next())?;
+                let file = parse_value(arg, iter.next())?;
+                checkpoint = Some((every, file));
+            }
+            "--resume" => {
+                resume = Some(parse_value(arg, iter.next())?);
+            }
+            "--delay" => {
+                delay = parse_value(arg, iter.next())?;
+            }
+            "--debug" => debug = true,
+            "--run-until" => {
+                run_until = Some(parse_value(arg, iter.next())?);
+            }
+            "--run-until-ptr" => {
+                run_until_ptr = Some(parse_value(arg, iter.next())?);
+            }
+            "--run-until-output" => {
+                run_until_output = Some(parse_value(arg, iter.next())?);
+            }
+            "--lock-at" => {
+                lock_at = Some(parse_value(arg, iter.next())?);
+            }
+            "--dump-ppm" => {
+                let w = parse_value(arg, iter.next())?;
+                let h = parse_value(arg, iter.next())?;
+                let file = parse_value(arg, iter.next())?;
+                dump_ppm = Some((w, h, file));
+            }
+            "--dump-tape-json" => {
+                dump_json = Some(parse_value(arg, iter.next())?);
+            }
+            "--bench" => bench = true,
+            "--repeat" => {
+                repeat = parse_value(arg, iter.next())?;
+            }
+            "--stats" => stats = true,
+            "--annotated-source" => annotated_source = true,
+            "--show-consumed" => show_consumed = true,
+            "--stats-json" => {
+                stats_json = Some(parse_value(arg, iter.next())?);
+            }
+            "--profile" => profile = true,
+            "--state-hash" => state_hash = true,
+            "--value-histogram" => value_histogram = true,
+            "--loop-timing" => loop_timing = true,
+            "--work" => work = true,
+            "--gen" => {
+                let text : String = parse_value(arg, iter.next())?;
+                println!("{}", brainfuck::generator::generate(text.as_bytes()));
+                return Ok(());
+            }
+            "--" => {
+                let rest : Vec<&str> = iter.by_ref().map(|s| s.as_str()).collect();
+                argv_input = Some(rest.join(" "));
+            }
+            _ => {
+                if sourcefile.is_some() {
+                    print_usage();
+                    return Ok(());
                 }
+                sourcefile = Some(arg);
             }
-            self.inst = inst;
         }
-        fn eval_instruction(&self) {
-            let mut memory : Vec<u8> = vec![0];
-            let mut adress : usize = 0;
+    }
+    if let Some(file) = resume {
+        let image = std::fs::read(&file).map_err(|e| format!("{}: {}", file, e))?;
+        let mut interpreter = interpreter::load_image(&image).map_err(|e| format!("{}: {}", file, e))?;
+        if let Some(input) = given_input(input_file.as_deref(), input_env.as_deref(), argv_input.as_deref())? {
+            interpreter = interpreter.with_input(input);
+        }
+        return match checkpoint {
+            Some((every, path)) => debugger::checkpoint(&mut interpreter, every, std::path::Path::new(&path)),
+            None => interpreter.resume().map(|_| ()).map_err(|e| e.to_string()),
+        };
+    }
+    // Assembly is always flat and picks dual-tape mode by itself.
+    let program = if let Some(file) = run_asm {
+        let text = std::fs::read_to_string(&file).map_err(|e| format!("{}: {}", file, e))?;
+        interpreter::from_asm(&text).map_err(|e| format!("{}: {}", file, e))?
+    } else {
+        let sourcefile = match sourcefile {
+            Some(f) => f,
+            None => {
+                print_usage();
+                return Ok(());
+            }
+        };
 
-            self.eval_liner(&self.inst, &mut memory, &mut adress);
-        }
-        fn eval_liner(
-            &self,
-            inst: &Vec<Instruction>,
-            memory: &mut Vec<u8>,
-            adress: &mut usize) {
-            for op in inst.iter() {
-                match op {
-                    Instruction::Increment => {
-                        memory[*adress] += 1
-                    }
-                    Instruction::Decrement => {
-                        memory[*adress] -= 1
-                    }
-                    Instruction::ShiftLeft => {
-                        if *adress == 0 {
-                            memory.insert(0, 0);
-                        } else {
-                            *adress -= 1;
-                        }
-                    }
-                    Instruction::ShiftRight => {
-                        *adress += 1;
-                        if *adress == memory.len() {
-                            memory.push(0);
-                        }
-                    }
-                    Instruction::PrintChar => {
-                        print!("{}", memory[*adress] as char);
-                    }
-                    Instruction::GetChar => {
-                        let mut input = String::new();
-                        match std::io::stdin().read_line(&mut input) {
-                            Ok(_) => (),
-                            Err(msg) => panic!("{}", msg),
-                        }
-                        memory[*adress] = input.as_bytes()[0];
-                    }
-                    Instruction::Loop(inst) => {
-                        if memory[*adress] != 0 {
-                            loop {
-                                self.eval_liner(inst, memory, adress);
-                                if memory[*adress] == 0 {
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
+        let source = match std::fs::read(sourcefile) {
+            Ok(s) => s,
+            Err(msg) => {
+                println!("Error occured while reading a file: {}", sourcefile);
+                println!("{}", msg);
+                std::process::exit(1);
             }
+        };
+        interpreter::from_bytes(source).with_flat(flat).with_dual_tape(dual_tape)
+    };
+
+    let mut builder = program
+        .with_validate_on_build(true)
+        .with_strict(strict)
+        .with_left_growth(!classic)
+        .with_warn_left(warn_left)
+        .with_opt_level(opt_level)
+        .with_arith_mode(arith_mode)
+        .with_keep_going(keep_going)
+        .with_initial_pointer(initial_pointer)
+        .with_fill_value(fill_value)
+        .with_eof_policy(eof_value.map_or(eof_policy, interpreter::EofPolicy::Value))
+        .with_input_encoding(input_encoding)
+        .with_require_input(require_input)
+        .with_newline_is_eof(newline_is_eof)
+        .with_output_format(output_format)
+        .with_ascii7(ascii7)
+        .with_ensure_newline(ensure_newline)
+        .with_signed_display(signed_display)
+        .with_profile(profile || stats || stats_json.is_some())
+        .with_consumed_input(stats)
+        .with_loop_timing(loop_timing)
+        .with_work(work)
+        .with_op_counts(annotated_source)
+        .with_undo(debug)
+        .with_replayable_input(debug && (argv_input.is_some() || input_file.is_some() || input_env.is_some()));
+    if let Some(max) = max_tape {
+        builder = builder.with_max_tape(max);
+    }
+    if let Some(max) = max_source {
+        builder = builder.with_max_source_bytes(max);
+    }
+    if let Some(max) = max_nesting {
+        builder = builder.with_max_nesting(max);
+    }
+    let passes_given = passes.is_some();
+    if let Some(passes) = passes {
+        builder = builder.with_passes(passes);
+    }
+    if let Some(window) = ptr_modulo {
+        builder = builder.with_ptr_modulo(window);
+    }
+    if let Some(cells) = tape_chunk {
+        builder = builder.with_growth_chunk(cells);
+    }
+    if let Some(max) = max_steps {
+        builder = builder.with_max_steps(max);
+    }
+    if let Some(ms) = max_time {
+        builder = builder.with_max_time(std::time::Duration::from_millis(ms));
+    }
+    if assert_terminates && max_steps.is_none() && max_time.is_none() {
+        return Err(String::from("--assert-terminates needs --max-steps or --max-time."));
+    }
+    if let Some(n) = check_interval {
+        builder = builder.with_check_interval(n);
+    }
+    if let Some(max) = max_depth {
+        builder = builder.with_max_runtime_depth(max);
+    }
+    if let Some(max) = max_output {
+        builder = builder.with_max_output(max);
+    }
+    if bench && sandbox.is_none() {
+        sandbox = Some(interpreter::SandboxInput::Byte(0));
+    }
+    if let Some(input) = sandbox {
+        builder = builder.with_sandbox_input(input);
+    }
+    if let Some(p) = prompt {
+        builder = builder.with_prompt(p);
+    }
+    if let Some(seed) = random_input {
+        builder = builder.with_input(Box::new(interpreter::RandomInput::new(seed)));
+    }
+    if let Some(input) = given_input(input_file.as_deref(), input_env.as_deref(), argv_input.as_deref())? {
+        builder = builder.with_input(input);
+    }
+    if let Some(file) = transcript {
+        let log = std::fs::File::create(&file).map_err(|e| format!("{}: {}", file, e))?;
+        builder = builder.with_transcript(Box::new(std::io::BufWriter::new(log)));
+    }
+    // With --pin, an existing golden file is checked like --expect and a
+    // missing one is recorded.
+    let mut record : Option<String> = None;
+    if pin {
+        let golden = out_golden.ok_or("--pin needs --out-golden.")?;
+        if std::path::Path::new(&golden).exists() {
+            expect = Some(golden);
+        } else {
+            record = Some(golden);
         }
     }
-}
-
-fn main() -> Result<(), String> {
-    let args : Vec<String> = std::env::args().collect();
-    if args.len() != 2 || args[1] == "-h" || args[1] == "--help" {
-        let usage = vec![
-        "Usage: ./bf <argument>",
-        "    argument:",
-        "        <source-file>    Run brainfuck program.",
-        "        -h|--help        Show this help"
-        ];
-        for s in usage.iter() {
-            println!("{}", s);
+    if expect.is_some() || record.is_some() {
+        builder = builder.with_captured_output();
+    }
+    if line_buffered {
+        builder = builder
+            .with_output(Box::new(std::io::BufWriter::new(std::io::stdout())))
+            .with_line_buffered(true);
+    }
+    let mut interpreter = builder.build().map_err(|e| e.to_string())?;
+    if disasm {
+        print!("{}", interpreter.disassemble().map_err(|e| e.to_string())?);
+        return Ok(());
+    }
+    if emit_asm {
+        print!("{}", interpreter.to_asm().map_err(|e| e.to_string())?);
+        return Ok(());
+    }
+    if explain_opt {
+        print!("{}", interpreter.explain_opt().map_err(|e| e.to_string())?);
+        return Ok(());
+    }
+    if emit_bf {
+        if opt_level == 0 && !passes_given {
+            interpreter = interpreter.with_opt_level(interpreter::MAX_OPT_LEVEL);
         }
+        println!("{}", interpreter.optimized_source().map_err(|e| e.to_string())?);
         return Ok(());
     }
-
-    let sourcefile = &args[1];
-    let source = match std::fs::read_to_string(sourcefile) {
-        Ok(s) => s,
-        Err(msg) => {
-            println!("Error occured while reading a file: {}", sourcefile);
-            println!("{}", msg);
-            std::process::exit(1);
+    if emit_c {
+        print!("{}", interpreter.to_c(source_map).map_err(|e| e.to_string())?);
+        return Ok(());
+    }
+    if emit_rust {
+        print!("{}", interpreter.to_rust(source_map).map_err(|e| e.to_string())?);
+        return Ok(());
+    }
+    if annotate {
+        print!("{}", interpreter.annotated_source().map_err(|e| e.to_string())?);
+        return Ok(());
+    }
+    if emit_sexpr {
+        println!("{}", interpreter.to_sexpr().map_err(|e| e.to_string())?);
+        return Ok(());
+    }
+    if show_ast {
+        eprintln!("{}", interpreter.to_sexpr().map_err(|e| e.to_string())?);
+    }
+    if opt_report {
+        for r in interpreter.opt_report().map_err(|e| e.to_string())? {
+            eprintln!("{}: {} -> {} ({:+})", r.pass, r.before, r.after, r.after as isize - r.before as isize);
         }
-    };
-
-    let mut interpreter = interpreter::new(source);
-    interpreter.run()?;
+    }
+    if warn {
+        for w in interpreter.warnings().map_err(|e| e.to_string())? {
+            eprintln!("warning: offset {}: {}", w.offset, w.message);
+        }
+    }
+    if report_unreachable {
+        for s in interpreter.unreachable().map_err(|e| e.to_string())? {
+            eprintln!("unreachable: offsets {} to {}", s.start, s.end);
+        }
+    }
+    if check_termination {
+        interpreter.check_termination().map_err(|e| e.to_string())?;
+    }
+    if cfg {
+        print!("{}", interpreter.loop_tree().map_err(|e| e.to_string())?);
+        return Ok(());
+    }
+    if metrics {
+        let m = interpreter.metrics().map_err(|e| e.to_string())?;
+        println!("ops: {}", m.ops);
+        println!("loops: {}", m.loops);
+        println!("max depth: {}", m.max_depth);
+        println!("loop weight: {}", m.loop_weight);
+        println!("comment density: {:.2}", m.comment_density());
+        return Ok(());
+    }
+    if size_table {
+        let max_level = if opt_level == 0 { interpreter::MAX_OPT_LEVEL } else { opt_level };
+        for (level, size) in interpreter.size_table(max_level).map_err(|e| e.to_string())?.iter().enumerate() {
+            println!("O{}: {}", level, size);
+        }
+        return Ok(());
+    }
+    if estimate_tape {
+        match interpreter.pointer_range().map_err(|e| e.to_string())? {
+            Some(r) => println!("{} {} ({} to {:+})", r.cells(), if r.cells() == 1 { "cell" } else { "cells" }, r.min, r.max),
+            None => println!("unbounded"),
+        }
+        return Ok(());
+    }
+    if summarize {
+        let summary = interpreter.summarize().map_err(|e| e.to_string())?
+            .ok_or("The program has loops or I/O left after optimization.")?;
+        for (offset, delta) in summary.deltas.iter() {
+            println!("cell {}: {:+}", offset, *delta as i8);
+        }
+        println!("pointer: {:+}", summary.pointer);
+        return Ok(());
+    }
+    if bench {
+        let mut times = interpreter.bench(repeat).map_err(|e| e.to_string())?;
+        if times.is_empty() {
+            return Ok(());
+        }
+        times.sort();
+        eprintln!("runs: {}", times.len());
+        eprintln!("min: {:?}", times[0]);
+        eprintln!("median: {:?}", times[times.len() / 2]);
+        eprintln!("max: {:?}", times[times.len() - 1]);
+        return Ok(());
+    }
+    if let Some(file) = cost_table {
+        let text = std::fs::read_to_string(&file).map_err(|e| format!("{}: {}", file, e))?;
+        let table : debugger::CostTable = text.parse().map_err(|e| format!("{}: {}", file, e))?;
+        let report = debugger::estimate_cost(&mut interpreter, &table)?;
+        for category in debugger::TraceCategory::ALL {
+            eprintln!("{}: {} x {}", category, report.count(category), table.cost(category));
+        }
+        eprintln!("total cost: {}", report.total);
+        return Ok(());
+    }
+    if explain {
+        return debugger::explain(&mut interpreter, &mut std::io::stderr(), trace_filter.as_deref());
+    }
+    if watch_tape {
+        let ansi = std::io::IsTerminal::is_terminal(&std::io::stderr());
+        let delay = std::time::Duration::from_millis(delay);
+        return debugger::watch(&mut interpreter, &mut std::io::stderr(), delay, ansi);
+    }
+    if let Some(file) = tape_log {
+        let log = std::fs::OpenOptions::new().create(true).append(true).open(&file)
+            .map_err(|e| format!("{}: {}", file, e))?;
+        return debugger::tape_log(&mut interpreter, &mut std::io::BufWriter::new(log), log_every);
+    }
+    if let Some((every, file)) = checkpoint {
+        interpreter.prepare().map_err(|e| e.to_string())?;
+        return debugger::checkpoint(&mut interpreter, every, std::path::Path::new(&file));
+    }
+    if let Some(dir) = testdir {
+        return run_testdir(interpreter, &dir);
+    }
+    if debug {
+        return debugger::run(&mut interpreter, &mut std::io::stdin().lock());
+    }
+    let start = std::time::Instant::now();
+    if let Some(offset) = lock_at {
+        if debugger::run_to(&mut interpreter, offset)? == interpreter::Status::Running {
+            interpreter.lock_tape();
+        }
+        interpreter.resume().map_err(|e| e.to_string())?;
+    } else if let Some(offset) = run_until {
+        if debugger::run_to(&mut interpreter, offset)? == interpreter::Status::Running {
+            eprintln!("stopped at offset {}", offset);
+        }
+    } else if let Some(index) = run_until_ptr {
+        if debugger::run_to_pointer(&mut interpreter, index)? {
+            eprintln!("stopped with the pointer at cell {}", index);
+        } else {
+            eprintln!("the pointer never reached cell {}", index);
+        }
+    } else if let Some(byte) = run_until_output {
+        if debugger::run_to_output(&mut interpreter, byte)? == interpreter::Status::Running {
+            eprintln!("stopped after printing byte {}", byte);
+        }
+    } else {
+        interpreter.run().map_err(|e| match e.limit() {
+            Some(limit) if assert_terminates => format!("Did not terminate within the {} limit. {}", limit, e),
+            _ => e.to_string(),
+        })?;
+    }
+    let wall_time = start.elapsed();
+    if let Some(file) = expect {
+        let expected = std::fs::read(&file).map_err(|e| format!("{}: {}", file, e))?;
+        let got = interpreter.take_output();
+        if let Some(m) = interpreter::first_mismatch(&got, &expected) {
+            if let interpreter::Mismatch::Output { index, .. } = m {
+                eprintln!("got:      {}", context(&got, index));
+                eprintln!("expected: {}", context(&expected, index));
+            }
+            return Err(m.to_string());
+        }
+    }
+    if let Some(file) = record {
+        std::fs::write(&file, interpreter.take_output()).map_err(|e| format!("{}: {}", file, e))?;
+        eprintln!("wrote golden file {}", file);
+    }
+    if let Some((w, h, file)) = dump_ppm {
+        let mut out = std::fs::File::create(&file).map_err(|e| format!("{}: {}", file, e))?;
+        interpreter.dump_ppm(w, h, &mut out).map_err(|e| format!("{}: {}", file, e))?;
+    }
+    if let Some(file) = dump_json {
+        if file == "-" {
+            interpreter.dump_tape_json(&mut std::io::stdout()).map_err(|e| e.to_string())?;
+        } else {
+            let mut out = std::fs::File::create(&file).map_err(|e| format!("{}: {}", file, e))?;
+            interpreter.dump_tape_json(&mut out).map_err(|e| format!("{}: {}", file, e))?;
+        }
+    }
+    if let Some(file) = stats_json {
+        let json = interpreter.stats_json(wall_time);
+        if file == "-" {
+            println!("{}", json);
+        } else {
+            std::fs::write(&file, json + "\n").map_err(|e| format!("{}: {}", file, e))?;
+        }
+    }
+    if stats {
+        eprintln!("steps: {}", interpreter.steps());
+        eprintln!("bytes written: {}", interpreter.bytes_written());
+        eprintln!("input ops: {}", interpreter.input_ops());
+        eprintln!("bytes consumed: {}", interpreter.consumed_input().len());
+        if show_consumed {
+            eprintln!("consumed: \"{}\"", interpreter.consumed_input().escape_ascii());
+        }
+        eprintln!("cells touched: {}", interpreter.cells_touched());
+        eprintln!("workload: {}", if interpreter.is_io_bound() { "I/O-bound" } else { "compute-bound" });
+    }
+    if state_hash {
+        eprintln!("state hash: {:016x}", interpreter.state_hash());
+    }
+    if annotated_source {
+        eprint!("{}", interpreter.counted_source().map_err(|e| e.to_string())?);
+    }
+    if let Some(work) = interpreter.work() {
+        eprintln!("work: {}", work);
+    }
+    if value_histogram {
+        let mut counts = [0usize; 256];
+        for v in interpreter.memory() {
+            counts[*v as usize] += 1;
+        }
+        for (v, n) in counts.iter().enumerate().filter(|(_, n)| **n > 0) {
+            eprintln!("{}: {} {}", interpreter.display_cell(v as u8), n, if *n == 1 { "cell" } else { "cells" });
+        }
+    }
+    if profile {
+        for (i, a) in interpreter.cell_access().iter().enumerate() {
+            eprintln!("{},{},{}", i, a.reads, a.writes);
+        }
+    }
+    if loop_timing {
+        for (offset, time) in interpreter.loop_times() {
+            eprintln!("{}: {:.3}ms", offset, time.as_secs_f64() * 1000.0);
+        }
+    }
     Ok(())
 }
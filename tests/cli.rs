@@ -0,0 +1,231 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+// Runs the interpreter binary with `args`, feeding it `stdin`.
+fn bf(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_brainfuck"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+// Writes `contents` to `name` in the scratch directory and returns its path.
+fn scratch(name: &str, contents: &[u8]) -> String {
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::write(&path, contents).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn arguments_after_dashes_are_the_input() {
+    let program = scratch("argv_echo.bf", b",[.,]");
+    let out = bf(&[&program, "--", "abc"], b"");
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"abc");
+    let out = bf(&[&program, "--", "a", "b"], b"");
+    assert_eq!(out.stdout, b"a b");
+}
+
+#[test]
+fn classic_runs_hello_world() {
+    let program = scratch(
+        "classic_hello.bf",
+        b"++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.",
+    );
+    let out = bf(&["--classic", &program], b"");
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"Hello World!\n");
+    let program = scratch("classic_left.bf", b"<+");
+    let out = bf(&["--classic", &program], b"");
+    assert!(!out.status.success());
+    let program = scratch("classic_eof.bf", b"+++,.");
+    let out = bf(&["--classic", &program], b"");
+    assert_eq!(out.stdout, b"\x03");
+}
+
+#[test]
+fn source_may_hold_invalid_utf8() {
+    let program = scratch("binary_comment.bf", b"\xff\xfe a comment \x80+++.");
+    let out = bf(&[&program], b"");
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(out.stdout, b"\x03");
+}
+
+#[test]
+fn eof_value_is_stored_at_end_of_input() {
+    let program = scratch("eof_value.bf", b"+,.");
+    let out = bf(&["--eof-value", "255", &program], b"");
+    assert!(out.status.success());
+    assert_eq!(out.stdout, [255]);
+    let out = bf(&["--eof-value", "256", &program], b"");
+    assert!(!out.status.success());
+}
+
+#[test]
+fn input_file_feeds_comma() {
+    let program = scratch("input_file.bf", b",.,.,.");
+    let input = scratch("input_file.in", b"ab");
+    let out = bf(&["--input", &input, &program], b"");
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"ab\0");
+}
+
+// An endless input can only work if it is read as ',' asks for it.
+#[cfg(unix)]
+#[test]
+fn input_file_is_not_loaded_up_front() {
+    let program = scratch("input_stream.bf", b",+.");
+    let out = bf(&["--input", "/dev/zero", &program], b"");
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"\x01");
+}
+
+#[test]
+fn expect_reports_the_first_difference() {
+    let program = scratch("expect.bf", b",[.,]");
+    let input = scratch("expect.in", b"hello");
+    let good = scratch("expect_good.out", b"hello");
+    let out = bf(&["--input", &input, "--expect", &good, &program], b"");
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let bad = scratch("expect_bad.out", b"help!");
+    let out = bf(&["--input", &input, "--expect", &bad, &program], b"");
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("byte 3"), "{}", stderr);
+}
+
+#[test]
+fn emitted_rust_compiles_with_rustc() {
+    let program = scratch("emit_rust.bf", b"++++++++[>++++++++<-]>+.+.,[.,]");
+    let out = bf(&["--emit-rust", &program], b"");
+    assert!(out.status.success());
+    let code = String::from_utf8(out.stdout).unwrap();
+    assert!(code.contains("fn main"));
+    let source = scratch("emit_rust.rs", code.as_bytes());
+    let exe = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("emit_rust");
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let status = Command::new(rustc).arg(&source).arg("-o").arg(&exe).status().unwrap();
+    assert!(status.success());
+    let mut child = Command::new(&exe).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+    child.stdin.take().unwrap().write_all(b"C!").unwrap();
+    assert_eq!(child.wait_with_output().unwrap().stdout, b"ABC!");
+}
+
+#[test]
+fn input_env_feeds_comma() {
+    let program = scratch("input_env.bf", b",[.,]");
+    let out = Command::new(env!("CARGO_BIN_EXE_brainfuck"))
+        .args(["--input-env", "BF_TEST_INPUT", &program])
+        .env("BF_TEST_INPUT", "from the env")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"from the env");
+}
+
+#[test]
+fn warn_left_warns_once() {
+    let program = scratch("warn_left.bf", b"+<+<+>>.");
+    let out = bf(&["--warn-left", &program], b"");
+    assert!(out.status.success());
+    assert_eq!(out.stdout, [1]);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert_eq!(stderr.matches("left of the origin").count(), 1, "{}", stderr);
+    let out = bf(&[&program], b"");
+    assert!(out.stderr.is_empty());
+}
+
+#[test]
+fn value_histogram_counts_cells_per_value() {
+    let program = scratch("histogram.bf", b"+>+>>++>>");
+    let out = bf(&["--value-histogram", &program], b"");
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stderr), "0: 3 cells\n1: 2 cells\n2: 1 cell\n");
+}
+
+#[test]
+fn assert_terminates_names_the_tripped_limit() {
+    let finite = scratch("terminates_finite.bf", b"+++[-]");
+    let out = bf(&["--assert-terminates", "--max-steps", "100", &finite], b"");
+    assert!(out.status.success());
+    let endless = scratch("terminates_endless.bf", b"+[>+.]");
+    for (args, limit) in [
+        (["--max-steps", "100"], "step"),
+        (["--max-time", "50"], "time"),
+        (["--max-tape", "10"], "tape"),
+        (["--max-output", "5"], "output"),
+    ] {
+        let mut all = vec!["--assert-terminates", "--max-steps", "100000000"];
+        all.extend_from_slice(&args);
+        all.push(&endless);
+        let out = bf(&all, b"");
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains(&format!("Did not terminate within the {} limit.", limit)), "{}", stderr);
+    }
+    let nested = scratch("terminates_deep.bf", b"+[[[-]]]");
+    let out = bf(&["--assert-terminates", "--max-steps", "100", "--max-depth", "2", &nested], b"");
+    assert!(String::from_utf8_lossy(&out.stderr).contains("within the depth limit."));
+    let out = bf(&["--assert-terminates", &finite], b"");
+    assert!(!out.status.success());
+}
+
+#[test]
+fn show_ast_prints_the_tree_then_runs() {
+    let program = scratch("show_ast.bf", b"++[->+<]>.");
+    let out = bf(&["--show-ast", &program], b"");
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stderr), "(seq + + (loop - > + <) > .)\n");
+    assert_eq!(out.stdout, [2]);
+}
+
+#[test]
+fn pin_records_then_checks_the_golden_file() {
+    let program = scratch("pin.bf", b",[.,]");
+    let input = scratch("pin.in", b"pinned");
+    let golden = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("pin.golden");
+    let _ = std::fs::remove_file(&golden);
+    let golden = golden.to_string_lossy().into_owned();
+    let out = bf(&["--pin", &program, "--input", &input, "--out-golden", &golden], b"");
+    assert!(out.status.success());
+    assert_eq!(std::fs::read(&golden).unwrap(), b"pinned");
+    let out = bf(&["--pin", &program, "--input", &input, "--out-golden", &golden], b"");
+    assert!(out.status.success());
+    let other = scratch("pin_other.in", b"changed");
+    let out = bf(&["--pin", &program, "--input", &other, "--out-golden", &golden], b"");
+    assert!(!out.status.success());
+    assert_eq!(std::fs::read(&golden).unwrap(), b"pinned");
+}
+
+#[test]
+fn testdir_summarizes_passes_and_failures() {
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("testdir_cases");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    for (name, input, output) in [("1", "ab", "bc"), ("2", "xy", "yz"), ("10", "a", "a"), ("3", "", "")] {
+        std::fs::write(dir.join(format!("{}.in", name)), input).unwrap();
+        std::fs::write(dir.join(format!("{}.out", name)), output).unwrap();
+    }
+    std::fs::write(dir.join("4.in"), "q").unwrap();
+    let program = scratch("testdir.bf", b",[+.,]");
+    let out = bf(&["--testdir", &dir.to_string_lossy(), &program], b"");
+    assert!(!out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let lines : Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 6, "{}", stdout);
+    assert_eq!(&lines[..3], ["1: ok", "2: ok", "3: ok"]);
+    assert!(lines[3].starts_with("4: FAIL"));
+    assert!(lines[4].starts_with("10: FAIL"));
+    assert_eq!(lines[5], "3 passed, 2 failed");
+    std::fs::remove_file(dir.join("4.in")).unwrap();
+    std::fs::write(dir.join("10.out"), "b").unwrap();
+    let out = bf(&["--testdir", &dir.to_string_lossy(), &program], b"");
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).ends_with("4 passed, 0 failed\n"));
+}